@@ -0,0 +1,735 @@
+use std::io::Read;
+use crate::{lexer::{Lexer, SyntaxError, Token}, parser::MAX_LEVELS};
+
+pub use crate::lexer::Span;
+
+// Syntax tree for analysis tools. `ParseProto` does not use it: it still
+// compiles the source to bytecode directly in a single pass.
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    Local(Vec<LocalName>, Vec<Exp>),
+    Assign(Vec<Exp>, Vec<Exp>),
+    Call(Exp),
+    Do(Block),
+    While(Exp, Block),
+    Repeat(Block, Exp),
+    If(Vec<(Exp, Block)>, Option<Block>),
+    NumericFor(Name, Box<Exp>, Box<Exp>, Option<Box<Exp>>, Block),
+    GenericFor(Vec<Name>, Vec<Exp>, Block),
+    Function(FuncName, FuncBody),
+    LocalFunction(Name, FuncBody),
+    Return(Vec<Exp>),
+    Break,
+    Goto(Name),
+    Label(Name),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Name {
+    pub name: String,
+    pub span: Span,
+}
+
+/// Name in a `local` statement, with the optional `<const>` or `<close>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalName {
+    pub name: Name,
+    pub attrib: Option<Name>,
+}
+
+/// `a.b.c` or `a.b:c` in `function a.b:c() end`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncName {
+    pub path: Vec<Name>,
+    pub method: Option<Name>,
+}
+
+/// Parameters and body of a function. The implicit `self` of methods is
+/// not listed in `params`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub params: Vec<Name>,
+    pub is_vararg: bool,
+    pub block: Block,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Exp {
+    pub kind: ExpKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExpKind {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(f64),
+    String(Vec<u8>),
+    Vararg,
+    Function(FuncBody),
+    Table(Vec<Field>),
+    BinOp(BinOp, Box<Exp>, Box<Exp>),
+    UnOp(UnOp, Box<Exp>),
+    Name(String),
+    Paren(Box<Exp>),
+    Index(Box<Exp>, Box<Exp>), // `t.k` has a string key
+    Call(Box<Exp>, Vec<Exp>),
+    MethodCall(Box<Exp>, Name, Vec<Exp>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Positional(Exp),
+    Named(Name, Exp),
+    Keyed(Exp, Exp),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add, Sub, Mul, Div, Idiv, Mod, Pow, Concat,
+    BitAnd, BitOr, BitXor, ShiftL, ShiftR,
+    Equal, NotEq, Less, LesEq, Greater, GreEq,
+    And, Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg, Not, Len, BitNot,
+}
+
+pub(crate) const UNARY_PRIORITY: u8 = 12;
+
+impl BinOp {
+    pub(crate) fn from_token(t: &Token) -> Option<Self> {
+        let op = match t {
+            Token::Add => BinOp::Add,
+            Token::Sub => BinOp::Sub,
+            Token::Mul => BinOp::Mul,
+            Token::Div => BinOp::Div,
+            Token::Idiv => BinOp::Idiv,
+            Token::Mod => BinOp::Mod,
+            Token::Pow => BinOp::Pow,
+            Token::Concat => BinOp::Concat,
+            Token::BitAnd => BinOp::BitAnd,
+            Token::BitOr => BinOp::BitOr,
+            Token::BitXor => BinOp::BitXor,
+            Token::ShiftL => BinOp::ShiftL,
+            Token::ShiftR => BinOp::ShiftR,
+            Token::Equal => BinOp::Equal,
+            Token::NotEq => BinOp::NotEq,
+            Token::Less => BinOp::Less,
+            Token::LesEq => BinOp::LesEq,
+            Token::Greater => BinOp::Greater,
+            Token::GreEq => BinOp::GreEq,
+            Token::And => BinOp::And,
+            Token::Or => BinOp::Or,
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    // (left, right) priorities, same as Lua's
+    pub(crate) fn priority(self) -> (u8, u8) {
+        match self {
+            BinOp::Or => (1, 1),
+            BinOp::And => (2, 2),
+            BinOp::Equal | BinOp::NotEq | BinOp::Less | BinOp::LesEq
+                | BinOp::Greater | BinOp::GreEq => (3, 3),
+            BinOp::BitOr => (4, 4),
+            BinOp::BitXor => (5, 5),
+            BinOp::BitAnd => (6, 6),
+            BinOp::ShiftL | BinOp::ShiftR => (7, 7),
+            BinOp::Concat => (9, 8), // right associative
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Div | BinOp::Idiv | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13), // right associative
+        }
+    }
+}
+
+impl UnOp {
+    pub(crate) fn from_token(t: &Token) -> Option<Self> {
+        match t {
+            Token::Sub => Some(UnOp::Neg),
+            Token::Not => Some(UnOp::Not),
+            Token::Len => Some(UnOp::Len),
+            Token::BitXor => Some(UnOp::BitNot),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a whole chunk into a syntax tree.
+pub fn parse_ast<R: Read>(lexer: Lexer<R>) -> Result<Block, SyntaxError> {
+    let mut parser = AstParser { lexer, level: 0 };
+    let block = parser.block()?;
+    match parser.lexer.next()? {
+        Token::Eos => Ok(block),
+        t => Err(parser.lexer.error_near("'<eof>' expected", &t)),
+    }
+}
+
+/// Traverse a syntax tree. Each method defaults to walking the children of
+/// the node, so implementors override only the nodes they care about and
+/// call the matching `walk_*` function to keep descending.
+pub trait Visitor {
+    fn visit_block(&mut self, block: &Block) {
+        walk_block(self, block);
+    }
+
+    fn visit_stat(&mut self, stat: &Stat) {
+        walk_stat(self, stat);
+    }
+
+    fn visit_exp(&mut self, exp: &Exp) {
+        walk_exp(self, exp);
+    }
+
+    fn visit_func_body(&mut self, body: &FuncBody) {
+        walk_func_body(self, body);
+    }
+}
+
+pub fn walk_block<V: Visitor + ?Sized>(visitor: &mut V, block: &Block) {
+    for stat in &block.stats {
+        visitor.visit_stat(stat);
+    }
+}
+
+pub fn walk_stat<V: Visitor + ?Sized>(visitor: &mut V, stat: &Stat) {
+    match &stat.kind {
+        StatKind::Local(_, exps) | StatKind::Return(exps) => {
+            exps.iter().for_each(|exp| visitor.visit_exp(exp));
+        }
+        StatKind::Assign(vars, exps) => {
+            vars.iter().for_each(|var| visitor.visit_exp(var));
+            exps.iter().for_each(|exp| visitor.visit_exp(exp));
+        }
+        StatKind::Call(exp) => visitor.visit_exp(exp),
+        StatKind::Do(block) => visitor.visit_block(block),
+        StatKind::While(cond, block) => {
+            visitor.visit_exp(cond);
+            visitor.visit_block(block);
+        }
+        StatKind::Repeat(block, cond) => {
+            visitor.visit_block(block);
+            visitor.visit_exp(cond);
+        }
+        StatKind::If(cond_blocks, else_block) => {
+            for (cond, block) in cond_blocks {
+                visitor.visit_exp(cond);
+                visitor.visit_block(block);
+            }
+            if let Some(block) = else_block {
+                visitor.visit_block(block);
+            }
+        }
+        StatKind::NumericFor(_, init, limit, step, block) => {
+            visitor.visit_exp(init);
+            visitor.visit_exp(limit);
+            if let Some(step) = step {
+                visitor.visit_exp(step);
+            }
+            visitor.visit_block(block);
+        }
+        StatKind::GenericFor(_, exps, block) => {
+            exps.iter().for_each(|exp| visitor.visit_exp(exp));
+            visitor.visit_block(block);
+        }
+        StatKind::Function(_, body) | StatKind::LocalFunction(_, body) => visitor.visit_func_body(body),
+        StatKind::Break | StatKind::Goto(_) | StatKind::Label(_) => (),
+    }
+}
+
+pub fn walk_exp<V: Visitor + ?Sized>(visitor: &mut V, exp: &Exp) {
+    match &exp.kind {
+        ExpKind::Function(body) => visitor.visit_func_body(body),
+        ExpKind::Table(fields) => {
+            for field in fields {
+                match field {
+                    Field::Positional(value) | Field::Named(_, value) => visitor.visit_exp(value),
+                    Field::Keyed(key, value) => {
+                        visitor.visit_exp(key);
+                        visitor.visit_exp(value);
+                    }
+                }
+            }
+        }
+        ExpKind::BinOp(_, left, right) | ExpKind::Index(left, right) => {
+            visitor.visit_exp(left);
+            visitor.visit_exp(right);
+        }
+        ExpKind::UnOp(_, exp) | ExpKind::Paren(exp) => visitor.visit_exp(exp),
+        ExpKind::Call(func, args) | ExpKind::MethodCall(func, _, args) => {
+            visitor.visit_exp(func);
+            args.iter().for_each(|arg| visitor.visit_exp(arg));
+        }
+        ExpKind::Nil | ExpKind::Boolean(_) | ExpKind::Integer(_) | ExpKind::Float(_)
+            | ExpKind::String(_) | ExpKind::Vararg | ExpKind::Name(_) => (),
+    }
+}
+
+pub fn walk_func_body<V: Visitor + ?Sized>(visitor: &mut V, body: &FuncBody) {
+    visitor.visit_block(&body.block);
+}
+
+struct AstParser<R: Read> {
+    lexer: Lexer<R>,
+    level: usize, // of the statements and expressions being parsed
+}
+
+impl<R: Read> AstParser<R> {
+    fn block(&mut self) -> Result<Block, SyntaxError> {
+        self.enter_level()?;
+        let mut stats = Vec::new();
+        loop {
+            match self.lexer.peek()? {
+                Token::Else | Token::Elseif | Token::End | Token::Until | Token::Eos => break,
+                Token::Return => {
+                    stats.push(self.ret_stat()?);
+                    break;
+                }
+                _ => stats.extend(self.statement()?),
+            }
+        }
+
+        let span = match (stats.first(), stats.last()) {
+            (Some(first), Some(last)) => first.span.to(last.span),
+            _ => {
+                let end = self.lexer.span().end;
+                Span { start: end, end, line: self.lexer.line() }
+            }
+        };
+        self.level -= 1;
+        Ok(Block { stats, span })
+    }
+
+    fn statement(&mut self) -> Result<Option<Stat>, SyntaxError> {
+        let t = self.lexer.next()?;
+        let start = self.lexer.span();
+        let line = start.line;
+
+        let kind = match t {
+            Token::SemiColon => return Ok(None),
+            Token::If => self.if_stat(line)?,
+            Token::While => {
+                let cond = self.exp()?;
+                self.lexer.expect(Token::Do)?;
+                let block = self.block()?;
+                self.lexer.check_match(Token::End, Token::While, line)?;
+                StatKind::While(cond, block)
+            }
+            Token::Do => {
+                let block = self.block()?;
+                self.lexer.check_match(Token::End, Token::Do, line)?;
+                StatKind::Do(block)
+            }
+            Token::For => self.for_stat(line)?,
+            Token::Repeat => {
+                let block = self.block()?;
+                self.lexer.check_match(Token::Until, Token::Repeat, line)?;
+                StatKind::Repeat(block, self.exp()?)
+            }
+            Token::Function => {
+                let name = self.func_name()?;
+                StatKind::Function(name, self.func_body(start)?)
+            }
+            Token::Local => {
+                if self.lexer.peek()? == &Token::Function {
+                    self.lexer.next()?;
+                    let name = self.name()?;
+                    StatKind::LocalFunction(name, self.func_body(start)?)
+                } else {
+                    self.local_stat()?
+                }
+            }
+            Token::DoubColon => {
+                let name = self.name()?;
+                self.lexer.expect(Token::DoubColon)?;
+                StatKind::Label(name)
+            }
+            Token::Break => StatKind::Break,
+            Token::Goto => StatKind::Goto(self.name()?),
+            t => self.exp_stat(t)?,
+        };
+        Ok(Some(Stat { kind, span: start.to(self.lexer.span()) }))
+    }
+
+    fn ret_stat(&mut self) -> Result<Stat, SyntaxError> {
+        self.lexer.next()?; // `return`
+        let start = self.lexer.span();
+
+        let exps = match self.lexer.peek()? {
+            Token::Else | Token::Elseif | Token::End | Token::Until | Token::Eos
+                | Token::SemiColon => Vec::new(),
+            _ => self.explist()?,
+        };
+        if self.lexer.peek()? == &Token::SemiColon {
+            self.lexer.next()?;
+        }
+        Ok(Stat { kind: StatKind::Return(exps), span: start.to(self.lexer.span()) })
+    }
+
+    fn if_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        let mut cond_blocks = Vec::new();
+        loop {
+            let cond = self.exp()?;
+            self.lexer.expect(Token::Then)?;
+            cond_blocks.push((cond, self.block()?));
+
+            match self.lexer.next()? {
+                Token::Elseif => continue,
+                Token::Else => {
+                    let else_block = self.block()?;
+                    self.lexer.check_match(Token::End, Token::If, line)?;
+                    break Ok(StatKind::If(cond_blocks, Some(else_block)));
+                }
+                Token::End => break Ok(StatKind::If(cond_blocks, None)),
+                t => break Err(self.lexer.match_error(Token::End, Token::If, line, &t)),
+            }
+        }
+    }
+
+    fn for_stat(&mut self, line: usize) -> Result<StatKind, SyntaxError> {
+        let var = self.name()?;
+        match self.lexer.next()? {
+            Token::Assign => {
+                let init = self.exp()?;
+                self.lexer.expect(Token::Comma)?;
+                let limit = self.exp()?;
+                let step = if self.lexer.peek()? == &Token::Comma {
+                    self.lexer.next()?;
+                    Some(Box::new(self.exp()?))
+                } else {
+                    None
+                };
+                self.lexer.expect(Token::Do)?;
+                let block = self.block()?;
+                self.lexer.check_match(Token::End, Token::For, line)?;
+                Ok(StatKind::NumericFor(var, Box::new(init), Box::new(limit), step, block))
+            }
+            t@(Token::Comma | Token::In) => {
+                let mut vars = vec![var];
+                if t == Token::Comma {
+                    loop {
+                        vars.push(self.name()?);
+                        if self.lexer.peek()? != &Token::Comma {
+                            break;
+                        }
+                        self.lexer.next()?;
+                    }
+                    self.lexer.expect(Token::In)?;
+                }
+                let exps = self.explist()?;
+                self.lexer.expect(Token::Do)?;
+                let block = self.block()?;
+                self.lexer.check_match(Token::End, Token::For, line)?;
+                Ok(StatKind::GenericFor(vars, exps, block))
+            }
+            t => Err(self.lexer.error_near("'=' or 'in' expected", &t)),
+        }
+    }
+
+    fn local_stat(&mut self) -> Result<StatKind, SyntaxError> {
+        let mut names = Vec::new();
+        loop {
+            let name = self.name()?;
+            let attrib = if self.lexer.peek()? == &Token::Less {
+                self.lexer.next()?;
+                let attrib = self.name()?;
+                if attrib.name != "const" && attrib.name != "close" {
+                    return Err(self.lexer.error(format!("unknown attribute '{}'", attrib.name)));
+                }
+                self.lexer.expect(Token::Greater)?;
+                Some(attrib)
+            } else {
+                None
+            };
+            names.push(LocalName { name, attrib });
+
+            if self.lexer.peek()? != &Token::Comma {
+                break;
+            }
+            self.lexer.next()?;
+        }
+
+        let nclose = names.iter()
+            .filter(|n| n.attrib.as_ref().is_some_and(|a| a.name == "close"))
+            .count();
+        if nclose > 1 {
+            return Err(self.lexer.error("multiple to-be-closed variables in local list"));
+        }
+
+        let exps = if self.lexer.peek()? == &Token::Assign {
+            self.lexer.next()?;
+            self.explist()?
+        } else {
+            Vec::new()
+        };
+        Ok(StatKind::Local(names, exps))
+    }
+
+    fn exp_stat(&mut self, t: Token) -> Result<StatKind, SyntaxError> {
+        let exp = self.suffixedexp(t)?;
+        if !matches!(self.lexer.peek()?, Token::Assign | Token::Comma) {
+            return match exp.kind {
+                ExpKind::Call(..) | ExpKind::MethodCall(..) => Ok(StatKind::Call(exp)),
+                _ => {
+                    let t = self.lexer.next()?;
+                    Err(self.lexer.error_near("syntax error", &t))
+                }
+            };
+        }
+
+        let mut vars = vec![exp];
+        loop {
+            let t = self.lexer.next()?;
+            if !matches!(vars.last().unwrap().kind, ExpKind::Name(_) | ExpKind::Index(..)) {
+                return Err(self.lexer.error_near("syntax error", &t));
+            }
+            match t {
+                Token::Comma => {
+                    let t = self.lexer.next()?;
+                    vars.push(self.suffixedexp(t)?);
+                }
+                Token::Assign => break,
+                t => return Err(self.lexer.error_near("'=' expected", &t)),
+            }
+        }
+        Ok(StatKind::Assign(vars, self.explist()?))
+    }
+
+    fn func_name(&mut self) -> Result<FuncName, SyntaxError> {
+        let mut path = vec![self.name()?];
+        let mut method = None;
+        loop {
+            match self.lexer.peek()? {
+                Token::Dot => {
+                    self.lexer.next()?;
+                    path.push(self.name()?);
+                }
+                Token::Colon => {
+                    self.lexer.next()?;
+                    method = Some(self.name()?);
+                    break;
+                }
+                _ => break,
+            }
+        }
+        Ok(FuncName { path, method })
+    }
+
+    // `start` is the span of the `function` keyword
+    fn func_body(&mut self, start: Span) -> Result<FuncBody, SyntaxError> {
+        self.lexer.expect(Token::ParL)?;
+
+        let mut params = Vec::new();
+        let mut is_vararg = false;
+        if self.lexer.peek()? != &Token::ParR {
+            loop {
+                match self.lexer.next()? {
+                    Token::Ident(name) => params.push(Name { name, span: self.lexer.span() }),
+                    Token::Dots => {
+                        is_vararg = true;
+                        break;
+                    }
+                    t => return Err(self.lexer.error_near("<name> expected", &t)),
+                }
+                if self.lexer.peek()? != &Token::Comma {
+                    break;
+                }
+                self.lexer.next()?;
+            }
+        }
+        self.lexer.expect(Token::ParR)?;
+
+        let block = self.block()?;
+        self.lexer.check_match(Token::End, Token::Function, start.line)?;
+        Ok(FuncBody { params, is_vararg, block, span: start.to(self.lexer.span()) })
+    }
+
+    fn explist(&mut self) -> Result<Vec<Exp>, SyntaxError> {
+        let mut exps = vec![self.exp()?];
+        while self.lexer.peek()? == &Token::Comma {
+            self.lexer.next()?;
+            exps.push(self.exp()?);
+        }
+        Ok(exps)
+    }
+
+    fn exp(&mut self) -> Result<Exp, SyntaxError> {
+        let t = self.lexer.next()?;
+        self.subexp(t, 0)
+    }
+
+    // binary operators with left priority higher than `limit`
+    fn subexp(&mut self, t: Token, limit: u8) -> Result<Exp, SyntaxError> {
+        self.enter_level()?;
+        let mut left = if let Some(op) = UnOp::from_token(&t) {
+            let start = self.lexer.span();
+            let t = self.lexer.next()?;
+            let operand = self.subexp(t, UNARY_PRIORITY)?;
+            Exp { span: start.to(operand.span), kind: ExpKind::UnOp(op, Box::new(operand)) }
+        } else {
+            self.simpleexp(t)?
+        };
+
+        while let Some(op) = BinOp::from_token(self.lexer.peek()?) {
+            let (left_priority, right_priority) = op.priority();
+            if left_priority <= limit {
+                break;
+            }
+            self.lexer.next()?;
+            let t = self.lexer.next()?;
+            let right = self.subexp(t, right_priority)?;
+            left = Exp {
+                span: left.span.to(right.span),
+                kind: ExpKind::BinOp(op, Box::new(left), Box::new(right)),
+            };
+        }
+        self.level -= 1;
+        Ok(left)
+    }
+
+    // like Lua's enterlevel(), to fail before the native stack overflows
+    fn enter_level(&mut self) -> Result<(), SyntaxError> {
+        self.level += 1;
+        if self.level > MAX_LEVELS {
+            return Err(self.lexer.error(format!("too many C levels (limit is {})", MAX_LEVELS)));
+        }
+        Ok(())
+    }
+
+    fn simpleexp(&mut self, t: Token) -> Result<Exp, SyntaxError> {
+        let span = self.lexer.span();
+        let kind = match t {
+            Token::Nil => ExpKind::Nil,
+            Token::True => ExpKind::Boolean(true),
+            Token::False => ExpKind::Boolean(false),
+            Token::Integer(i) => ExpKind::Integer(i),
+            Token::Float(f) => ExpKind::Float(f),
+            Token::String(s) => ExpKind::String(s),
+            Token::Dots => ExpKind::Vararg,
+            Token::Function => {
+                let body = self.func_body(span)?;
+                return Ok(Exp { span: body.span, kind: ExpKind::Function(body) });
+            }
+            Token::CurlyL => return self.table_constructor(),
+            t => return self.suffixedexp(t),
+        };
+        Ok(Exp { kind, span })
+    }
+
+    fn suffixedexp(&mut self, t: Token) -> Result<Exp, SyntaxError> {
+        let start = self.lexer.span();
+        let mut exp = match t {
+            Token::Ident(name) => Exp { kind: ExpKind::Name(name), span: start },
+            Token::ParL => {
+                let inner = self.exp()?;
+                self.lexer.check_match(Token::ParR, Token::ParL, start.line)?;
+                Exp { kind: ExpKind::Paren(Box::new(inner)), span: start.to(self.lexer.span()) }
+            }
+            t => return Err(self.lexer.error_near("unexpected symbol", &t)),
+        };
+
+        loop {
+            let kind = match self.lexer.peek()? {
+                Token::Dot => {
+                    self.lexer.next()?;
+                    let name = self.name()?;
+                    let key = Exp { kind: ExpKind::String(name.name.into_bytes()), span: name.span };
+                    ExpKind::Index(Box::new(exp), Box::new(key))
+                }
+                Token::SqurL => {
+                    self.lexer.next()?;
+                    let key = self.exp()?;
+                    self.lexer.expect(Token::SqurR)?;
+                    ExpKind::Index(Box::new(exp), Box::new(key))
+                }
+                Token::Colon => {
+                    self.lexer.next()?;
+                    let name = self.name()?;
+                    let args = self.args()?;
+                    ExpKind::MethodCall(Box::new(exp), name, args)
+                }
+                Token::ParL | Token::CurlyL | Token::String(_) => {
+                    let args = self.args()?;
+                    ExpKind::Call(Box::new(exp), args)
+                }
+                _ => break Ok(exp),
+            };
+            exp = Exp { kind, span: start.to(self.lexer.span()) };
+        }
+    }
+
+    fn args(&mut self) -> Result<Vec<Exp>, SyntaxError> {
+        match self.lexer.next()? {
+            Token::ParL => {
+                let line = self.lexer.line();
+                if self.lexer.peek()? == &Token::ParR {
+                    self.lexer.next()?;
+                    return Ok(Vec::new());
+                }
+                let args = self.explist()?;
+                self.lexer.check_match(Token::ParR, Token::ParL, line)?;
+                Ok(args)
+            }
+            Token::CurlyL => Ok(vec![self.table_constructor()?]),
+            Token::String(s) => Ok(vec![Exp { kind: ExpKind::String(s), span: self.lexer.span() }]),
+            t => Err(self.lexer.error_near("function arguments expected", &t)),
+        }
+    }
+
+    // after the `{`
+    fn table_constructor(&mut self) -> Result<Exp, SyntaxError> {
+        let start = self.lexer.span();
+        let mut fields = Vec::new();
+        loop {
+            let field = match self.lexer.next()? {
+                Token::CurlyR => break,
+                Token::SqurL => {
+                    let key = self.exp()?;
+                    self.lexer.expect(Token::SqurR)?;
+                    self.lexer.expect(Token::Assign)?;
+                    Field::Keyed(key, self.exp()?)
+                }
+                Token::Ident(name) if self.lexer.peek()? == &Token::Assign => {
+                    let name = Name { name, span: self.lexer.span() };
+                    self.lexer.next()?;
+                    Field::Named(name, self.exp()?)
+                }
+                t => Field::Positional(self.subexp(t, 0)?),
+            };
+            fields.push(field);
+
+            match self.lexer.next()? {
+                Token::Comma | Token::SemiColon => (),
+                Token::CurlyR => break,
+                t => return Err(self.lexer.match_error(Token::CurlyR, Token::CurlyL, start.line, &t)),
+            }
+        }
+        Ok(Exp { kind: ExpKind::Table(fields), span: start.to(self.lexer.span()) })
+    }
+
+    fn name(&mut self) -> Result<Name, SyntaxError> {
+        match self.lexer.next()? {
+            Token::Ident(name) => Ok(Name { name, span: self.lexer.span() }),
+            t => Err(self.lexer.error_near("<name> expected", &t)),
+        }
+    }
+}
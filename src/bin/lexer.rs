@@ -1,5 +1,6 @@
 use std::env;
 use std::fs::File;
+use std::process;
 
 use rlua::lexer::{Lexer, Token};
 
@@ -11,12 +12,16 @@ fn main() {
     }
 
     let file = File::open(&args[1]).unwrap();
-    let mut lexer = Lexer::new(file);
+    let mut lexer = Lexer::new(file, &args[1]);
 
     loop {
         match lexer.next() {
-            Token::Eos => break,
-            any => {println!("{:?}", any);},
+            Ok(Token::Eos) => break,
+            Ok(any) => {println!("{:?}", any);},
+            Err(e) => {
+                eprintln!("{}: {}", args[0], e);
+                process::exit(1);
+            }
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,80 @@
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::process;
+use std::rc::Rc;
+
+use rlua::{disasm, dump, parser, proto::Proto};
+
+fn usage(progname: &str) -> ! {
+    eprintln!("Usage: {} [-l] [-p] [-s] [-o output] <filename>", progname);
+    process::exit(1);
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let mut output = "rluac.out".to_string();
+    let mut strip = false;
+    let mut list = false;
+    let mut parse_only = false;
+    let mut filename = None;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-l" => list = true,
+            "-p" => parse_only = true,
+            "-s" => strip = true,
+            "-o" => match iter.next() {
+                Some(o) => output = o.clone(),
+                None => usage(&args[0]),
+            },
+            _ if filename.is_none() && !arg.starts_with('-') => filename = Some(arg.clone()),
+            _ => usage(&args[0]),
+        }
+    }
+    let Some(filename) = filename else {
+        usage(&args[0]);
+    };
+
+    let file = File::open(&filename).unwrap();
+    let mut input = BufReader::new(file);
+
+    // precompiled chunks can be listed or stripped too
+    let binary = input.fill_buf().is_ok_and(|buf| buf.starts_with(&dump::SIGNATURE[..1]));
+    let result: Result<Rc<Proto>, Box<dyn Error>> = if binary {
+        dump::undump(input, &filename).map_err(Into::into)
+    } else {
+        let options = parser::CompileOptions {
+            chunk_name: filename,
+            strip,
+            ..Default::default()
+        };
+        parser::ParseProto::load(input, options).map_err(Into::into)
+    };
+    let proto = match result {
+        Ok(proto) => proto,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            process::exit(1);
+        }
+    };
+
+    if list {
+        let mut stdout = io::stdout().lock();
+        if let Err(e) = disasm::list(&proto, &mut stdout) {
+            eprintln!("{}: cannot write listing: {}", args[0], e);
+            process::exit(1);
+        }
+    }
+    if parse_only {
+        return;
+    }
+
+    let mut out = BufWriter::new(File::create(&output).unwrap());
+    if let Err(e) = dump::dump(&proto, &mut out, strip).and_then(|_| out.flush()) {
+        eprintln!("{}: cannot write {}: {}", args[0], output, e);
+        process::exit(1);
+    }
+}
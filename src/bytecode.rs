@@ -1,14 +1,38 @@
-#[derive(Debug)]
+/// Registers are addressed by `u8` operands.
+pub const MAX_REGS: usize = 255;
+
+/// Largest operand carried by an `ExtraArg`.
+pub const MAX_EXTRA_ARG: usize = (1 << 24) - 1;
+
+/// Count operand of `Call`, `Return` and `SetList` standing for all the
+/// values up to the top of the stack, as left by a `Call` with this many
+/// results just before. No register range is this long.
+pub const MULTRET: u8 = u8::MAX;
+
+/// One instruction of a compiled function.
+///
+/// The enum fits in a 32-bit word, like Lua's instructions: operands are a
+/// byte each, or a byte and 16 bits, with `ExtraArg` for those which do not
+/// fit. Its layout is still Rust's, not a packed opcode + A/B/C/Bx/sBx word
+/// decoded by hand, except in binary chunks; see `Bytecode::encode()`.
+#[derive(Debug, Clone, Copy)]
 pub enum Bytecode {
     GetGlobal(u8, u8),
     SetGlobal(u8, u8),
     SetGlobalConst(u8, u8),
+    GetGlobalX(u8),
+    SetGlobalX(u8),
     LoadConst(u8, u16),
+    LoadConstX(u8),
     LoadNil(u8, u8),
     LoadBool(u8, bool),
     LoadInt(u8, i16),
     Move(u8, u8),
-    Call(u8, u8),
+    Call(u8, u8, u8), // (func, nargs, nresults)
+    Return(u8, u8), // (first, n)
+    Closure(u8, u16), // (dst, index of the nested prototype)
+    Tbc(u8), // mark the variable in the register to be closed
+    Close(u8), // close the to-be-closed variables from the register up
     NewTable(u8, u8, u8),
     SetTable(u8, u8, u8),
     GetTable(u8, u8, u8),
@@ -20,4 +44,196 @@ pub enum Bytecode {
     SetFieldConst(u8, u8, u8),
     SetIntConst(u8, u8, u8),
     SetList(u8, u8, u8),
-}
\ No newline at end of file
+    SetListX(u8, u8),
+
+    // (dst, a, b), all in registers
+    Add(u8, u8, u8),
+    Sub(u8, u8, u8),
+    Mul(u8, u8, u8),
+    Div(u8, u8, u8),
+    IDiv(u8, u8, u8),
+    Mod(u8, u8, u8),
+    Pow(u8, u8, u8),
+    BAnd(u8, u8, u8),
+    BOr(u8, u8, u8),
+    BXor(u8, u8, u8),
+    Shl(u8, u8, u8),
+    Shr(u8, u8, u8),
+    Concat(u8, u8, u8),
+    Eq(u8, u8, u8),
+    Ne(u8, u8, u8),
+    Lt(u8, u8, u8),
+    Le(u8, u8, u8),
+
+    // (dst, src)
+    Unm(u8, u8),
+    Not(u8, u8),
+    Len(u8, u8),
+    BNot(u8, u8),
+
+    // offsets are relative to the next instruction
+    Jump(i16),
+    JumpFalse(u8, i16),
+    JumpTrue(u8, i16),
+
+    // (a, b, k): run the next instruction, a `Jump`, only if the comparison
+    // result is `k`, otherwise skip it
+    TestEq(u8, u8, bool),
+    TestLt(u8, u8, bool),
+    TestLe(u8, u8, bool),
+
+    // (dst, src, immediate)
+    AddI(u8, u8, i8),
+
+    // operand of the previous `*X` instruction, split as (high, low)
+    ExtraArg(u8, u16),
+}
+
+// more would spread the code of a function over more cache lines
+const _: () = assert!(size_of::<Bytecode>() == 4);
+
+impl Bytecode {
+    pub fn extra_arg(n: usize) -> Self {
+        assert!(n <= MAX_EXTRA_ARG);
+        Bytecode::ExtraArg((n >> 16) as u8, n as u16)
+    }
+
+    pub fn extra_arg_value(&self) -> usize {
+        match self {
+            Bytecode::ExtraArg(high, low) => (*high as usize) << 16 | *low as usize,
+            code => panic!("expected ExtraArg: {:?}", code),
+        }
+    }
+    /// Opcode followed by the operands in little endian, as in binary chunks.
+    pub fn encode(&self) -> [u8; 4] {
+        match *self {
+            Bytecode::GetGlobal(a, b) => [0, a, b, 0],
+            Bytecode::SetGlobal(a, b) => [1, a, b, 0],
+            Bytecode::SetGlobalConst(a, b) => [2, a, b, 0],
+            Bytecode::GetGlobalX(a) => [3, a, 0, 0],
+            Bytecode::SetGlobalX(a) => [4, a, 0, 0],
+            Bytecode::LoadConst(a, b) => [5, a, b as u8, (b >> 8) as u8],
+            Bytecode::LoadConstX(a) => [6, a, 0, 0],
+            Bytecode::LoadNil(a, b) => [7, a, b, 0],
+            Bytecode::LoadBool(a, b) => [8, a, b as u8, 0],
+            Bytecode::LoadInt(a, b) => [9, a, b as u8, (b >> 8) as u8],
+            Bytecode::Move(a, b) => [10, a, b, 0],
+            Bytecode::Call(a, b, c) => [11, a, b, c],
+            Bytecode::NewTable(a, b, c) => [12, a, b, c],
+            Bytecode::SetTable(a, b, c) => [13, a, b, c],
+            Bytecode::GetTable(a, b, c) => [14, a, b, c],
+            Bytecode::SetField(a, b, c) => [15, a, b, c],
+            Bytecode::GetField(a, b, c) => [16, a, b, c],
+            Bytecode::SetInt(a, b, c) => [17, a, b, c],
+            Bytecode::GetInt(a, b, c) => [18, a, b, c],
+            Bytecode::SetTableConst(a, b, c) => [19, a, b, c],
+            Bytecode::SetFieldConst(a, b, c) => [20, a, b, c],
+            Bytecode::SetIntConst(a, b, c) => [21, a, b, c],
+            Bytecode::SetList(a, b, c) => [22, a, b, c],
+            Bytecode::SetListX(a, b) => [23, a, b, 0],
+            Bytecode::Add(a, b, c) => [24, a, b, c],
+            Bytecode::Sub(a, b, c) => [25, a, b, c],
+            Bytecode::Mul(a, b, c) => [26, a, b, c],
+            Bytecode::Div(a, b, c) => [27, a, b, c],
+            Bytecode::IDiv(a, b, c) => [28, a, b, c],
+            Bytecode::Mod(a, b, c) => [29, a, b, c],
+            Bytecode::Pow(a, b, c) => [30, a, b, c],
+            Bytecode::BAnd(a, b, c) => [31, a, b, c],
+            Bytecode::BOr(a, b, c) => [32, a, b, c],
+            Bytecode::BXor(a, b, c) => [33, a, b, c],
+            Bytecode::Shl(a, b, c) => [34, a, b, c],
+            Bytecode::Shr(a, b, c) => [35, a, b, c],
+            Bytecode::Concat(a, b, c) => [36, a, b, c],
+            Bytecode::Eq(a, b, c) => [37, a, b, c],
+            Bytecode::Ne(a, b, c) => [38, a, b, c],
+            Bytecode::Lt(a, b, c) => [39, a, b, c],
+            Bytecode::Le(a, b, c) => [40, a, b, c],
+            Bytecode::Unm(a, b) => [41, a, b, 0],
+            Bytecode::Not(a, b) => [42, a, b, 0],
+            Bytecode::Len(a, b) => [43, a, b, 0],
+            Bytecode::BNot(a, b) => [44, a, b, 0],
+            Bytecode::Jump(a) => [45, a as u8, (a >> 8) as u8, 0],
+            Bytecode::JumpFalse(a, b) => [46, a, b as u8, (b >> 8) as u8],
+            Bytecode::JumpTrue(a, b) => [47, a, b as u8, (b >> 8) as u8],
+            Bytecode::TestEq(a, b, c) => [48, a, b, c as u8],
+            Bytecode::TestLt(a, b, c) => [49, a, b, c as u8],
+            Bytecode::TestLe(a, b, c) => [50, a, b, c as u8],
+            Bytecode::AddI(a, b, c) => [51, a, b, c as u8],
+            Bytecode::ExtraArg(a, b) => [52, a, b as u8, (b >> 8) as u8],
+            Bytecode::Return(a, b) => [53, a, b, 0],
+            Bytecode::Closure(a, b) => [54, a, b as u8, (b >> 8) as u8],
+            Bytecode::Tbc(a) => [55, a, 0, 0],
+            Bytecode::Close(a) => [56, a, 0, 0],
+        }
+    }
+
+    /// `None` for invalid opcodes or operands.
+    pub fn decode(b: [u8; 4]) -> Option<Self> {
+        let bool = |b: u8| match b {
+            0 => Some(false),
+            1 => Some(true),
+            _ => None,
+        };
+        let code = match b[0] {
+            0 => Bytecode::GetGlobal(b[1], b[2]),
+            1 => Bytecode::SetGlobal(b[1], b[2]),
+            2 => Bytecode::SetGlobalConst(b[1], b[2]),
+            3 => Bytecode::GetGlobalX(b[1]),
+            4 => Bytecode::SetGlobalX(b[1]),
+            5 => Bytecode::LoadConst(b[1], u16::from_le_bytes([b[2], b[3]])),
+            6 => Bytecode::LoadConstX(b[1]),
+            7 => Bytecode::LoadNil(b[1], b[2]),
+            8 => Bytecode::LoadBool(b[1], bool(b[2])?),
+            9 => Bytecode::LoadInt(b[1], i16::from_le_bytes([b[2], b[3]])),
+            10 => Bytecode::Move(b[1], b[2]),
+            11 => Bytecode::Call(b[1], b[2], b[3]),
+            12 => Bytecode::NewTable(b[1], b[2], b[3]),
+            13 => Bytecode::SetTable(b[1], b[2], b[3]),
+            14 => Bytecode::GetTable(b[1], b[2], b[3]),
+            15 => Bytecode::SetField(b[1], b[2], b[3]),
+            16 => Bytecode::GetField(b[1], b[2], b[3]),
+            17 => Bytecode::SetInt(b[1], b[2], b[3]),
+            18 => Bytecode::GetInt(b[1], b[2], b[3]),
+            19 => Bytecode::SetTableConst(b[1], b[2], b[3]),
+            20 => Bytecode::SetFieldConst(b[1], b[2], b[3]),
+            21 => Bytecode::SetIntConst(b[1], b[2], b[3]),
+            22 => Bytecode::SetList(b[1], b[2], b[3]),
+            23 => Bytecode::SetListX(b[1], b[2]),
+            24 => Bytecode::Add(b[1], b[2], b[3]),
+            25 => Bytecode::Sub(b[1], b[2], b[3]),
+            26 => Bytecode::Mul(b[1], b[2], b[3]),
+            27 => Bytecode::Div(b[1], b[2], b[3]),
+            28 => Bytecode::IDiv(b[1], b[2], b[3]),
+            29 => Bytecode::Mod(b[1], b[2], b[3]),
+            30 => Bytecode::Pow(b[1], b[2], b[3]),
+            31 => Bytecode::BAnd(b[1], b[2], b[3]),
+            32 => Bytecode::BOr(b[1], b[2], b[3]),
+            33 => Bytecode::BXor(b[1], b[2], b[3]),
+            34 => Bytecode::Shl(b[1], b[2], b[3]),
+            35 => Bytecode::Shr(b[1], b[2], b[3]),
+            36 => Bytecode::Concat(b[1], b[2], b[3]),
+            37 => Bytecode::Eq(b[1], b[2], b[3]),
+            38 => Bytecode::Ne(b[1], b[2], b[3]),
+            39 => Bytecode::Lt(b[1], b[2], b[3]),
+            40 => Bytecode::Le(b[1], b[2], b[3]),
+            41 => Bytecode::Unm(b[1], b[2]),
+            42 => Bytecode::Not(b[1], b[2]),
+            43 => Bytecode::Len(b[1], b[2]),
+            44 => Bytecode::BNot(b[1], b[2]),
+            45 => Bytecode::Jump(i16::from_le_bytes([b[1], b[2]])),
+            46 => Bytecode::JumpFalse(b[1], i16::from_le_bytes([b[2], b[3]])),
+            47 => Bytecode::JumpTrue(b[1], i16::from_le_bytes([b[2], b[3]])),
+            48 => Bytecode::TestEq(b[1], b[2], bool(b[3])?),
+            49 => Bytecode::TestLt(b[1], b[2], bool(b[3])?),
+            50 => Bytecode::TestLe(b[1], b[2], bool(b[3])?),
+            51 => Bytecode::AddI(b[1], b[2], b[3] as i8),
+            52 => Bytecode::ExtraArg(b[1], u16::from_le_bytes([b[2], b[3]])),
+            53 => Bytecode::Return(b[1], b[2]),
+            54 => Bytecode::Closure(b[1], u16::from_le_bytes([b[2], b[3]])),
+            55 => Bytecode::Tbc(b[1]),
+            56 => Bytecode::Close(b[1]),
+            _ => return None,
+        };
+        Some(code)
+    }
+}
@@ -0,0 +1,162 @@
+use std::io::{self, Write};
+use crate::{bytecode::{Bytecode, MULTRET}, proto::Proto, value::Value};
+
+// Listing of compiled prototypes, in the manner of `luac -l`. Instructions
+// and jump targets are numbered from 1, registers are shown by the names
+// of the local variables in them if known, and constants inline.
+
+enum Arg {
+    Reg(u8),
+    Const(usize),
+    Int(i64),
+    Bool(bool),
+    Jump(i16),
+    Count(u8), // of values, or all up to the top
+    Missing,
+}
+
+/// Write the listing of the prototype and of its nested functions.
+pub fn list(proto: &Proto, out: &mut dyn Write) -> io::Result<()> {
+    list_function(proto, out, true)
+}
+
+fn list_function(proto: &Proto, out: &mut dyn Write, main: bool) -> io::Result<()> {
+    let source = proto.source.as_deref().unwrap_or("=?");
+    if main {
+        write!(out, "main <{}>", source)?;
+    } else {
+        write!(out, "function <{}:{}>", source, proto.linedefined)?;
+    }
+    writeln!(out, " ({} instruction{})", proto.bytecodes.len(), plural(proto.bytecodes.len()))?;
+    writeln!(out, "{} param{}, {} slot{}, {} local{}, {} constant{}, {} function{}",
+        proto.nparams, plural(proto.nparams),
+        proto.max_stack_size, plural(proto.max_stack_size),
+        proto.locvars.len(), plural(proto.locvars.len()),
+        proto.constants.len(), plural(proto.constants.len()),
+        proto.protos.len(), plural(proto.protos.len()))?;
+
+    for (pc, code) in proto.bytecodes.iter().enumerate() {
+        let line = match proto.line(pc) {
+            Some(line) => line.to_string(),
+            None => "-".to_string(),
+        };
+        let name = format!("{:?}", code);
+        let name = name.split('(').next().unwrap();
+        write!(out, "\t{}\t[{}]\t{:<14}", pc + 1, line, name)?;
+
+        let args = operands(*code, proto.bytecodes.get(pc + 1));
+        for (i, arg) in args.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            match *arg {
+                Arg::Reg(r) => match proto.local_name(r as usize, pc) {
+                    Some(name) => write!(out, "{}{}", sep, name)?,
+                    None => write!(out, "{}R{}", sep, r)?,
+                },
+                Arg::Const(k) => match proto.constants.get(k) {
+                    Some(c) => write!(out, "{}{}", sep, constant(c))?,
+                    None => write!(out, "{}K{}?", sep, k)?,
+                },
+                Arg::Int(i) => write!(out, "{}{}", sep, i)?,
+                Arg::Bool(b) => write!(out, "{}{}", sep, b)?,
+                Arg::Jump(offset) => {
+                    let target = (pc + 2) as isize + offset as isize;
+                    write!(out, "{}to {}", sep, target)?
+                }
+                Arg::Count(MULTRET) => write!(out, "{}all", sep)?,
+                Arg::Count(n) => write!(out, "{}{}", sep, n)?,
+                Arg::Missing => write!(out, "{}?", sep)?,
+            }
+        }
+        writeln!(out)?;
+    }
+
+    writeln!(out, "constants ({}):", proto.constants.len())?;
+    for (i, c) in proto.constants.iter().enumerate() {
+        writeln!(out, "\t{}\t{}\t{}", i, c.type_name(), constant(c))?;
+    }
+    writeln!(out, "locals ({}):", proto.locvars.len())?;
+    for (i, v) in proto.locvars.iter().enumerate() {
+        writeln!(out, "\t{}\t{}\t{}\t{}", i, v.name, v.startpc + 1, v.endpc + 1)?;
+    }
+
+    for p in &proto.protos {
+        writeln!(out)?;
+        list_function(p, out, false)?;
+    }
+    Ok(())
+}
+
+fn plural(n: usize) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+// `next` is the following instruction, carrying the operand of `*X` ones
+fn operands(code: Bytecode, next: Option<&Bytecode>) -> Vec<Arg> {
+    use Arg::*;
+    let extra = match next {
+        Some(next @ Bytecode::ExtraArg(..)) => Some(next.extra_arg_value()),
+        _ => None,
+    };
+    match code {
+        Bytecode::GetGlobal(a, k) => vec![Reg(a), Const(k as usize)],
+        Bytecode::SetGlobal(k, a) => vec![Const(k as usize), Reg(a)],
+        Bytecode::SetGlobalConst(k, v) => vec![Const(k as usize), Const(v as usize)],
+        Bytecode::GetGlobalX(a) | Bytecode::SetGlobalX(a) | Bytecode::LoadConstX(a) =>
+            vec![Reg(a), extra.map_or(Missing, Const)],
+        Bytecode::LoadConst(a, k) => vec![Reg(a), Const(k as usize)],
+        Bytecode::LoadNil(a, n) => vec![Reg(a), Int(n as i64)],
+        Bytecode::LoadBool(a, b) => vec![Reg(a), Bool(b)],
+        Bytecode::LoadInt(a, i) => vec![Reg(a), Int(i as i64)],
+        Bytecode::Call(a, nargs, nresults) => vec![Reg(a), Count(nargs), Count(nresults)],
+        Bytecode::Return(a, n) => vec![Reg(a), Count(n)],
+        Bytecode::Closure(a, i) => vec![Reg(a), Int(i as i64)],
+        Bytecode::Tbc(a) | Bytecode::Close(a) => vec![Reg(a)],
+        Bytecode::NewTable(a, narray, nmap) => vec![Reg(a), Int(narray as i64), Int(nmap as i64)],
+        Bytecode::SetTable(t, k, v) => vec![Reg(t), Reg(k), Reg(v)],
+        Bytecode::SetField(t, k, v) => vec![Reg(t), Const(k as usize), Reg(v)],
+        Bytecode::SetInt(t, i, v) => vec![Reg(t), Int(i as i64), Reg(v)],
+        Bytecode::SetTableConst(t, k, v) => vec![Reg(t), Reg(k), Const(v as usize)],
+        Bytecode::SetFieldConst(t, k, v) => vec![Reg(t), Const(k as usize), Const(v as usize)],
+        Bytecode::SetIntConst(t, i, v) => vec![Reg(t), Int(i as i64), Const(v as usize)],
+        Bytecode::GetField(a, t, k) => vec![Reg(a), Reg(t), Const(k as usize)],
+        Bytecode::GetInt(a, t, i) => vec![Reg(a), Reg(t), Int(i as i64)],
+        Bytecode::SetList(t, n, stored) => vec![Reg(t), Count(n), Int(stored as i64)],
+        Bytecode::SetListX(t, n) => vec![Reg(t), Count(n), extra.map_or(Missing, |n| Int(n as i64))],
+        Bytecode::GetTable(a, b, c) | Bytecode::Add(a, b, c) | Bytecode::Sub(a, b, c)
+            | Bytecode::Mul(a, b, c) | Bytecode::Div(a, b, c) | Bytecode::IDiv(a, b, c)
+            | Bytecode::Mod(a, b, c) | Bytecode::Pow(a, b, c) | Bytecode::BAnd(a, b, c)
+            | Bytecode::BOr(a, b, c) | Bytecode::BXor(a, b, c) | Bytecode::Shl(a, b, c)
+            | Bytecode::Shr(a, b, c) | Bytecode::Concat(a, b, c) | Bytecode::Eq(a, b, c)
+            | Bytecode::Ne(a, b, c) | Bytecode::Lt(a, b, c) | Bytecode::Le(a, b, c) =>
+            vec![Reg(a), Reg(b), Reg(c)],
+        Bytecode::Move(a, b) | Bytecode::Unm(a, b) | Bytecode::Not(a, b) | Bytecode::Len(a, b)
+            | Bytecode::BNot(a, b) => vec![Reg(a), Reg(b)],
+        Bytecode::Jump(offset) => vec![Jump(offset)],
+        Bytecode::JumpFalse(a, offset) | Bytecode::JumpTrue(a, offset) => vec![Reg(a), Jump(offset)],
+        Bytecode::TestEq(a, b, k) | Bytecode::TestLt(a, b, k) | Bytecode::TestLe(a, b, k) =>
+            vec![Reg(a), Reg(b), Bool(k)],
+        Bytecode::AddI(a, b, i) => vec![Reg(a), Reg(b), Int(i as i64)],
+        Bytecode::ExtraArg(..) => vec![Int(code.extra_arg_value() as i64)],
+    }
+}
+
+// constants as they would be written in the source
+fn constant(c: &Value) -> String {
+    if !c.is_string() {
+        return c.to_string();
+    }
+    let mut s = String::from("\"");
+    for &b in <&[u8]>::from(c) {
+        match b {
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            b'\n' => s.push_str("\\n"),
+            b'\r' => s.push_str("\\r"),
+            b'\t' => s.push_str("\\t"),
+            b' ' ..= b'~' => s.push(b as char),
+            _ => s.push_str(&format!("\\{}", b)),
+        }
+    }
+    s.push('"');
+    s
+}
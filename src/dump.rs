@@ -0,0 +1,302 @@
+use std::{error::Error, fmt, io::{self, Read, Write}, rc::Rc};
+use crate::{bytecode::{Bytecode, MAX_REGS}, proto::{AbsLineInfo, LocVar, Proto, ABSLINEINFO}, value::Value, verify};
+
+// Binary chunks of compiled prototypes. Numbers are written in little
+// endian and sizes as LEB128 varints, whatever the host is.
+
+/// First bytes of a binary chunk. Source files can not start with ESC.
+pub const SIGNATURE: &[u8] = b"\x1bRlua";
+
+const FORMAT_VERSION: u8 = 5;
+
+// catches newline and encoding conversions, like Lua's LUAC_DATA
+const CHECK_DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+const CHECK_INT: i64 = 0x5678;
+const CHECK_FLOAT: f64 = 370.5;
+
+// nested prototypes deeper than this are rejected
+const MAX_NESTING: usize = 200;
+
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INTEGER: u8 = 3;
+const TAG_FLOAT: u8 = 4;
+const TAG_STRING: u8 = 5;
+
+/// Write the prototype as a binary chunk, without debug info if `strip`.
+pub fn dump(proto: &Proto, out: &mut impl Write, strip: bool) -> io::Result<()> {
+    out.write_all(SIGNATURE)?;
+    out.write_all(&[FORMAT_VERSION])?;
+    out.write_all(CHECK_DATA)?;
+    out.write_all(&CHECK_INT.to_le_bytes())?;
+    out.write_all(&CHECK_FLOAT.to_le_bytes())?;
+    dump_function(proto, out, strip)
+}
+
+fn dump_function(proto: &Proto, out: &mut impl Write, strip: bool) -> io::Result<()> {
+    // 0 for no source, or its length plus 1
+    match proto.source.as_ref().filter(|_| !strip) {
+        Some(source) => dump_string(source.as_bytes(), 1, out)?,
+        None => dump_size(0, out)?,
+    }
+    dump_size(proto.linedefined as usize, out)?;
+    dump_size(proto.nparams, out)?;
+    dump_size(proto.max_stack_size, out)?;
+
+    dump_size(proto.bytecodes.len(), out)?;
+    for code in &proto.bytecodes {
+        out.write_all(&code.encode())?;
+    }
+
+    dump_size(proto.constants.len(), out)?;
+    for c in &proto.constants {
+        match c {
+            Value::Nil => out.write_all(&[TAG_NIL])?,
+            Value::Boolean(false) => out.write_all(&[TAG_FALSE])?,
+            Value::Boolean(true) => out.write_all(&[TAG_TRUE])?,
+            Value::Integer(i) => {
+                out.write_all(&[TAG_INTEGER])?;
+                out.write_all(&i.to_le_bytes())?;
+            }
+            Value::Float(f) => {
+                out.write_all(&[TAG_FLOAT])?;
+                out.write_all(&f.to_le_bytes())?;
+            }
+            v if v.is_string() => {
+                out.write_all(&[TAG_STRING])?;
+                dump_string(v.into(), 0, out)?;
+            }
+            v => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("can not dump constant {:?}", v))),
+        }
+    }
+
+    dump_size(proto.protos.len(), out)?;
+    for p in &proto.protos {
+        dump_function(p, out, strip)?;
+    }
+
+    if strip {
+        dump_size(0, out)?;
+        dump_size(0, out)?;
+        return dump_size(0, out);
+    }
+    dump_size(proto.lineinfo.len(), out)?;
+    out.write_all(&proto.lineinfo.iter().map(|&d| d as u8).collect::<Vec<_>>())?;
+    dump_size(proto.abslineinfo.len(), out)?;
+    for abs in &proto.abslineinfo {
+        dump_size(abs.pc, out)?;
+        dump_size(abs.line as usize, out)?;
+    }
+    dump_size(proto.locvars.len(), out)?;
+    for v in &proto.locvars {
+        dump_string(v.name.as_bytes(), 0, out)?;
+        dump_size(v.startpc, out)?;
+        dump_size(v.endpc, out)?;
+    }
+    Ok(())
+}
+
+fn dump_string(s: &[u8], bias: usize, out: &mut impl Write) -> io::Result<()> {
+    dump_size(s.len() + bias, out)?;
+    out.write_all(s)
+}
+
+fn dump_size(mut n: usize, out: &mut impl Write) -> io::Result<()> {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            return out.write_all(&[byte]);
+        }
+        out.write_all(&[byte | 0x80])?;
+    }
+}
+
+#[derive(Debug)]
+pub struct UndumpError {
+    pub chunk_name: String,
+    pub message: String,
+}
+
+impl fmt::Display for UndumpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.chunk_name, self.message)
+    }
+}
+
+impl Error for UndumpError {}
+
+/// Load a binary chunk written by `dump()`. The code is verified, so a
+/// malformed chunk is rejected here rather than failing in the VM.
+pub fn undump(input: impl Read, chunk_name: &str) -> Result<Rc<Proto>, UndumpError> {
+    let mut undump = Undump { input, chunk_name };
+    undump.header()?;
+    undump.function(0)
+}
+
+struct Undump<'a, R> {
+    input: R,
+    chunk_name: &'a str,
+}
+
+impl<R: Read> Undump<'_, R> {
+    fn error(&self, why: &str) -> UndumpError {
+        UndumpError {
+            chunk_name: self.chunk_name.to_string(),
+            message: format!("bad binary format ({})", why),
+        }
+    }
+
+    fn read_error(&self, e: io::Error) -> UndumpError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            self.error("truncated chunk")
+        } else {
+            UndumpError { chunk_name: self.chunk_name.to_string(), message: e.to_string() }
+        }
+    }
+
+    fn bytes<const N: usize>(&mut self) -> Result<[u8; N], UndumpError> {
+        let mut buf = [0; N];
+        self.input.read_exact(&mut buf).map_err(|e| self.read_error(e))?;
+        Ok(buf)
+    }
+
+    fn byte(&mut self) -> Result<u8, UndumpError> {
+        Ok(self.bytes::<1>()?[0])
+    }
+
+    fn check(&mut self, expected: &[u8], why: &str) -> Result<(), UndumpError> {
+        let mut buf = vec![0; expected.len()];
+        self.input.read_exact(&mut buf).map_err(|e| self.read_error(e))?;
+        if buf != expected {
+            return Err(self.error(why));
+        }
+        Ok(())
+    }
+
+    fn header(&mut self) -> Result<(), UndumpError> {
+        self.check(SIGNATURE, "not a binary chunk")?;
+        self.check(&[FORMAT_VERSION], "version mismatch")?;
+        self.check(CHECK_DATA, "corrupted chunk")?;
+        self.check(&CHECK_INT.to_le_bytes(), "integer format mismatch")?;
+        self.check(&CHECK_FLOAT.to_le_bytes(), "float format mismatch")?;
+        Ok(())
+    }
+
+    fn size(&mut self) -> Result<usize, UndumpError> {
+        let mut n: usize = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7f) as usize;
+            if shift >= usize::BITS || (bits << shift) >> shift != bits {
+                return Err(self.error("integer overflow"));
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+            shift += 7;
+        }
+    }
+
+    fn string(&mut self, len: usize) -> Result<Vec<u8>, UndumpError> {
+        // the length is not trusted for allocation
+        let mut s = Vec::new();
+        let n = (&mut self.input).take(len as u64).read_to_end(&mut s)
+            .map_err(|e| self.read_error(e))?;
+        if n < len {
+            return Err(self.error("truncated chunk"));
+        }
+        Ok(s)
+    }
+
+    fn function(&mut self, depth: usize) -> Result<Rc<Proto>, UndumpError> {
+        if depth > MAX_NESTING {
+            return Err(self.error("too deeply nested"));
+        }
+
+        let source = match self.size()? {
+            0 => None,
+            n => Some(String::from_utf8_lossy(&self.string(n - 1)?).into_owned()),
+        };
+        let linedefined = u32::try_from(self.size()?).map_err(|_| self.error("integer overflow"))?;
+        let nparams = self.size()?;
+        let max_stack_size = self.size()?;
+        if max_stack_size > MAX_REGS {
+            return Err(self.error("stack size too large"));
+        }
+        if nparams > max_stack_size {
+            return Err(self.error("too many parameters"));
+        }
+
+        let mut bytecodes = Vec::new();
+        for _ in 0 .. self.size()? {
+            let Some(code) = Bytecode::decode(self.bytes()?) else {
+                return Err(self.error("invalid instruction"));
+            };
+            bytecodes.push(code);
+        }
+
+        let mut constants = Vec::new();
+        for _ in 0 .. self.size()? {
+            let c = match self.byte()? {
+                TAG_NIL => Value::Nil,
+                TAG_FALSE => Value::Boolean(false),
+                TAG_TRUE => Value::Boolean(true),
+                TAG_INTEGER => Value::Integer(i64::from_le_bytes(self.bytes()?)),
+                TAG_FLOAT => Value::Float(f64::from_le_bytes(self.bytes()?)),
+                TAG_STRING => {
+                    let len = self.size()?;
+                    self.string(len)?.into()
+                }
+                _ => return Err(self.error("invalid constant")),
+            };
+            constants.push(c);
+        }
+
+        let mut protos = Vec::new();
+        for _ in 0 .. self.size()? {
+            protos.push(self.function(depth + 1)?);
+        }
+
+        // debug info, which is either absent or about all the instructions
+        let nlines = self.size()?;
+        if nlines != 0 && nlines != bytecodes.len() {
+            return Err(self.error("invalid debug info"));
+        }
+        let lineinfo: Vec<i8> = self.string(nlines)?.into_iter().map(|d| d as i8).collect();
+        let mut abslineinfo = Vec::new();
+        for _ in 0 .. self.size()? {
+            let pc = self.size()?;
+            let line = u32::try_from(self.size()?).map_err(|_| self.error("integer overflow"))?;
+            abslineinfo.push(AbsLineInfo { pc, line });
+        }
+        // each marked instruction has its absolute line, in order
+        let marked = lineinfo.iter().enumerate().filter(|(_, d)| **d == ABSLINEINFO).map(|(pc, _)| pc);
+        if !marked.eq(abslineinfo.iter().map(|abs| abs.pc)) {
+            return Err(self.error("invalid debug info"));
+        }
+        let mut locvars = Vec::new();
+        for _ in 0 .. self.size()? {
+            let len = self.size()?;
+            let name = String::from_utf8_lossy(&self.string(len)?).into_owned();
+            let (startpc, endpc) = (self.size()?, self.size()?);
+            if startpc > endpc || endpc > bytecodes.len() {
+                return Err(self.error("invalid debug info"));
+            }
+            locvars.push(LocVar { name, startpc, endpc });
+        }
+
+        let proto = Proto {
+            constants, bytecodes, protos, max_stack_size, nparams, linedefined,
+            source, lineinfo, abslineinfo, locvars,
+        };
+
+        // binary chunks may come from anywhere, unlike the compiler output
+        verify::verify(&proto).map_err(|why| self.error(&why))?;
+        Ok(Rc::new(proto))
+    }
+}
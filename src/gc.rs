@@ -0,0 +1,171 @@
+use std::{cell::RefCell, collections::HashMap, mem, rc::{Rc, Weak}};
+use crate::{value::{RustClosure, Table, Value}, vm::Coroutine};
+
+// The values stay reference counted, which frees all garbage but cycles at
+// once. All tables, coroutines and Rust closures, the only values which may
+// refer to others, are also tracked here, and a tracing collection finds
+// those unreachable from the roots and empties them, breaking their cycles.
+// Strings and Lua functions refer to no tracked ones, so they are freed by
+// their counts alone.
+//
+// Values held by Rust code, such as the host or a running Rust function,
+// are not on the stack. Objects referred to more often than by the others
+// are counted as roots for them.
+
+// collect after this many new objects at least
+const MIN_THRESHOLD: usize = 1000;
+
+enum Object {
+    Table(Weak<RefCell<Table>>),
+    Thread(Weak<RefCell<Coroutine>>),
+    Closure(Weak<RustClosure>),
+}
+
+/// The objects which may form reference cycles.
+pub struct Heap {
+    objects: Vec<Object>,
+    threshold: usize, // number of objects to start a collection at
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap { objects: Vec::new(), threshold: MIN_THRESHOLD }
+    }
+
+    /// Track `value` if it may refer to others. It is then emptied once
+    /// unreachable, whatever else refers to it.
+    pub fn track(&mut self, value: &Value) {
+        let object = match value {
+            Value::Table(t) => Object::Table(Rc::downgrade(t)),
+            Value::Thread(co) => Object::Thread(Rc::downgrade(co)),
+            Value::RustClosure(c) => Object::Closure(Rc::downgrade(c)),
+            _ => return,
+        };
+        self.objects.push(object);
+    }
+
+    /// There are new objects enough for a collection, twice as many as
+    /// were left by the last one, like Lua's default pause.
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.threshold
+    }
+
+    /// Free the objects unreachable from `roots` and from Rust code, and
+    /// return how many.
+    pub fn collect<'a>(&mut self, roots: impl IntoIterator<Item = &'a Value>) -> usize {
+        // the living ones, kept alive until the end
+        let live: Vec<Value> = mem::take(&mut self.objects).into_iter().filter_map(|object| match object {
+            Object::Table(t) => t.upgrade().map(Value::Table),
+            Object::Thread(co) => co.upgrade().map(Value::Thread),
+            Object::Closure(c) => c.upgrade().map(Value::RustClosure),
+        }).collect();
+        let index: HashMap<*const (), usize> = live.iter().enumerate()
+            .map(|(i, v)| (address(v).unwrap(), i))
+            .collect();
+
+        // the references from outside the objects, less ours
+        let mut outside: Vec<usize> = live.iter().map(|v| strong_count(v) - 1).collect();
+        for v in &live {
+            // those in use can not be looked into, and are kept
+            let _ = children(v, &mut |child| {
+                if let Some(&i) = address(child).and_then(|p| index.get(&p)) {
+                    outside[i] -= 1;
+                }
+            });
+        }
+
+        // mark
+        let mut marked = vec![false; live.len()];
+        let mut pending: Vec<usize> = (0 .. live.len())
+            .filter(|&i| outside[i] > 0 || !can_look_into(&live[i]))
+            .chain(roots.into_iter().filter_map(|v| address(v).and_then(|p| index.get(&p).copied())))
+            .collect();
+        while let Some(i) = pending.pop() {
+            if mem::replace(&mut marked[i], true) {
+                continue;
+            }
+            let _ = children(&live[i], &mut |child| {
+                if let Some(&j) = address(child).and_then(|p| index.get(&p)) && !marked[j] {
+                    pending.push(j);
+                }
+            });
+        }
+
+        // sweep, by emptying the garbage which is then freed with `live`
+        let mut freed = 0;
+        for (v, marked) in live.iter().zip(&marked) {
+            if *marked {
+                self.track(v);
+                continue;
+            }
+            freed += 1;
+            match v {
+                Value::Table(t) => {
+                    let mut t = t.borrow_mut();
+                    t.array.clear();
+                    t.map.clear();
+                    t.metatable = None;
+                }
+                Value::Thread(co) => co.borrow_mut().clear(),
+                // fixed, and so parts of cycles only through the others
+                _ => (),
+            }
+        }
+        self.threshold = MIN_THRESHOLD.max(self.objects.len() * 2);
+        freed
+    }
+}
+
+// of the tracked value `v`
+fn strong_count(v: &Value) -> usize {
+    match v {
+        Value::Table(t) => Rc::strong_count(t),
+        Value::Thread(co) => Rc::strong_count(co),
+        Value::RustClosure(c) => Rc::strong_count(c),
+        _ => unreachable!(),
+    }
+}
+
+fn address(v: &Value) -> Option<*const ()> {
+    match v {
+        Value::Table(t) => Some(Rc::as_ptr(t) as *const ()),
+        Value::Thread(co) => Some(Rc::as_ptr(co) as *const ()),
+        Value::RustClosure(c) => Some(Rc::as_ptr(c) as *const ()),
+        _ => None,
+    }
+}
+
+fn can_look_into(v: &Value) -> bool {
+    match v {
+        Value::Table(t) => t.try_borrow().is_ok(),
+        Value::Thread(co) => co.try_borrow().is_ok(),
+        _ => true,
+    }
+}
+
+// call `f` with the values `v` refers to, or fail if it is in use
+fn children(v: &Value, f: &mut dyn FnMut(&Value)) -> Result<(), ()> {
+    match v {
+        Value::Table(t) => {
+            let t = t.try_borrow().map_err(|_| ())?;
+            t.array.iter().for_each(&mut *f);
+            for (key, value) in &t.map {
+                f(key);
+                f(value);
+            }
+            if let Some(mt) = &t.metatable {
+                f(&Value::Table(mt.clone()));
+            }
+        }
+        Value::Thread(co) => co.try_borrow().map_err(|_| ())?.values().for_each(f),
+        Value::RustClosure(c) => c.upvalues.iter().for_each(f),
+        _ => (),
+    }
+    Ok(())
+}
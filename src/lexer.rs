@@ -1,4 +1,4 @@
-use std::{io::{Bytes, Read}, iter::Peekable};
+use std::{fmt, io::{BufReader, Bytes, Read}, iter::Peekable};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -27,336 +27,555 @@ pub enum Token {
     Eos
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Add => "+",
+            Token::Sub => "-",
+            Token::Mul => "*",
+            Token::Div => "/",
+            Token::Mod => "%",
+            Token::Pow => "^",
+            Token::Len => "#",
+            Token::BitAnd => "&",
+            Token::BitXor => "~",
+            Token::BitOr => "|",
+            Token::ShiftL => "<<",
+            Token::ShiftR => ">>",
+            Token::Idiv => "//",
+            Token::Equal => "==",
+            Token::NotEq => "~=",
+            Token::LesEq => "<=",
+            Token::GreEq => ">=",
+            Token::Less => "<",
+            Token::Greater => ">",
+            Token::Assign => "=",
+            Token::ParL => "(",
+            Token::ParR => ")",
+            Token::CurlyL => "{",
+            Token::CurlyR => "}",
+            Token::SqurL => "[",
+            Token::SqurR => "]",
+            Token::DoubColon => "::",
+            Token::SemiColon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Integer(i) => return write!(f, "{}", i),
+            Token::Float(n) => return write!(f, "{:?}", n),
+            Token::Ident(name) => name,
+            Token::String(s) => return write!(f, "\"{}\"", String::from_utf8_lossy(s)),
+            Token::Eos => "<eof>",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Error raised while loading a chunk, displayed like Lua's
+/// `chunkname:line: message`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntaxError {
+    pub chunk_name: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.chunk_name, self.line, self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+/// Source range of a token or a syntax node: byte offsets and the line
+/// where it starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+}
+
+impl Span {
+    /// Span from the start of `self` to the end of `other`.
+    pub fn to(self, other: Span) -> Span {
+        Span { start: self.start, end: other.end, line: self.line }
+    }
+}
+
 pub struct Lexer<R: Read> {
-    bytes: Peekable<Bytes<R>>,
-    ahead: Option<Token>,
+    bytes: Peekable<Bytes<BufReader<R>>>,
+    ahead: Option<(Token, Span)>,
+
+    chunk_name: String,
+    cur_line: usize, // line of the reading position
+    offset: usize, // byte offset of the reading position
+    span: Span, // span of the last token returned by `next()`
 }
 
 impl<R: Read> Lexer<R> {
-    pub fn new(input: R) -> Self {
+    pub fn new(input: R, chunk_name: &str) -> Self {
         Self {
-            bytes: input.bytes().into_iter().peekable(),
+            bytes: BufReader::new(input).bytes().peekable(),
             ahead: None,
+            chunk_name: chunk_name.to_string(),
+            cur_line: 1,
+            offset: 0,
+            span: Span { start: 0, end: 0, line: 1 },
         }
     }
 
-    pub fn peek(&mut self) -> &Token {
+    pub fn chunk_name(&self) -> &str {
+        &self.chunk_name
+    }
+
+    /// Line of the last token returned by `next()`.
+    pub fn line(&self) -> usize {
+        self.span.line
+    }
+
+    /// Span of the last token returned by `next()`.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    pub fn peek(&mut self) -> Result<&Token, SyntaxError> {
         if self.ahead.is_none() {
-            self.ahead = Some(self.next());
+            // peeking does not move the position reported in errors
+            let span = self.span;
+            let token = self.next()?;
+            self.ahead = Some((token, self.span));
+            self.span = span;
         }
-        self.ahead.as_ref().unwrap()
+        Ok(&self.ahead.as_ref().unwrap().0)
     }
 
-    pub fn next(&mut self) -> Token {
-        if self.ahead.is_some() {
-            return self.ahead.take().unwrap();
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Result<Token, SyntaxError> {
+        if let Some((token, span)) = self.ahead.take() {
+            self.span = span;
+            return Ok(token);
         }
 
-        loop {
-            let next_byte = self.bytes.next();
-            match next_byte {
-                Some(Ok(byte)) => {
-                    match byte {
-                        // ignore whitespace
-                        b' ' | b'\n' | b'\r' | b'\t' => continue,
-                        b'+' => break Token::Add,
-                        b'*' => break Token::Mul,
-                        b'%' => break Token::Mod,
-                        b'^' => break Token::Pow,
-                        b'#' => break Token::Len,
-                        b'&' => break Token::BitAnd,
-                        b'|' => break Token::BitOr,
-                        b'(' => break Token::ParL,
-                        b')' => break Token::ParR,
-                        b'{' => break Token::CurlyL,
-                        b'}' => break Token::CurlyR,
-                        b'[' => break Token::SqurL,
-                        b']' => break Token::SqurR,
-                        b';' => break Token::SemiColon,
-                        b',' => break Token::Comma,
-                        b'/' => break self.try_parse_long(b'/', Token::Idiv, Token::Div),
-                        b'=' => break self.try_parse_long(b'=', Token::Equal, Token::Assign),
-                        b'~' => break self.try_parse_long(b'=', Token::NotEq, Token::BitXor),
-                        b':' => break self.try_parse_long(b':', Token::DoubColon, Token::Colon),
-                        b'<' => break self.try_parse_long_alt(b'=', Token::LesEq, b'<', Token::ShiftL, Token::Less),
-                        b'>' => break self.try_parse_long_alt(b'=', Token::GreEq, b'>', Token::ShiftR, Token::Greater),
-                        // identifier
-                        ident_head if ident_head.is_ascii_alphabetic() || ident_head == b'_' => {
-                            let mut ident = String::from(ident_head as char);
-                            while let Some(Ok(ident_byte)) = self.bytes.peek() {
-                                if ident_byte.is_ascii_alphanumeric()
-                                || ident_byte == &b'_' {
-                                    ident.push(*ident_byte as char);
-                                    self.bytes.next();
-                                } else {
-                                    break;
-                                }
-                            }
-                            break match &ident {
-                                str if str == "and" => Token::And,
-                                str if str == "break" => Token::Break,
-                                str if str == "do" => Token::Do,
-                                str if str == "else" => Token::Else,
-                                str if str == "elseif" => Token::Elseif,
-                                str if str == "end" => Token::End,
-                                str if str == "false" => Token::False,
-                                str if str == "for" => Token::For,
-                                str if str == "function" => Token::Function,
-                                str if str == "goto" => Token::Goto,
-                                str if str == "if" => Token::If,
-                                str if str == "in" => Token::In,
-                                str if str == "local" => Token::Local,
-                                str if str == "nil" => Token::Nil,
-                                str if str == "not" => Token::Not,
-                                str if str == "or" => Token::Or,
-                                str if str == "repeat" => Token::Repeat,
-                                str if str == "return" => Token::Return,
-                                str if str == "then" => Token::Then,
-                                str if str == "true" => Token::True,
-                                str if str == "until" => Token::Until,
-                                str if str == "while" => Token::While,
-                                _ => Token::Ident(ident)
-                            };
-                        },
-                        b'.' => break self.parse_dot_token(),
-                        // sub or comment
-                        b'-' => break match self.bytes.peek() {
-                            Some(Ok(comment_byte)) => {
-                                match *comment_byte {
-                                    b'-' => {
-                                        self.bytes.next();
-                                        while let Some(Ok(comment_content_byte)) = self.bytes.next() {
-                                            if comment_content_byte == b'\n' {
-                                                break;
-                                            }
-                                        }
-                                        continue;
-                                    },
-                                    _ => Token::Sub
-                                }
-                            },
-                            _ => Token::Sub
-                        },
-                        // string
-                        b'"' | b'\'' => break self.parse_string(byte),
-                        // number
-                        b'0'..=b'9' => break self.parse_number(byte),
-                        // unknown
-                        unknown => unimplemented!("Unexpected u8: {}", unknown),
+        let mut start;
+        let token = loop {
+            start = (self.offset, self.cur_line);
+            let byte = match self.next_byte()? {
+                Some(byte) => byte,
+                None => break Token::Eos,
+            };
+            match byte {
+                // ignore whitespace
+                b' ' | b'\n' | b'\r' | b'\t' => continue,
+                b'+' => break Token::Add,
+                b'*' => break Token::Mul,
+                b'%' => break Token::Mod,
+                b'^' => break Token::Pow,
+                b'#' => break Token::Len,
+                b'&' => break Token::BitAnd,
+                b'|' => break Token::BitOr,
+                b'(' => break Token::ParL,
+                b')' => break Token::ParR,
+                b'{' => break Token::CurlyL,
+                b'}' => break Token::CurlyR,
+                b'[' => break Token::SqurL,
+                b']' => break Token::SqurR,
+                b';' => break Token::SemiColon,
+                b',' => break Token::Comma,
+                b'/' => break self.try_parse_long(b'/', Token::Idiv, Token::Div)?,
+                b'=' => break self.try_parse_long(b'=', Token::Equal, Token::Assign)?,
+                b'~' => break self.try_parse_long(b'=', Token::NotEq, Token::BitXor)?,
+                b':' => break self.try_parse_long(b':', Token::DoubColon, Token::Colon)?,
+                b'<' => break self.try_parse_long_alt(b'=', Token::LesEq, b'<', Token::ShiftL, Token::Less)?,
+                b'>' => break self.try_parse_long_alt(b'=', Token::GreEq, b'>', Token::ShiftR, Token::Greater)?,
+                // identifier
+                ident_head if ident_head.is_ascii_alphabetic() || ident_head == b'_' => {
+                    let mut ident = String::from(ident_head as char);
+                    while let Some(ident_byte) = self.peek_byte()? {
+                        if ident_byte.is_ascii_alphanumeric() || ident_byte == b'_' {
+                            ident.push(ident_byte as char);
+                            self.next_byte()?;
+                        } else {
+                            break;
+                        }
                     }
+                    break match &ident {
+                        str if str == "and" => Token::And,
+                        str if str == "break" => Token::Break,
+                        str if str == "do" => Token::Do,
+                        str if str == "else" => Token::Else,
+                        str if str == "elseif" => Token::Elseif,
+                        str if str == "end" => Token::End,
+                        str if str == "false" => Token::False,
+                        str if str == "for" => Token::For,
+                        str if str == "function" => Token::Function,
+                        str if str == "goto" => Token::Goto,
+                        str if str == "if" => Token::If,
+                        str if str == "in" => Token::In,
+                        str if str == "local" => Token::Local,
+                        str if str == "nil" => Token::Nil,
+                        str if str == "not" => Token::Not,
+                        str if str == "or" => Token::Or,
+                        str if str == "repeat" => Token::Repeat,
+                        str if str == "return" => Token::Return,
+                        str if str == "then" => Token::Then,
+                        str if str == "true" => Token::True,
+                        str if str == "until" => Token::Until,
+                        str if str == "while" => Token::While,
+                        _ => Token::Ident(ident)
+                    };
                 },
-                Some(Err(e)) => panic!("Error reading token: {}", e),
-                _ => break Token::Eos
+                b'.' => break self.parse_dot_token()?,
+                // sub or comment
+                b'-' => {
+                    if self.peek_byte()? != Some(b'-') {
+                        break Token::Sub;
+                    }
+                    while let Some(comment_byte) = self.next_byte()? {
+                        if comment_byte == b'\n' {
+                            break;
+                        }
+                    }
+                },
+                // string
+                b'"' | b'\'' => break self.parse_string(byte)?,
+                // number
+                b'0'..=b'9' => break self.parse_number(byte)?,
+                // unknown
+                unknown => return Err(self.lex_error("unexpected symbol", &[unknown])),
             }
+        };
+
+        self.span = Span { start: start.0, end: self.offset, line: start.1 };
+        Ok(token)
+    }
+
+    pub fn expect(&mut self, t: Token) -> Result<(), SyntaxError> {
+        let token = self.next()?;
+        if token == t {
+            Ok(())
+        } else {
+            Err(self.error_near(&format!("'{}' expected", t), &token))
         }
     }
 
-    pub fn expect(&mut self, t: Token) {
-        assert_eq!(self.next(), t);
+    /// Expect `what` closing `who` which was opened at `line`.
+    pub fn check_match(&mut self, what: Token, who: Token, line: usize) -> Result<(), SyntaxError> {
+        let token = self.next()?;
+        if token == what {
+            Ok(())
+        } else {
+            Err(self.match_error(what, who, line, &token))
+        }
     }
 
-    fn peek_byte(&mut self) -> u8 {
-        match self.bytes.peek() {
-            Some(Ok(byt)) => *byt,
-            Some(_) => panic!("lex peek error"),
-            None => b'\0',
+    pub fn match_error(&self, what: Token, who: Token, line: usize, token: &Token) -> SyntaxError {
+        if line == self.line() {
+            self.error_near(&format!("'{}' expected", what), token)
+        } else {
+            self.error_near(&format!("'{}' expected (to close '{}' at line {})", what, who, line), token)
         }
     }
 
-    fn next_byte(&mut self) -> Option<u8> {
-        self.bytes.next().and_then(|r|Some(r.unwrap()))
+    /// Error at the line of the last token.
+    pub fn error(&self, message: impl Into<String>) -> SyntaxError {
+        self.error_at(self.span.line, message.into())
+    }
+
+    pub fn error_near(&self, message: &str, token: &Token) -> SyntaxError {
+        match token {
+            Token::Eos => self.error(format!("{} near <eof>", message)),
+            _ => self.error(format!("{} near '{}'", message, token)),
+        }
     }
 
-    fn try_parse_long(&mut self, second: u8, long: Token, short: Token) -> Token {
-        let peek_byte = self.bytes.next();
-        if let Some(Ok(byte)) = peek_byte {
-            if byte == second {
-                return long
+    fn error_at(&self, line: usize, message: String) -> SyntaxError {
+        SyntaxError {
+            chunk_name: self.chunk_name.clone(),
+            line,
+            message,
+        }
+    }
+
+    // error at the reading position, `near` is the text of the token being read
+    fn lex_error(&self, message: &str, near: &[u8]) -> SyntaxError {
+        self.error_at(self.cur_line, format!("{} near '{}'", message, String::from_utf8_lossy(near)))
+    }
+
+    fn peek_byte(&mut self) -> Result<Option<u8>, SyntaxError> {
+        match self.bytes.peek() {
+            Some(Ok(byte)) => Ok(Some(*byte)),
+            Some(Err(e)) => {
+                let message = e.to_string();
+                Err(self.error_at(self.cur_line, message))
             }
+            None => Ok(None),
         }
-        short
     }
 
-    fn try_parse_long_alt(&mut self, second_a: u8, long_a: Token, second_b: u8, long_b: Token, short: Token) -> Token {
-        let peek_byte = self.bytes.next();
-        if let Some(Ok(byte)) = peek_byte {
-            if byte == second_a {
-                return long_a
-            } else if byte == second_b {
-                return long_b
+    fn next_byte(&mut self) -> Result<Option<u8>, SyntaxError> {
+        match self.bytes.next() {
+            Some(Ok(byte)) => {
+                self.offset += 1;
+                if byte == b'\n' {
+                    self.cur_line += 1;
+                }
+                Ok(Some(byte))
             }
+            Some(Err(e)) => Err(self.error_at(self.cur_line, e.to_string())),
+            None => Ok(None),
+        }
+    }
+
+    fn try_parse_long(&mut self, second: u8, long: Token, short: Token) -> Result<Token, SyntaxError> {
+        if self.peek_byte()? == Some(second) {
+            self.next_byte()?;
+            return Ok(long);
         }
-        short
+        Ok(short)
     }
 
-    fn parse_string(&mut self, quote: u8) -> Token {
+    fn try_parse_long_alt(&mut self, second_a: u8, long_a: Token, second_b: u8, long_b: Token, short: Token) -> Result<Token, SyntaxError> {
+        match self.peek_byte()? {
+            Some(byte) if byte == second_a => {
+                self.next_byte()?;
+                Ok(long_a)
+            }
+            Some(byte) if byte == second_b => {
+                self.next_byte()?;
+                Ok(long_b)
+            }
+            _ => Ok(short)
+        }
+    }
+
+    fn parse_string(&mut self, quote: u8) -> Result<Token, SyntaxError> {
         let mut string = Vec::new();
         loop {
-            let next_byte = self.bytes.next();
-            match next_byte {
-                Some(Ok(string_byte)) => {
-                    match string_byte {
-                        b'\n' => panic!("Unexpected newline in string"),
-                        b'\\' => string.push(self.parse_escape()),
-                        end if end == quote => break Token::String(string),
-                        content => string.push(content)
-                    }
-                },
-                Some(Err(e)) => panic!("Error parsing string: {}", e),
-                _ => panic!("Expected closing quote: {}", quote)
+            match self.next_byte()? {
+                Some(b'\n') => {
+                    let near = [&[quote], string.as_slice()].concat();
+                    return Err(self.error_at(self.cur_line - 1, format!("unfinished string near '{}'", String::from_utf8_lossy(&near))));
+                }
+                Some(b'\\') => self.parse_escape(quote, &mut string)?,
+                Some(end) if end == quote => break Ok(Token::String(string)),
+                Some(content) => string.push(content),
+                None => return Err(self.error_at(self.cur_line, "unfinished string near <eof>".to_string())),
             }
         }
     }
 
-    fn parse_escape(&mut self) -> u8 {
-        let next_byte = self.bytes.next();
-        match next_byte {
-            Some(Ok(byte)) => {
-                match byte {
-                    b'n' => b'\n',
-                    b't' => b'\t',
-                    b'r' => b'\r',
-                    b'b' => b'\x08',
-                    b'f' => b'\x0C',
-                    b'a' => b'\x07',
-                    b'v' => b'\x0B',
-                    b'\\' => b'\\',
-                    b'"' => b'"',
-                    b'\'' => b'\'',
-                    b'x' => { // format: \xXX
-                        let n1 = char::to_digit(self.next_byte().unwrap() as char, 16).unwrap();
-                        let n2 = char::to_digit(self.next_byte().unwrap() as char, 16).unwrap();
-                        (n1 * 16 + n2) as u8
+    fn parse_escape(&mut self, quote: u8, string: &mut Vec<u8>) -> Result<(), SyntaxError> {
+        // text of the string so far, for error messages
+        let near = |string: &[u8], escape: &[u8]| [&[quote], string, b"\\", escape].concat();
+
+        let byte = match self.next_byte()? {
+            Some(byte) => byte,
+            None => return Err(self.error_at(self.cur_line, "unfinished string near <eof>".to_string())),
+        };
+        let escaped = match byte {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'b' => b'\x08',
+            b'f' => b'\x0C',
+            b'a' => b'\x07',
+            b'v' => b'\x0B',
+            b'\\' => b'\\',
+            b'"' => b'"',
+            b'\'' => b'\'',
+            b'\n' => b'\n',
+            b'x' => { // format: \xXX
+                let mut n = 0;
+                let mut escape = vec![b'x'];
+                for _ in 0..2 {
+                    let digit = self.next_byte()?;
+                    escape.extend(digit);
+                    match digit.and_then(|d| (d as char).to_digit(16)) {
+                        Some(d) => n = n * 16 + d,
+                        None => return Err(self.lex_error("hexadecimal digit expected", &near(string, &escape))),
                     }
-                    ch@b'0'..=b'9' => { // format: \d[d[d]]
-                        let mut n = char::to_digit(ch as char, 10).unwrap(); // TODO no unwrap
-                        if let Some(d) = char::to_digit(self.peek_byte() as char, 10) {
-                            self.next_byte();
-                            n = n * 10 + d;
-                            if let Some(d) = char::to_digit(self.peek_byte() as char, 10) {
-                                self.next_byte();
-                                n = n * 10 + d;
-                            }
+                }
+                n as u8
+            }
+            b'z' => { // skip the following whitespace
+                while let Some(b' ' | b'\n' | b'\r' | b'\t') = self.peek_byte()? {
+                    self.next_byte()?;
+                }
+                return Ok(());
+            }
+            b'u' => { // format: \u{XXX}
+                let mut escape = vec![b'u'];
+                let open = self.next_byte()?;
+                escape.extend(open);
+                if open != Some(b'{') {
+                    return Err(self.lex_error("missing '{' in \\u{xxxx}", &near(string, &escape)));
+                }
+                let mut n: u32 = 0;
+                let mut ndigits = 0;
+                loop {
+                    let digit = self.next_byte()?;
+                    escape.extend(digit);
+                    match digit {
+                        Some(b'}') if ndigits > 0 => break,
+                        Some(d) if d.is_ascii_hexdigit() => {
+                            n = n.checked_mul(16)
+                                .and_then(|n| n.checked_add((d as char).to_digit(16).unwrap()))
+                                .filter(|n| *n <= 0x10FFFF)
+                                .ok_or_else(|| self.lex_error("UTF-8 value too large", &near(string, &escape)))?;
+                            ndigits += 1;
                         }
-                        u8::try_from(n).expect("decimal escape too large")
+                        _ if ndigits == 0 => return Err(self.lex_error("hexadecimal digit expected", &near(string, &escape))),
+                        _ => return Err(self.lex_error("missing '}' in \\u{xxxx}", &near(string, &escape))),
                     }
-                    _ => byte
                 }
-            },
-            Some(Err(e)) => panic!("Error parsing escape: {}", e),
-            _ => panic!("Expected escape character")
-        }
+                // surrogates are not valid chars, encode them the way Lua does
+                let mut buf = [0; 4];
+                match char::from_u32(n) {
+                    Some(ch) => string.extend_from_slice(ch.encode_utf8(&mut buf).as_bytes()),
+                    None => string.extend_from_slice(&[0xED, 0xA0 | ((n >> 6) & 0x1F) as u8, 0x80 | (n & 0x3F) as u8]),
+                }
+                return Ok(());
+            }
+            ch@b'0'..=b'9' => { // format: \d[d[d]]
+                let mut escape = vec![ch];
+                let mut n = (ch - b'0') as u32;
+                for _ in 0..2 {
+                    match self.peek_byte()? {
+                        Some(d@b'0'..=b'9') => {
+                            self.next_byte()?;
+                            escape.push(d);
+                            n = n * 10 + (d - b'0') as u32;
+                        }
+                        _ => break,
+                    }
+                }
+                u8::try_from(n).map_err(|_| self.lex_error("decimal escape too large", &near(string, &escape)))?
+            }
+            _ => return Err(self.lex_error("invalid escape sequence", &near(string, &[byte]))),
+        };
+        string.push(escaped);
+        Ok(())
     }
 
-    fn parse_dot_token(&mut self) -> Token {
-        match self.bytes.peek() {
-            Some(Ok(dot_byte)) => {
-                match *dot_byte {
-                    b'.' => {
-                        match self.bytes.peek() {
-                            Some(Ok(dots_byte)) => {
-                                match *dots_byte {
-                                    b'.' => {
-                                        self.bytes.next();
-                                        Token::Dots
-                                    },
-                                    _ => Token::Concat
-                                }
-                            },
-                            _ => Token::Concat
-                        }
-                    },
-                    frac_byte if frac_byte.is_ascii_digit() => self.parse_number_frac(0.0),
-                    _ => Token::Dot,
+    fn parse_dot_token(&mut self) -> Result<Token, SyntaxError> {
+        match self.peek_byte()? {
+            Some(b'.') => {
+                self.next_byte()?;
+                if self.peek_byte()? == Some(b'.') {
+                    self.next_byte()?;
+                    Ok(Token::Dots)
+                } else {
+                    Ok(Token::Concat)
                 }
             },
-            _ => Token::Dot
+            Some(b'0'..=b'9') => self.parse_number(b'.'),
+            _ => Ok(Token::Dot),
         }
     }
 
-    fn parse_number(&mut self, first_byte: u8) -> Token {
-        if first_byte == b'0' {
-            match self.bytes.peek() {
-                Some(Ok(hex_byte)) => {
-                    match *hex_byte {
-                        b'x' | b'X' => {
-                            self.bytes.next();
-                            return self.parse_number_hex();
-                        },
-                        _ => ()
-                    }
-                },
-                _ => ()
-            }
+    // read the numeral like Lua's lexer, then convert it
+    fn parse_number(&mut self, first_byte: u8) -> Result<Token, SyntaxError> {
+        let mut numeral = vec![first_byte];
+        let mut expo = b"eE";
+        if first_byte == b'0' && let Some(x@(b'x' | b'X')) = self.peek_byte()? {
+            self.next_byte()?;
+            numeral.push(x);
+            expo = b"pP";
         }
 
-        let mut n: i64 = (first_byte as char).to_digit(10).unwrap() as i64;
         loop {
-            let ch = self.bytes.peek();
-            match ch {
-                Some(Ok(byte)) => {
-                    match *byte {
-                        b'0'..=b'9' => {
-                            let int_byte = self.bytes.next().unwrap().unwrap();
-                            n = n * 10 + (int_byte as char).to_digit(10).unwrap() as i64;
-                        },
-                        b'.' => return self.parse_number_frac(n as f64),
-                        b'e' | b'E' => return self.parse_number_exp(n as f64),
-                        _ => break
+            match self.peek_byte()? {
+                Some(byte) if expo.contains(&byte) => {
+                    self.next_byte()?;
+                    numeral.push(byte);
+                    if let Some(sign@(b'+' | b'-')) = self.peek_byte()? {
+                        self.next_byte()?;
+                        numeral.push(sign);
                     }
-                },
-                Some(Err(e)) => panic!("Error parsing number: {}", e),
+                }
+                Some(byte) if byte.is_ascii_hexdigit() || byte == b'.' => {
+                    self.next_byte()?;
+                    numeral.push(byte);
+                }
                 _ => break
             }
         }
 
-        let follow = self.bytes.peek();
-        match follow {
-            Some(Ok(byte)) => {
-                match *byte {
-                    invalid_u8 if (invalid_u8 as char).is_alphabetic() || invalid_u8 == b'.' => {
-                        panic!("Invalid number end: {}", invalid_u8)
-                    }
-                    _ => ()
-                }
-            },
-            _ => ()
+        // force an error for things like `3x`
+        if let Some(byte) = self.peek_byte()? && (byte.is_ascii_alphabetic() || byte == b'_') {
+            self.next_byte()?;
+            numeral.push(byte);
         }
 
-        return Token::Integer(n);
+        str_to_number(&numeral).ok_or_else(|| self.lex_error("malformed number", &numeral))
     }
+}
 
-    fn parse_number_frac(&mut self, number_base: f64) -> Token {
-        self.bytes.next(); // consume '.'
+pub(crate) fn str_to_number(numeral: &[u8]) -> Option<Token> {
+    let text = std::str::from_utf8(numeral).ok()?;
+    if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        return hex_to_number(hex);
+    }
 
-        let mut n = 0;
-        let mut x = 1.0;
-        loop {
-            let ch = self.bytes.peek();
-            match ch {
-                Some(Ok(byte)) => {
-                    match *byte {
-                        b'0'..=b'9' => {
-                            let int_byte = self.bytes.next().unwrap().unwrap();
-                            n = n * 10 + (int_byte as char).to_digit(10).unwrap() as i64;
-                            x *= 10.0;
-                        },
-                        b'e' | b'E' => return self.parse_number_exp(number_base + (n as f64) / x),
-                        _ => break
-                    }
-                },
-                Some(Err(e)) => panic!("Error parsing number: {}", e),
-                _ => break
-            }
+    if text.bytes().all(|b| b.is_ascii_digit()) {
+        // decimal integers that overflow are read as floats
+        if let Ok(i) = text.parse::<i64>() {
+            return Some(Token::Integer(i));
         }
-        Token::Float(number_base as f64 + n as f64 / x)
     }
+    text.parse::<f64>().ok().map(Token::Float)
+}
 
-    fn parse_number_hex(&mut self) -> Token {
-        unimplemented!()
+fn hex_to_number(hex: &str) -> Option<Token> {
+    let (mantissa, exp) = match hex.find(['p', 'P']) {
+        Some(i) => (&hex[..i], Some(hex[i + 1..].parse::<i32>().ok()?)),
+        None => (hex, None),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    let digits = || int_part.chars().chain(frac_part.unwrap_or("").chars());
+    if digits().next().is_none() || !digits().all(|c| c.is_ascii_hexdigit()) {
+        return None;
     }
 
-    fn parse_number_exp(&mut self, number_base: f64) -> Token {
-        unimplemented!()
+    if frac_part.is_none() && exp.is_none() {
+        // hexadecimal integers wrap around
+        let n = int_part.chars().fold(0i64, |n, c| {
+            n.wrapping_mul(16).wrapping_add(c.to_digit(16).unwrap() as i64)
+        });
+        return Some(Token::Integer(n));
     }
-}
\ No newline at end of file
+
+    let mut n = digits().fold(0.0, |n, c| n * 16.0 + c.to_digit(16).unwrap() as f64);
+    let frac_len = frac_part.map_or(0, |f| f.len()) as i32;
+    n *= 2f64.powi(exp.unwrap_or(0) - 4 * frac_len);
+    Some(Token::Float(n))
+}
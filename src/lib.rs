@@ -1,5 +1,12 @@
 pub mod value;
+pub mod ast;
 pub mod bytecode;
+pub mod disasm;
+pub mod dump;
+pub mod gc;
 pub mod lexer;
 pub mod parser;
+pub mod peephole;
+pub mod proto;
+pub mod verify;
 pub mod vm;
\ No newline at end of file
@@ -1,12 +1,11 @@
 use std::env;
+use std::error::Error;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader};
+use std::process;
+use std::rc::Rc;
 
-mod value;
-mod bytecode;
-mod lexer;
-mod parser;
-mod vm;
+use rlua::{dump, parser, proto::Proto, vm};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
@@ -16,10 +15,30 @@ fn main() {
     }
 
     let file = File::open(&args[1]).unwrap();
-    let input = BufReader::new(file);
-    let lexer = lexer::Lexer::new(input);
-    let proto = parser::ParseProto::load(lexer);
+    let mut input = BufReader::new(file);
+
+    // precompiled chunks are told apart by their first byte
+    let binary = input.fill_buf().is_ok_and(|buf| buf.starts_with(&dump::SIGNATURE[..1]));
+    let result: Result<Rc<Proto>, Box<dyn Error>> = if binary {
+        dump::undump(input, &args[1]).map_err(Into::into)
+    } else {
+        let options = parser::CompileOptions {
+            chunk_name: args[1].clone(),
+            ..Default::default()
+        };
+        parser::ParseProto::load(input, options).map_err(Into::into)
+    };
+    let proto = match result {
+        Ok(proto) => proto,
+        Err(e) => {
+            eprintln!("{}: {}", args[0], e);
+            process::exit(1);
+        }
+    };
 
     let mut exe_state = vm::ExeState::new();
-    exe_state.execute(&proto);
+    if let Err(e) = exe_state.execute(&proto) {
+        eprintln!("{}: {}", args[0], e);
+        process::exit(1);
+    }
 }
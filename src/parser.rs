@@ -1,7 +1,18 @@
-use std::io::Read;
-use crate::{bytecode::Bytecode, lexer::{Lexer, Token}, value::Value};
+use std::{collections::HashMap, io::{Read, Write}, rc::Rc};
+use crate::{ast::{BinOp, UnOp, UNARY_PRIORITY}, bytecode::{Bytecode, MAX_EXTRA_ARG, MAX_REGS, MULTRET}, disasm,
+    lexer::{Lexer, SyntaxError, Token}, peephole, proto::{LocVar, Proto}, value::{arith::{self, ArithOp}, Value}};
 
-#[derive(Debug, PartialEq)]
+const MAX_LOCALS: usize = 200;
+
+// of nested statements and expressions, like Lua's LUAI_MAXCCALLS but
+// lower, for the unoptimized frames of the parsers to fit in the 2 MiB
+// stack of a thread
+pub(crate) const MAX_LEVELS: usize = 64;
+
+// constants referred by `u8` operands, others are loaded into registers first
+const MAX_K_OPERAND: usize = u8::MAX as usize;
+
+#[derive(Debug)]
 enum ExpDesc {
     Nil,
     Boolean(bool),
@@ -13,7 +24,71 @@ enum ExpDesc {
     Index(usize, usize),
     IndexField(usize, usize),
     IndexInt(usize, u8),
-    Call
+    UnaryOp(UnaryCode, usize),
+    BinaryOp(BinaryCode, usize, usize),
+    Function(usize),
+    Call(usize), // pc of the `Call`, giving no results until discharged
+}
+
+impl ExpDesc {
+    fn is_assignable(&self) -> bool {
+        matches!(self, ExpDesc::Local(_) | ExpDesc::Global(_) | ExpDesc::Index(..)
+            | ExpDesc::IndexField(..) | ExpDesc::IndexInt(..))
+    }
+
+    // numeric constants are folded
+    fn numeral(&self) -> Option<Value> {
+        match self {
+            ExpDesc::Integer(i) => Some(Value::Integer(*i)),
+            ExpDesc::Float(f) => Some(Value::Float(*f)),
+            _ => None,
+        }
+    }
+
+    // truth of a constant condition, `None` if known only at run time
+    fn const_truth(&self) -> Option<bool> {
+        match self {
+            ExpDesc::Nil | ExpDesc::Boolean(false) => Some(false),
+            ExpDesc::Boolean(true) | ExpDesc::Integer(_) | ExpDesc::Float(_) | ExpDesc::String(_) => Some(true),
+            _ => None,
+        }
+    }
+}
+
+type SetCode = fn(u8, u8, u8) -> Bytecode;
+type UnaryCode = fn(u8, u8) -> Bytecode;
+type BinaryCode = fn(u8, u8, u8) -> Bytecode;
+
+// attributes of local variables
+#[derive(Clone, Copy, PartialEq)]
+enum Attrib {
+    None,
+    Const, // not to be assigned
+    Close, // nor to be left without calling its `__close` metamethod
+}
+
+// Constants are deduplicated by this. Floats are compared by their bits,
+// so 0.0 and -0.0 are kept apart, and integers apart from equal floats.
+#[derive(PartialEq, Eq, Hash)]
+enum ConstKey {
+    Nil,
+    Boolean(bool),
+    Integer(i64),
+    Float(u64),
+    String(Vec<u8>),
+}
+
+impl From<&Value> for ConstKey {
+    fn from(v: &Value) -> Self {
+        match v {
+            Value::Nil => ConstKey::Nil,
+            Value::Boolean(b) => ConstKey::Boolean(*b),
+            Value::Integer(i) => ConstKey::Integer(*i),
+            Value::Float(f) => ConstKey::Float(f.to_bits()),
+            v if v.is_string() => ConstKey::String(<&[u8]>::from(v).to_vec()),
+            v => unreachable!("constant {:?}", v),
+        }
+    }
 }
 
 enum ConstStack {
@@ -21,147 +96,664 @@ enum ConstStack {
     Stack(usize)
 }
 
-pub struct ParseProto<R: Read> {
-    pub constants: Vec<Value>,
-    pub bytecodes: Vec<Bytecode>,
+/// Options for compiling a chunk.
+pub struct CompileOptions<'a> {
+    /// Name used in error messages, like `luac`'s chunk name.
+    pub chunk_name: String,
+
+    /// Leave debug information out of the compiled prototype.
+    pub strip: bool,
+
+    /// 0 disables the optional optimization passes.
+    pub opt_level: u8,
+
+    /// Where to write the listing of the compiled chunk, if wanted. The
+    /// listing is diagnostic output so write errors are ignored.
+    pub listing: Option<&'a mut dyn Write>,
+}
+
+impl Default for CompileOptions<'_> {
+    fn default() -> Self {
+        Self {
+            chunk_name: "?".to_string(),
+            strip: false,
+            opt_level: 1,
+            listing: None,
+        }
+    }
+}
+
+// The function being compiled. Those enclosing it are kept aside until it
+// is done, as their code is interrupted by the nested one.
+#[derive(Default)]
+struct FuncState {
+    constants: Vec<Value>,
+    bytecodes: Vec<Bytecode>,
+    protos: Vec<Rc<Proto>>,
+    max_stack_size: usize,
+    nparams: usize,
+    linedefined: usize,
+
+    // debug info
+    lines: Vec<u32>, // of each instruction
+    locvars: Vec<LocVar>,
+
+    const_map: HashMap<ConstKey, usize>,
 
     sp: usize,
-    locals: Vec<String>,
+    locals: Vec<usize>, // active local variables, as indexes in `locvars`
+    attribs: Vec<Attrib>, // of the active local variables
+}
+
+pub struct ParseProto<R: Read> {
+    fs: FuncState,
+    outer: Vec<FuncState>,
+
+    source: Option<String>,
+    strip: bool,
+    opt_level: u8,
     lexer: Lexer<R>,
+
+    // collected errors in recovering mode
+    errors: Option<Vec<SyntaxError>>,
+    depth: usize, // of the blocks being parsed
+    level: usize, // of the statements and expressions being parsed
+    returned: bool, // whether the last block parsed ended with a `return`
 }
 
 impl<R: Read> ParseProto<R> {
-    pub fn load(lexer: Lexer<R>) -> Self {
-        let mut proto = Self {
-            constants: Vec::new(),
-            bytecodes: Vec::new(),
-            sp: 0,
-            locals: Vec::new(),
-            lexer,
+    pub fn load(input: R, options: CompileOptions) -> Result<Rc<Proto>, SyntaxError> {
+        let mut parser = Self::new(input, &options, None);
+        parser.chunk()?;
+        Ok(parser.finish(options.listing))
+    }
+
+    /// Keep parsing after syntax errors and return all of them, for editor
+    /// tooling. Statements with errors are left out of the returned proto.
+    pub fn load_recovering(input: R, options: CompileOptions) -> (Rc<Proto>, Vec<SyntaxError>) {
+        let mut parser = Self::new(input, &options, Some(Vec::new()));
+        let result = parser.chunk();
+
+        let mut errors = parser.errors.take().unwrap();
+        errors.extend(result.err());
+        (parser.finish(options.listing), errors)
+    }
+
+    fn new(input: R, options: &CompileOptions, errors: Option<Vec<SyntaxError>>) -> Self {
+        Self {
+            fs: FuncState::default(),
+            outer: Vec::new(),
+            source: (!options.strip).then(|| options.chunk_name.clone()),
+            strip: options.strip,
+            opt_level: options.opt_level,
+            lexer: Lexer::new(input, &options.chunk_name),
+            errors,
+            depth: 0,
+            level: 0,
+            returned: false,
+        }
+    }
+
+    // the parser is dropped here, leaving only the prototype
+    fn finish(mut self, listing: Option<&mut dyn Write>) -> Rc<Proto> {
+        let proto = self.close_function();
+        if let Some(out) = listing {
+            let _ = disasm::list(&proto, out);
+        }
+        Rc::new(proto)
+    }
+
+    fn open_function(&mut self, linedefined: usize) {
+        let fs = FuncState { linedefined, ..Default::default() };
+        self.outer.push(std::mem::replace(&mut self.fs, fs));
+    }
+
+    // end the current function, returning to the enclosing one if any
+    fn close_function(&mut self) -> Proto {
+        self.emit(Bytecode::Return(0, 0));
+        self.close_locals(0);
+        let mut fs = match self.outer.pop() {
+            Some(outer) => std::mem::replace(&mut self.fs, outer),
+            None => std::mem::take(&mut self.fs),
         };
-        proto.chunk();
-        
-        println!("constants: {:?}", &proto.constants);
-        println!("bytecodes: {:?}", &proto.bytecodes);
+
+        if self.opt_level > 0 {
+            let remap = peephole::optimize(&mut fs.bytecodes, &mut fs.lines);
+            for v in &mut fs.locvars {
+                v.startpc = remap[v.startpc];
+                v.endpc = remap[v.endpc];
+            }
+        }
+        let mut proto = Proto {
+            constants: fs.constants,
+            bytecodes: fs.bytecodes,
+            protos: fs.protos,
+            max_stack_size: fs.max_stack_size,
+            nparams: fs.nparams,
+            linedefined: fs.linedefined as u32,
+            source: self.source.clone(),
+            ..Default::default()
+        };
+        if !self.strip {
+            proto.set_lines(&fs.lines);
+            proto.locvars = fs.locvars;
+        }
         proto
     }
 
-    fn chunk(&mut self) {
-        self.block();
+    // like Lua's errorlimit()
+    fn limit_error(&self, what: &str, limit: usize) -> SyntaxError {
+        let func = match self.fs.linedefined {
+            0 => "main function".to_string(),
+            line => format!("function at line {}", line),
+        };
+        self.lexer.error(format!("too many {} (limit is {}) in {}", what, limit, func))
+    }
+
+    fn emit(&mut self, code: Bytecode) {
+        self.fs.bytecodes.push(code);
+        self.fs.lines.push(self.lexer.line() as u32);
+    }
+
+    // drop the code after `pc`, and the variables scoped in it
+    fn truncate_code(&mut self, pc: usize) {
+        self.fs.bytecodes.truncate(pc);
+        self.fs.lines.truncate(pc);
+        for v in &mut self.fs.locvars {
+            v.startpc = v.startpc.min(pc);
+            v.endpc = v.endpc.min(pc);
+        }
+    }
+
+    // the variables after the first `n` go out of scope
+    fn close_locals(&mut self, n: usize) {
+        for &i in &self.fs.locals[n..] {
+            self.fs.locvars[i].endpc = self.fs.bytecodes.len();
+        }
+        self.fs.locals.truncate(n);
+        self.fs.attribs.truncate(n);
     }
 
-    fn block(&mut self) {
+    fn chunk(&mut self) -> Result<(), SyntaxError> {
         loop {
-            match self.lexer.next() {
-                Token::SemiColon => continue,
-                t@Token::Ident(_) | t@Token::ParL => {
-                    let desc = self.prefixexp(t);
-                    if desc == ExpDesc::Call {
-                    } else {
-                        self.assignment(desc);
+            match self.block()? {
+                Token::Eos => break Ok(()),
+                t => {
+                    // a stray block end is a synchronization point itself
+                    let e = self.lexer.error_near("'<eof>' expected", &t);
+                    self.report(e)?;
+                }
+            }
+        }
+    }
+
+    // parse statements until the end of the block, and return the token ending it
+    fn block(&mut self) -> Result<Token, SyntaxError> {
+        self.depth += 1;
+        let end = self.statements();
+        self.depth -= 1;
+        end
+    }
+
+    fn statements(&mut self) -> Result<Token, SyntaxError> {
+        let level = self.level;
+        loop {
+            let ncode = self.fs.bytecodes.len();
+            let result = match self.lexer.next() {
+                Ok(t @ (Token::Eos | Token::End | Token::Else | Token::Elseif)) => {
+                    self.returned = false;
+                    break Ok(t);
+                }
+                Ok(Token::Return) => match self.ret_stat() {
+                    // the block must end after it, or the caller reports the token
+                    Ok(()) => {
+                        self.returned = true;
+                        break self.lexer.next();
                     }
+                    Err(e) => Err(e),
+                },
+                Ok(t) => self.statement(t),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                self.level = level;
+                self.recover(e, ncode)?;
+            }
+
+            // temporaries do not live across statements
+            self.fs.sp = self.fs.locals.len();
+        }
+    }
+
+    // a block whose local variables are dropped at its end
+    fn block_scope(&mut self) -> Result<Token, SyntaxError> {
+        let nlocals = self.fs.locals.len();
+        let end = self.block()?;
+        // a return closes the variables itself, but only one ending the
+        // block is sure to be run
+        if self.fs.attribs[nlocals..].contains(&Attrib::Close) && !self.returned {
+            self.emit(Bytecode::Close(nlocals as u8));
+        }
+        self.close_locals(nlocals);
+        self.fs.sp = nlocals;
+        Ok(end)
+    }
+
+    fn statement(&mut self, t: Token) -> Result<(), SyntaxError> {
+        self.enter_level()?;
+        match t {
+            Token::SemiColon => (),
+            t@Token::Ident(_) | t@Token::ParL => {
+                let desc = self.prefixexp(t)?;
+                if !matches!(desc, ExpDesc::Call(_)) {
+                    self.assignment(desc)?;
                 }
-                Token::Local => self.local(),
-                Token::Eos => break,
-                Token::Nil => continue,
-                t => panic!("Unexpected token: {:?}", t)
             }
+            Token::Function => self.function_stat(self.lexer.line())?,
+            Token::Local => if self.lexer.peek()? == &Token::Function {
+                self.lexer.next()?;
+                self.local_function(self.lexer.line())?
+            } else {
+                self.local()?
+            },
+            Token::If => self.if_stat(self.lexer.line())?,
+            Token::Nil => (),
+            t => return Err(self.lexer.error_near("unexpected symbol", &t)),
         }
+        self.level -= 1;
+        Ok(())
     }
 
-    fn local(&mut self) {
-        let mut vars = Vec::new();
-        let nexp = loop {
-            vars.push(self.read_name());
+    // like Lua's enterlevel(), to fail before the native stack overflows;
+    // the level is left by the caller, or reset after an error
+    fn enter_level(&mut self) -> Result<(), SyntaxError> {
+        self.level += 1;
+        if self.level > MAX_LEVELS {
+            return Err(self.limit_error("C levels", MAX_LEVELS));
+        }
+        Ok(())
+    }
+
+    fn ret_stat(&mut self) -> Result<(), SyntaxError> {
+        let first = self.fs.sp;
+        let n = match self.lexer.peek()? {
+            Token::Eos | Token::End | Token::Else | Token::Elseif | Token::SemiColon => 0,
+            _ => {
+                let (n, last) = self.explist()?;
+                self.discharge_multi(n, last)?
+            }
+        };
+        if self.lexer.peek()? == &Token::SemiColon {
+            self.lexer.next()?;
+        }
+        self.emit(Bytecode::Return(first as u8, n));
+        Ok(())
+    }
+
+    // In recovering mode, record the error, drop the code of the broken
+    // statement and skip to a token where a statement can restart. In a
+    // nested block, the tokens ending it are left to the enclosing one.
+    fn recover(&mut self, e: SyntaxError, ncode: usize) -> Result<(), SyntaxError> {
+        self.report(e)?;
+        self.truncate_code(ncode);
+
+        let nested = self.depth > 1;
+        loop {
             match self.lexer.peek() {
+                Ok(Token::Local | Token::Function | Token::If | Token::Eos
+                    | Token::Else | Token::Elseif) => break,
+                Ok(Token::End) if nested => break,
+                Ok(Token::End | Token::SemiColon) => {
+                    let _ = self.lexer.next();
+                    break;
+                }
+                Ok(_) => {
+                    let _ = self.lexer.next();
+                }
+                Err(e) => self.report(e)?,
+            }
+        }
+        Ok(())
+    }
+
+    // the error is recorded in recovering mode, and returned otherwise
+    fn report(&mut self, e: SyntaxError) -> Result<(), SyntaxError> {
+        match &mut self.errors {
+            Some(errors) => {
+                errors.push(e);
+                Ok(())
+            }
+            None => Err(e),
+        }
+    }
+
+    fn if_stat(&mut self, line: usize) -> Result<(), SyntaxError> {
+        let mut jump_ends = Vec::new();
+
+        // after a condition known to be true, the following branches are dead
+        let mut taken = false;
+        let mut token = Token::If;
+        loop {
+            let ncode = self.fs.bytecodes.len();
+            let mut dead = taken;
+            let mut jump_false = None;
+            if token != Token::Else {
+                let cond = self.exp()?;
+                self.lexer.expect(Token::Then)?;
+                match cond.const_truth() {
+                    Some(true) => taken = true,
+                    Some(false) => dead = true,
+                    None if !dead => {
+                        let r = self.discharge_top(cond)?;
+                        jump_false = Some(self.fs.bytecodes.len());
+                        self.emit(Bytecode::JumpFalse(r as u8, 0));
+                    }
+                    None => (),
+                }
+                self.fs.sp = self.fs.locals.len();
+            }
+
+            let next = self.block_scope()?;
+            if dead {
+                self.truncate_code(ncode);
+            } else if !taken && matches!(next, Token::Elseif | Token::Else) {
+                jump_ends.push(self.fs.bytecodes.len());
+                self.emit(Bytecode::Jump(0));
+            }
+            if let Some(pc) = jump_false {
+                self.fix_jump(pc)?;
+            }
+
+            match next {
+                Token::End => break,
+                Token::Elseif | Token::Else if token != Token::Else => token = next,
+                t => return Err(self.lexer.match_error(Token::End, Token::If, line, &t)),
+            }
+        }
+
+        for pc in jump_ends {
+            self.fix_jump(pc)?;
+        }
+        Ok(())
+    }
+
+    // point the jump at `pc` to the next instruction to be emitted
+    fn fix_jump(&mut self, pc: usize) -> Result<(), SyntaxError> {
+        let Ok(offset) = i16::try_from(self.fs.bytecodes.len() - (pc + 1)) else {
+            return Err(self.lexer.error("control structure too long"));
+        };
+        self.fs.bytecodes[pc] = match self.fs.bytecodes[pc] {
+            Bytecode::Jump(_) => Bytecode::Jump(offset),
+            Bytecode::JumpFalse(r, _) => Bytecode::JumpFalse(r, offset),
+            Bytecode::JumpTrue(r, _) => Bytecode::JumpTrue(r, offset),
+            ref code => unreachable!("fix jump {:?}", code),
+        };
+        Ok(())
+    }
+
+    fn local(&mut self) -> Result<(), SyntaxError> {
+        let mut vars = Vec::new();
+        let mut tbc = None;
+        loop {
+            let name = self.read_name()?;
+            let attrib = self.attrib()?;
+            if attrib == Attrib::Close {
+                if tbc.is_some() {
+                    return Err(self.lexer.error("multiple to-be-closed variables in local list"));
+                }
+                tbc = Some(vars.len());
+            }
+            vars.push((name, attrib));
+            if self.fs.locals.len() + vars.len() > MAX_LOCALS {
+                return Err(self.limit_error("local variables", MAX_LOCALS));
+            }
+
+            match self.lexer.peek()? {
                 Token::Comma => {
-                    self.lexer.next();
+                    self.lexer.next()?;
                     continue;
                 }
                 Token::Assign => {
-                    self.lexer.next();
-                    break self.explist();
+                    self.lexer.next()?;
+                    let (nexp, last) = self.explist()?;
+                    self.adjust_assign(vars.len(), nexp, last)?;
+                    break;
+                }
+                _ => {
+                    let ivar = self.reserve_regs(vars.len())?;
+                    self.emit(Bytecode::LoadNil(ivar as u8, vars.len() as u8));
+                    break;
                 }
-                _ => break 0,
             }
-        };
+        }
 
-        if nexp < vars.len() {
-            let ivar = self.locals.len() + nexp;
-            let nnil = vars.len() - nexp;
-            self.bytecodes.push(Bytecode::LoadNil(ivar as u8, nnil as u8));
+        let first = self.fs.locals.len();
+        for (name, attrib) in vars {
+            self.new_local(name, attrib);
+        }
+        if let Some(i) = tbc {
+            self.emit(Bytecode::Tbc((first + i) as u8));
         }
+        Ok(())
+    }
 
-        self.locals.append(&mut vars);
+    // `<const>` or `<close>` after the name of a local variable
+    fn attrib(&mut self) -> Result<Attrib, SyntaxError> {
+        if self.lexer.peek()? != &Token::Less {
+            return Ok(Attrib::None);
+        }
+        self.lexer.next()?;
+        let name = self.read_name()?;
+        self.lexer.expect(Token::Greater)?;
+        match name.as_str() {
+            "const" => Ok(Attrib::Const),
+            "close" => Ok(Attrib::Close),
+            _ => Err(self.lexer.error(format!("unknown attribute '{}'", name))),
+        }
     }
 
-    fn assignment(&mut self, first_var: ExpDesc) {
+    // the variable is active from the next instruction, in the next register
+    fn new_local(&mut self, name: String, attrib: Attrib) {
+        self.fs.locals.push(self.fs.locvars.len());
+        self.fs.attribs.push(attrib);
+        self.fs.locvars.push(LocVar { name, startpc: self.fs.bytecodes.len(), endpc: 0 });
+    }
+
+    // Put the values of `nexp` expressions, `last` being the last one not
+    // discharged yet, in the registers of `nvars` variables. The extra
+    // variables are nil, or the results of a call.
+    fn adjust_assign(&mut self, nvars: usize, nexp: usize, last: ExpDesc) -> Result<(), SyntaxError> {
+        if let ExpDesc::Call(pc) = last {
+            // the results replace the function, which is at the top
+            let nresults = (nvars + 1).saturating_sub(nexp);
+            if nresults >= MULTRET as usize {
+                return Err(self.lexer.error("function or expression too complex"));
+            }
+            self.reserve_regs(nresults)?;
+            self.set_returns(pc, nresults as u8);
+        } else {
+            self.discharge_new(last)?;
+            if nexp < nvars {
+                let nnil = nvars - nexp;
+                let ivar = self.reserve_regs(nnil)?;
+                self.emit(Bytecode::LoadNil(ivar as u8, nnil as u8));
+            }
+        }
+        Ok(())
+    }
+
+    fn local_function(&mut self, line: usize) -> Result<(), SyntaxError> {
+        let name = self.read_name()?;
+        if self.fs.locals.len() >= MAX_LOCALS {
+            return Err(self.limit_error("local variables", MAX_LOCALS));
+        }
+        // in scope in its own body, unlike with `local f = function`, but
+        // the debug info sees it only once it is assigned, like in luac
+        let ivar = self.reserve_regs(1)?;
+        self.new_local(name, Attrib::None);
+        let f = self.body(false, line)?;
+        self.discharge(ivar, f)?;
+        let ilocvar = self.fs.locals[ivar];
+        self.fs.locvars[ilocvar].startpc = self.fs.bytecodes.len();
+        Ok(())
+    }
+
+    // `function a.b.c:m() end` assigns the function to the field
+    fn function_stat(&mut self, line: usize) -> Result<(), SyntaxError> {
+        let name = self.read_name()?;
+        let mut var = self.simple_name(name)?;
+        let mut method = false;
+        while !method && matches!(self.lexer.peek()?, Token::Dot | Token::Colon) {
+            method = self.lexer.next()? == Token::Colon;
+            let name = self.read_name()?;
+            let itable = self.discharge_top(var)?;
+            var = self.index_field(itable, name.into_bytes())?;
+        }
+        self.check_readonly(&var)?;
+        let f = self.body(method, line)?;
+        self.assign_var(var, f)
+    }
+
+    // The parameters and the block of a function, compiled into a nested
+    // prototype. Methods have `self` as the first parameter.
+    fn body(&mut self, method: bool, line: usize) -> Result<ExpDesc, SyntaxError> {
+        self.open_function(line);
+        if let Err(e) = self.params_and_block(method, line) {
+            // the broken function is dropped
+            self.fs = self.outer.pop().unwrap();
+            return Err(e);
+        }
+        let proto = self.close_function();
+
+        let i = self.fs.protos.len();
+        if i > u16::MAX as usize {
+            return Err(self.limit_error("functions", u16::MAX as usize + 1));
+        }
+        self.fs.protos.push(Rc::new(proto));
+        Ok(ExpDesc::Function(i))
+    }
+
+    fn params_and_block(&mut self, method: bool, line: usize) -> Result<(), SyntaxError> {
+        if method {
+            self.new_local("self".to_string(), Attrib::None);
+        }
+        self.lexer.expect(Token::ParL)?;
+        if self.lexer.peek()? == &Token::ParR {
+            self.lexer.next()?;
+        } else {
+            loop {
+                let name = self.read_name()?;
+                if self.fs.locals.len() >= MAX_LOCALS {
+                    return Err(self.limit_error("local variables", MAX_LOCALS));
+                }
+                self.new_local(name, Attrib::None);
+                match self.lexer.next()? {
+                    Token::Comma => (),
+                    Token::ParR => break,
+                    t => return Err(self.lexer.error_near("')' expected", &t)),
+                }
+            }
+        }
+        self.fs.nparams = self.fs.locals.len();
+        self.reserve_regs(self.fs.nparams)?;
+
+        match self.block()? {
+            Token::End => Ok(()),
+            t => Err(self.lexer.match_error(Token::End, Token::Function, line, &t)),
+        }
+    }
+
+    fn assignment(&mut self, first_var: ExpDesc) -> Result<(), SyntaxError> {
         let mut vars = vec![first_var];
         loop {
-            match self.lexer.next() {
+            match self.lexer.next()? {
                 Token::Comma => {
-                    let token = self.lexer.next();
-                    vars.push(self.prefixexp(token));
+                    let token = self.lexer.next()?;
+                    vars.push(self.prefixexp(token)?);
                 }
                 Token::Assign => break,
-                t => panic!("unexpected token: {:?}", t)
+                t => return Err(self.lexer.error_near("syntax error", &t)),
             }
         }
+        // temporaries are values in parentheses
+        if vars.iter().any(|var| !var.is_assignable()
+            || matches!(*var, ExpDesc::Local(i) if i >= self.fs.locals.len())) {
+            return Err(self.lexer.error_near("syntax error", &Token::Assign));
+        }
+        for var in &vars {
+            self.check_readonly(var)?;
+        }
 
-        let exp_sp0 = self.sp;
+        let exp_sp0 = self.fs.sp;
         let mut nfexp = 0;
         let last_exp = loop {
-            let desc = self.exp();
+            let desc = self.exp()?;
 
-            if self.lexer.peek() == &Token::Comma {
-                self.lexer.next();
-                self.discharge(exp_sp0 + nfexp, desc);
+            if self.lexer.peek()? == &Token::Comma {
+                self.lexer.next()?;
+                self.discharge_new(desc)?;
                 nfexp += 1;
             } else {
                 break desc;
             }
         };
 
-        match (nfexp + 1).cmp(&vars.len()) {
-            std::cmp::Ordering::Less => {
-                todo!("handle less expressions than variables");
-            }
-            std::cmp::Ordering::Equal => {
-                let lask_var = vars.pop().unwrap();
-                self.assign_var(lask_var, last_exp);
-            }
-            std::cmp::Ordering::Greater => {
-                nfexp = vars.len()
-            }
+        if nfexp + 1 == vars.len() {
+            let lask_var = vars.pop().unwrap();
+            self.assign_var(lask_var, last_exp)?;
+        } else {
+            self.adjust_assign(vars.len(), nfexp + 1, last_exp)?;
+            nfexp = vars.len();
         }
 
         while let Some(var) = vars.pop() {
             nfexp -= 1;
             self.assign_from_stack(var, exp_sp0 + nfexp);
         }
+        Ok(())
     }
 
-    fn assign_var(&mut self, var: ExpDesc, value: ExpDesc) {
-        if let ExpDesc::Local(i) = var {
-            self.discharge(i, value);
-        } else {
-            match self.discharge_const(value) {
+    // `<const>` and `<close>` variables are not to be assigned
+    fn check_readonly(&self, var: &ExpDesc) -> Result<(), SyntaxError> {
+        match *var {
+            ExpDesc::Local(i) if self.fs.attribs[i] != Attrib::None => {
+                let name = &self.fs.locvars[self.fs.locals[i]].name;
+                Err(self.lexer.error(format!("attempt to assign to const variable '{}'", name)))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn assign_var(&mut self, var: ExpDesc, value: ExpDesc) -> Result<(), SyntaxError> {
+        match var {
+            ExpDesc::Local(i) => self.discharge(i, value)?,
+            ExpDesc::Global(i) if i > MAX_K_OPERAND => {
+                let src = self.discharge_top(value)?;
+                self.assign_from_stack(var, src);
+            }
+            _ => match self.discharge_const(value)? {
                 ConstStack::Const(i) => self.assign_from_const(var, i),
                 ConstStack::Stack(i) => self.assign_from_stack(var, i),
             }
         }
+        Ok(())
     }
 
     fn assign_from_stack(&mut self, var: ExpDesc, value: usize) {
         let code = match var {
             ExpDesc::Local(i) => Bytecode::Move(i as u8, value as u8),
-            ExpDesc::Global(i) => Bytecode::SetGlobal(i as u8, value as u8),
+            ExpDesc::Global(i) => if let Ok(i) = u8::try_from(i) {
+                Bytecode::SetGlobal(i, value as u8)
+            } else {
+                self.emit(Bytecode::SetGlobalX(value as u8));
+                Bytecode::extra_arg(i)
+            },
             ExpDesc::Index(t, k) => Bytecode::SetTable(t as u8, k as u8, value as u8),
             ExpDesc::IndexField(t, k) => Bytecode::SetField(t as u8, k as u8, value as u8),
             ExpDesc::IndexInt(t, k) => Bytecode::SetInt(t as u8, k, value as u8),
-            _ => panic!("assign from stack"),
+            _ => unreachable!("assign from stack"),
         };
-        self.bytecodes.push(code);
+        self.emit(code);
     }
 
     fn assign_from_const(&mut self, var: ExpDesc, value: usize) {
@@ -170,194 +762,461 @@ impl<R: Read> ParseProto<R> {
             ExpDesc::Index(t, k) => Bytecode::SetTableConst(t as u8, k as u8, value as u8),
             ExpDesc::IndexField(t, k) => Bytecode::SetFieldConst(t as u8, k as u8, value as u8),
             ExpDesc::IndexInt(t, k) => Bytecode::SetIntConst(t as u8, k, value as u8),
-            _ => panic!("assign from const"),
+            _ => unreachable!("assign from const"),
         };
-        self.bytecodes.push(code);
+        self.emit(code);
     }
 
-    fn add_const(&mut self, c: impl Into<Value>) -> usize {
+    fn add_const(&mut self, c: impl Into<Value>) -> Result<usize, SyntaxError> {
         let c = c.into();
-        let constants = &mut self.constants;
-        constants.iter().position(|v| v == &c).unwrap_or_else(|| {
-            constants.push(c);
-            constants.len() - 1
+        let key = ConstKey::from(&c);
+        if let Some(&i) = self.fs.const_map.get(&key) {
+            return Ok(i);
+        }
+        if self.fs.constants.len() > MAX_EXTRA_ARG {
+            return Err(self.limit_error("constants", MAX_EXTRA_ARG + 1));
+        }
+        let i = self.fs.constants.len();
+        self.fs.constants.push(c);
+        self.fs.const_map.insert(key, i);
+        Ok(i)
+    }
+
+    // the key is a constant operand if its index fits, otherwise it is loaded into a register
+    fn field_key(&mut self, key: Vec<u8>) -> Result<ConstStack, SyntaxError> {
+        let ikey = self.add_const(key.as_slice())?;
+        if ikey <= MAX_K_OPERAND {
+            Ok(ConstStack::Const(ikey))
+        } else {
+            Ok(ConstStack::Stack(self.discharge_top(ExpDesc::String(key))?))
+        }
+    }
+
+    fn index_field(&mut self, itable: usize, key: Vec<u8>) -> Result<ExpDesc, SyntaxError> {
+        Ok(match self.field_key(key)? {
+            ConstStack::Const(ikey) => ExpDesc::IndexField(itable, ikey),
+            ConstStack::Stack(ikey) => ExpDesc::Index(itable, ikey),
         })
     }
 
-    fn explist(&mut self) -> usize {
-        let mut n = 0;
-        let sp0 = self.sp;
-        loop {
-            let desc = self.exp();
-            self.discharge(sp0 + n, desc);
+    fn load_const(&mut self, dst: usize, c: impl Into<Value>) -> Result<Bytecode, SyntaxError> {
+        let iconst = self.add_const(c)?;
+        if let Ok(i) = u16::try_from(iconst) {
+            Ok(Bytecode::LoadConst(dst as u8, i))
+        } else {
+            self.emit(Bytecode::LoadConstX(dst as u8));
+            Ok(Bytecode::extra_arg(iconst))
+        }
+    }
 
+    // The expressions but the last are discharged in order, and the last
+    // is returned with the number of them.
+    fn explist(&mut self) -> Result<(usize, ExpDesc), SyntaxError> {
+        let mut n = 1;
+        let mut desc = self.exp()?;
+        while self.lexer.peek()? == &Token::Comma {
+            self.lexer.next()?;
+            self.discharge_new(desc)?;
+            desc = self.exp()?;
             n += 1;
-            if self.lexer.peek() == &Token::Comma {
-                self.lexer.next();
-            } else {
-                break n;
-            }
         }
+        Ok((n, desc))
     }
 
-    fn exp(&mut self) -> ExpDesc {
-        let ahead = self.lexer.next();
+    // Discharge the last of `n` expressions, a call giving all its results.
+    // Returns the count operand for all of them.
+    fn discharge_multi(&mut self, n: usize, last: ExpDesc) -> Result<u8, SyntaxError> {
+        if let ExpDesc::Call(pc) = last {
+            self.set_returns(pc, MULTRET);
+            return Ok(MULTRET);
+        }
+        self.discharge_new(last)?;
+        match u8::try_from(n) {
+            Ok(n) if n != MULTRET => Ok(n),
+            _ => Err(self.lexer.error("function or expression too complex")),
+        }
+    }
+
+    fn exp(&mut self) -> Result<ExpDesc, SyntaxError> {
+        let ahead = self.lexer.next()?;
         self.exp_with_ahead(ahead)
     }
 
-    fn exp_with_ahead(&mut self, ahead: Token) -> ExpDesc {
-        match ahead {
+    fn exp_with_ahead(&mut self, ahead: Token) -> Result<ExpDesc, SyntaxError> {
+        self.subexp(ahead, 0)
+    }
+
+    // binary operators with priority not greater than `limit` are left to the caller
+    fn subexp(&mut self, ahead: Token, limit: u8) -> Result<ExpDesc, SyntaxError> {
+        self.enter_level()?;
+        let mut desc = if let Some(op) = UnOp::from_token(&ahead) {
+            let t = self.lexer.next()?;
+            let operand = self.subexp(t, UNARY_PRIORITY)?;
+            self.unop(op, operand)?
+        } else {
+            self.simpleexp(ahead)?
+        };
+
+        while let Some(op) = BinOp::from_token(self.lexer.peek()?) {
+            let (left_priority, right_priority) = op.priority();
+            if left_priority <= limit {
+                break;
+            }
+            self.lexer.next()?;
+
+            let left = self.infix(op, desc)?;
+            let ncode = self.fs.bytecodes.len();
+            let t = self.lexer.next()?;
+            let right = self.subexp(t, right_priority)?;
+            desc = self.postfix(op, left, right, ncode)?;
+        }
+        self.level -= 1;
+        Ok(desc)
+    }
+
+    fn simpleexp(&mut self, ahead: Token) -> Result<ExpDesc, SyntaxError> {
+        let desc = match ahead {
             Token::Nil => ExpDesc::Nil,
             Token::True => ExpDesc::Boolean(true),
             Token::False => ExpDesc::Boolean(false),
             Token::Integer(i) => ExpDesc::Integer(i),
             Token::Float(f) => ExpDesc::Float(f),
             Token::String(s) => ExpDesc::String(s),
-            Token::Function => todo!("function"),
-            Token::CurlyL => self.table_constructor(),
-            Token::Sub | Token::Not | Token::BitXor | Token::Len => todo!("unop"),
-            Token::Dots => todo!("dots"),
-            t => self.prefixexp(t),
+            Token::Function => self.body(false, self.lexer.line())?,
+            Token::CurlyL => self.table_constructor()?,
+            Token::Dots => return Err(self.lexer.error_near("varargs are not supported", &Token::Dots)),
+            t => self.prefixexp(t)?,
+        };
+        Ok(desc)
+    }
+
+    fn unop(&mut self, op: UnOp, operand: ExpDesc) -> Result<ExpDesc, SyntaxError> {
+        if let Some(folded) = fold_unop(op, &operand) {
+            return Ok(folded);
+        }
+        let code: UnaryCode = match op {
+            UnOp::Neg => Bytecode::Unm,
+            UnOp::Not => Bytecode::Not,
+            UnOp::Len => Bytecode::Len,
+            UnOp::BitNot => Bytecode::BNot,
+        };
+        let src = self.discharge_top(operand)?;
+        Ok(ExpDesc::UnaryOp(code, src))
+    }
+
+    // prepare the left operand before the right one is parsed
+    fn infix(&mut self, op: BinOp, left: ExpDesc) -> Result<ExpDesc, SyntaxError> {
+        match op {
+            BinOp::And | BinOp::Or => {
+                if left.const_truth().is_some() {
+                    return Ok(left);
+                }
+                // the result register, skipping the right operand by the jump
+                let r = self.discharge_new(left)?;
+                let code = if op == BinOp::And {
+                    Bytecode::JumpFalse(r as u8, 0)
+                } else {
+                    Bytecode::JumpTrue(r as u8, 0)
+                };
+                self.emit(code);
+                Ok(ExpDesc::Local(r))
+            }
+            _ if left.numeral().is_some() && arith_op(op).is_some() => Ok(left),
+            _ => Ok(ExpDesc::Local(self.discharge_top(left)?)),
         }
     }
 
-    fn prefixexp(&mut self, ahead: Token) -> ExpDesc {
-        let sp0 = self.sp;
+    // `ncode` is where the code of the right operand begins
+    fn postfix(&mut self, op: BinOp, left: ExpDesc, right: ExpDesc, ncode: usize) -> Result<ExpDesc, SyntaxError> {
+        let code: BinaryCode = match op {
+            BinOp::And | BinOp::Or => return self.logic(op, left, right, ncode),
+            BinOp::Add => Bytecode::Add,
+            BinOp::Sub => Bytecode::Sub,
+            BinOp::Mul => Bytecode::Mul,
+            BinOp::Div => Bytecode::Div,
+            BinOp::Idiv => Bytecode::IDiv,
+            BinOp::Mod => Bytecode::Mod,
+            BinOp::Pow => Bytecode::Pow,
+            BinOp::BitAnd => Bytecode::BAnd,
+            BinOp::BitOr => Bytecode::BOr,
+            BinOp::BitXor => Bytecode::BXor,
+            BinOp::ShiftL => Bytecode::Shl,
+            BinOp::ShiftR => Bytecode::Shr,
+            BinOp::Concat => Bytecode::Concat,
+            BinOp::Equal => Bytecode::Eq,
+            BinOp::NotEq => Bytecode::Ne,
+            BinOp::Less | BinOp::Greater => Bytecode::Lt,
+            BinOp::LesEq | BinOp::GreEq => Bytecode::Le,
+        };
+        if let Some(aop) = arith_op(op) && let Some(folded) = fold_binop(aop, &left, &right) {
+            return Ok(folded);
+        }
+
+        let right = self.discharge_top(right)?;
+        let left = self.discharge_top(left)?;
+        if matches!(op, BinOp::Greater | BinOp::GreEq) {
+            Ok(ExpDesc::BinaryOp(code, right, left))
+        } else {
+            Ok(ExpDesc::BinaryOp(code, left, right))
+        }
+    }
 
+    fn logic(&mut self, op: BinOp, left: ExpDesc, right: ExpDesc, ncode: usize) -> Result<ExpDesc, SyntaxError> {
+        if let Some(truth) = left.const_truth() {
+            // the right operand is the result, or dead code
+            if truth == (op == BinOp::And) {
+                return Ok(right);
+            }
+            self.free_exp(&right);
+            self.truncate_code(ncode);
+            return Ok(left);
+        }
+
+        // the jump emitted by `infix()` is just before the right operand
+        let ExpDesc::Local(r) = left else {
+            unreachable!("logic operand");
+        };
+        self.free_exp(&right);
+        self.discharge(r, right)?;
+        self.fix_jump(ncode - 1)?;
+        Ok(ExpDesc::Local(r))
+    }
+
+    fn prefixexp(&mut self, ahead: Token) -> Result<ExpDesc, SyntaxError> {
         let mut desc = match ahead {
-            Token::Ident(name) => self.simple_name(name),
+            Token::Ident(name) => self.simple_name(name)?,
             Token::ParL => {
-                let desc = self.exp();
-                self.lexer.expect(Token::ParR);
-                desc
+                let line = self.lexer.line();
+                let desc = self.exp()?;
+                self.lexer.check_match(Token::ParR, Token::ParL, line)?;
+                // a value, of one result of a call, and not a variable
+                if matches!(desc, ExpDesc::Call(_)) || desc.is_assignable() {
+                    ExpDesc::Local(self.discharge_new(desc)?)
+                } else {
+                    desc
+                }
             }
-            t => panic!("prefixexp unexpected token: {:?}", t),
+            t => return Err(self.lexer.error_near("unexpected symbol", &t)),
         };
 
         loop {
-            match self.lexer.peek() {
+            match self.lexer.peek()? {
                 Token::SqurL => {
-                    self.lexer.next();
-                    let itable = self.discharge_if_need(sp0, desc);
-                    desc = match self.exp() {
-                        ExpDesc::String(s) => ExpDesc::IndexField(itable, self.add_const(s)),
+                    self.lexer.next()?;
+                    let itable = self.discharge_top(desc)?;
+                    desc = match table_key(self.exp()?) {
+                        ExpDesc::String(s) => self.index_field(itable, s)?,
                         ExpDesc::Integer(i) if u8::try_from(i).is_ok() => ExpDesc::IndexInt(itable, u8::try_from(i).unwrap()),
-                        key => ExpDesc::Index(itable, self.discharge_top(key))
+                        key => ExpDesc::Index(itable, self.discharge_top(key)?)
                     };
 
-                    self.lexer.expect(Token::SqurR);
+                    self.lexer.expect(Token::SqurR)?;
                 }
                 Token::Dot => {
-                    self.lexer.next();
-                    let name = self.read_name();
-                    let itable = self.discharge_if_need(sp0, desc);
-                    desc = ExpDesc::IndexField(itable, self.add_const(name));
+                    self.lexer.next()?;
+                    let name = self.read_name()?;
+                    let itable = self.discharge_top(desc)?;
+                    desc = self.index_field(itable, name.into_bytes())?;
+                }
+                Token::Colon => {
+                    self.lexer.next()?;
+                    let name = self.read_name()?;
+                    let iobj = self.discharge_top(desc)?;
+                    self.free_reg(iobj);
+
+                    // the object is copied first, it may be in the register of the method
+                    let ifunc = self.reserve_regs(2)?;
+                    self.emit(Bytecode::Move(ifunc as u8 + 1, iobj as u8));
+                    let code = match self.field_key(name.into_bytes())? {
+                        ConstStack::Const(ikey) => Bytecode::GetField(ifunc as u8, iobj as u8, ikey as u8),
+                        ConstStack::Stack(ikey) => {
+                            self.free_reg(ikey);
+                            Bytecode::GetTable(ifunc as u8, iobj as u8, ikey as u8)
+                        }
+                    };
+                    self.emit(code);
+                    desc = self.args(ifunc, 1)?;
                 }
-                Token::Colon => todo!("args"),
                 Token::ParL | Token::CurlyL | Token::String(_) => {
-                    self.discharge(sp0, desc);
-                    desc = self.args();
+                    let ifunc = self.discharge_new(desc)?;
+                    desc = self.args(ifunc, 0)?;
                 }
-                _ => {break desc}
+                _ => break Ok(desc)
             }
         }
     }
 
-    fn simple_name(&mut self, name: String) -> ExpDesc {
-        if let Some(ilocal) = self.locals.iter().rposition(|v| v == &name) {
-            ExpDesc::Local(ilocal) 
+    fn simple_name(&mut self, name: String) -> Result<ExpDesc, SyntaxError> {
+        let is_local = |fs: &FuncState| fs.locals.iter().rposition(|&i| fs.locvars[i].name == name);
+        if let Some(ilocal) = is_local(&self.fs) {
+            Ok(ExpDesc::Local(ilocal))
+        } else if self.outer.iter().any(|fs| is_local(fs).is_some()) {
+            // not to be taken for a global
+            Err(self.lexer.error(format!("local '{}' of an enclosing function is not accessible (no upvalues)", name)))
         } else {
-            ExpDesc::Global(self.add_const(name))
+            Ok(ExpDesc::Global(self.add_const(name)?))
         }
     }
 
-    fn args(&mut self) -> ExpDesc {
-        let ifunc = self.sp - 1;
-        let argn = match self.lexer.next() {
+    // `nfixed` arguments, like the object of a method call, are already in place
+    fn args(&mut self, ifunc: usize, nfixed: usize) -> Result<ExpDesc, SyntaxError> {
+        let argn = match self.lexer.next()? {
             Token::ParL => {
-                if self.lexer.peek() != &Token::ParR {
-                    let argn = self.explist();
-                    self.lexer.expect(Token::ParR);
+                if self.lexer.peek()? != &Token::ParR {
+                    let line = self.lexer.line();
+                    let (argn, last) = self.explist()?;
+                    let argn = self.discharge_multi(nfixed + argn, last)?;
+                    self.lexer.check_match(Token::ParR, Token::ParL, line)?;
                     argn
                 } else {
-                    self.lexer.next();
-                    0
+                    self.lexer.next()?;
+                    nfixed as u8
                 }
             }
             Token::CurlyL => {
-                self.table_constructor();
-                1
+                self.table_constructor()?;
+                nfixed as u8 + 1
             }
             Token::String(s) => {
-                self.discharge(ifunc + 1, ExpDesc::String(s));
-                1
+                self.discharge_new(ExpDesc::String(s))?;
+                nfixed as u8 + 1
             }
-            t => panic!("args unexpected token: {:?}", t),
+            t => return Err(self.lexer.error_near("function arguments expected", &t)),
         };
-        self.bytecodes.push(Bytecode::Call(ifunc as u8, argn as u8));
-        ExpDesc::Call
+        let pc = self.fs.bytecodes.len();
+        self.emit(Bytecode::Call(ifunc as u8, argn, 0));
+
+        // the function and its arguments are released
+        self.fs.sp = ifunc;
+        Ok(ExpDesc::Call(pc))
+    }
+
+    fn set_returns(&mut self, pc: usize, nresults: u8) {
+        let Bytecode::Call(func, nargs, _) = self.fs.bytecodes[pc] else {
+            unreachable!("set returns");
+        };
+        self.fs.bytecodes[pc] = Bytecode::Call(func, nargs, nresults);
+    }
+
+    fn reserve_regs(&mut self, n: usize) -> Result<usize, SyntaxError> {
+        let first = self.fs.sp;
+        if first + n > MAX_REGS {
+            return Err(self.lexer.error("function or expression too complex"));
+        }
+        self.fs.sp += n;
+        self.fs.max_stack_size = self.fs.max_stack_size.max(self.fs.sp);
+        Ok(first)
     }
 
-    fn discharge_top(&mut self, desc: ExpDesc) -> usize {
-        self.discharge_if_need(self.sp, desc)
+    // only temporaries are freed, in the reverse order of their reservation
+    fn free_reg(&mut self, reg: usize) {
+        if reg >= self.fs.locals.len() {
+            self.fs.sp -= 1;
+            debug_assert_eq!(reg, self.fs.sp, "free register out of order");
+        }
     }
 
-    fn discharge_if_need(&mut self, dst: usize, desc: ExpDesc) -> usize {
+    // release the registers that the expression reads from
+    fn free_exp(&mut self, desc: &ExpDesc) {
+        match *desc {
+            ExpDesc::Local(i) | ExpDesc::IndexField(i, _) | ExpDesc::IndexInt(i, _)
+                | ExpDesc::UnaryOp(_, i) => self.free_reg(i),
+            ExpDesc::Index(a, b) | ExpDesc::BinaryOp(_, a, b) => {
+                self.free_reg(a.max(b));
+                self.free_reg(a.min(b));
+            }
+            _ => (),
+        }
+    }
+
+    // discharge into any register, a local variable is used in place
+    fn discharge_top(&mut self, desc: ExpDesc) -> Result<usize, SyntaxError> {
         if let ExpDesc::Local(i) = desc {
-            i
+            Ok(i)
         } else {
-            self.discharge(dst, desc);
-            dst
+            self.discharge_new(desc)
         }
     }
 
-    fn discharge(&mut self, dst: usize, desc: ExpDesc) {
+    // discharge into a newly reserved register
+    fn discharge_new(&mut self, desc: ExpDesc) -> Result<usize, SyntaxError> {
+        self.free_exp(&desc);
+        let dst = self.reserve_regs(1)?;
+        self.discharge(dst, desc)?;
+        Ok(dst)
+    }
+
+    fn discharge(&mut self, dst: usize, desc: ExpDesc) -> Result<(), SyntaxError> {
         let code = match desc {
             ExpDesc::Nil => Bytecode::LoadNil(dst as u8, 1),
             ExpDesc::Boolean(b) => Bytecode::LoadBool(dst as u8, b),
             ExpDesc::Integer(i) => if let Ok(i) = i16::try_from(i) {
                 Bytecode::LoadInt(dst as u8, i)
             } else {
-                Bytecode::LoadConst(dst as u8, self.add_const(i) as u16)
+                self.load_const(dst, i)?
             },
-            ExpDesc::Float(f) => Bytecode::LoadConst(dst as u8, self.add_const(f) as u16),
-            ExpDesc::String(s) => Bytecode::LoadConst(dst as u8, self.add_const(s) as u16),
+            ExpDesc::Float(f) => self.load_const(dst, f)?,
+            ExpDesc::String(s) => self.load_const(dst, s)?,
             ExpDesc::Local(src) => if dst == src {
-                return;
+                return Ok(());
             } else {
                 Bytecode::Move(dst as u8, src as u8)
             },
-            ExpDesc::Global(iname) => Bytecode::GetGlobal(dst as u8, iname as u8),
+            ExpDesc::Global(iname) => if let Ok(iname) = u8::try_from(iname) {
+                Bytecode::GetGlobal(dst as u8, iname)
+            } else {
+                self.emit(Bytecode::GetGlobalX(dst as u8));
+                Bytecode::extra_arg(iname)
+            },
             ExpDesc::Index(t, k) => Bytecode::GetTable(dst as u8, t as u8, k as u8),
             ExpDesc::IndexField(t, k) => Bytecode::GetField(dst as u8, t as u8, k as u8),
             ExpDesc::IndexInt(t, k) => Bytecode::GetInt(dst as u8, t as u8, k),
-            ExpDesc::Call => panic!("discharge call"),
+            ExpDesc::UnaryOp(op, src) => op(dst as u8, src as u8),
+            ExpDesc::BinaryOp(op, a, b) => op(dst as u8, a as u8, b as u8),
+            ExpDesc::Function(i) => Bytecode::Closure(dst as u8, i as u16),
+            // one result, in the register of the function
+            ExpDesc::Call(pc) => {
+                self.set_returns(pc, 1);
+                let Bytecode::Call(func, _, _) = self.fs.bytecodes[pc] else {
+                    unreachable!("discharge call");
+                };
+                if dst == func as usize {
+                    return Ok(());
+                }
+                Bytecode::Move(dst as u8, func)
+            }
         };
-        self.bytecodes.push(code);
-        self.sp = dst + 1;
+        self.emit(code);
+        Ok(())
     }
 
-    fn discharge_const(&mut self, desc: ExpDesc) -> ConstStack {
-        match desc {
-            ExpDesc::Nil => ConstStack::Const(self.add_const(Value::Nil)),
-            ExpDesc::Boolean(b) => ConstStack::Const(self.add_const(b)),
-            ExpDesc::Integer(i) => ConstStack::Const(self.add_const(i)),
-            ExpDesc::Float(f) => ConstStack::Const(self.add_const(f)),
-            ExpDesc::String(s) => ConstStack::Const(self.add_const(s)),
+    fn discharge_const(&mut self, desc: ExpDesc) -> Result<ConstStack, SyntaxError> {
+        let iconst = match &desc {
+            ExpDesc::Nil => self.add_const(Value::Nil)?,
+            ExpDesc::Boolean(b) => self.add_const(*b)?,
+            ExpDesc::Integer(i) => self.add_const(*i)?,
+            ExpDesc::Float(f) => self.add_const(*f)?,
+            ExpDesc::String(s) => self.add_const(s.as_slice())?,
+
+            _ => return Ok(ConstStack::Stack(self.discharge_top(desc)?))
+        };
 
-            _ => ConstStack::Stack(self.discharge_top(desc))
+        if iconst <= MAX_K_OPERAND {
+            Ok(ConstStack::Const(iconst))
+        } else {
+            Ok(ConstStack::Stack(self.discharge_top(desc)?))
         }
     }
 
-    fn table_constructor(&mut self) -> ExpDesc {
-        let table = self.sp;
-        self.sp += 1;
+    fn table_constructor(&mut self) -> Result<ExpDesc, SyntaxError> {
+        let line = self.lexer.line();
+        let table = self.reserve_regs(1)?;
 
-        let inew = self.bytecodes.len();
-        self.bytecodes.push(Bytecode::NewTable(table as u8, 0, 0));
+        let inew = self.fs.bytecodes.len();
+        self.emit(Bytecode::NewTable(table as u8, 0, 0));
 
         enum TableEntry {
-            Map((fn(u8, u8, u8) -> Bytecode, fn(u8, u8, u8) -> Bytecode, usize)),
+            Map((SetCode, SetCode, usize)),
             Array(ExpDesc)
         }
 
@@ -365,89 +1224,194 @@ impl<R: Read> ParseProto<R> {
         let mut tostore = 0;
         let mut narray = 0;
         let mut nmap = 0;
+        // an array item left for the next one to discharge, unless it is
+        // the last one, which gives all its values
+        let mut pending = None;
         loop {
-            let sp0 = self.sp;
+            if self.lexer.peek()? != &Token::CurlyR && let Some(value) = pending.take() {
+                self.array_item(table, value, &mut tostore, &mut stored)?;
+            }
+            let sp0 = self.fs.sp;
 
-            let entry = match self.lexer.peek() {
+            let entry = match self.lexer.peek()? {
+                Token::CurlyR => {
+                    self.lexer.next()?;
+                    break;
+                }
                 Token::CurlyL => {
-                    self.lexer.next();
-                    TableEntry::Array(self.table_constructor())
+                    self.lexer.next()?;
+                    TableEntry::Array(self.table_constructor()?)
                 }
                 Token::SqurL => {
-                    self.lexer.next();
-                    let key = self.exp();
-                    self.lexer.expect(Token::SqurR);
-                    self.lexer.expect(Token::Assign);
+                    self.lexer.next()?;
+                    let key = table_key(self.exp()?);
+                    self.lexer.expect(Token::SqurR)?;
+                    self.lexer.expect(Token::Assign)?;
 
                     TableEntry::Map(
                         match key {
                             ExpDesc::Local(i) => (Bytecode::SetTable, Bytecode::SetTableConst, i),
-                            ExpDesc::String(s) => (Bytecode::SetField, Bytecode::SetFieldConst, self.add_const(s)),
+                            ExpDesc::String(s) => self.map_field_entry(s)?,
                             ExpDesc::Integer(i) if u8::try_from(i).is_ok() => (Bytecode::SetInt, Bytecode::SetIntConst, i as usize),
-                            ExpDesc::Nil => panic!("nil can not be table key"),
-                            ExpDesc::Float(f) if f.is_nan() => panic!("NaN can not be table key"),
-                            _ => (Bytecode::SetTable, Bytecode::SetTableConst, self.discharge_top(key)),
+                            _ => (Bytecode::SetTable, Bytecode::SetTableConst, self.discharge_top(key)?),
                         }
                     )
                 }
                 Token::Ident(_) => {
-                    let name = self.read_name();
-                    if self.lexer.peek() == &Token::Assign {
-                        self.lexer.next();
-                        TableEntry::Map((Bytecode::SetField, Bytecode::SetFieldConst, self.add_const(name)))
+                    let name = self.read_name()?;
+                    if self.lexer.peek()? == &Token::Assign {
+                        self.lexer.next()?;
+                        TableEntry::Map(self.map_field_entry(name.into_bytes())?)
                     } else {
-                        TableEntry::Array(self.exp_with_ahead(Token::Ident(name)))
+                        TableEntry::Array(self.exp_with_ahead(Token::Ident(name))?)
                     }
                 },
                 _ => {
-                    TableEntry::Array(self.exp())
+                    TableEntry::Array(self.exp()?)
                 }
             };
 
             match entry {
                 TableEntry::Map((op, opk, key)) => {
-                    let value = self.exp();
-                    let code = match self.discharge_const(value) {
+                    let value = self.exp()?;
+                    let code = match self.discharge_const(value)? {
                         ConstStack::Const(iv) => opk(table as u8, key as u8, iv as u8),
                         ConstStack::Stack(iv) => op(table as u8, key as u8, iv as u8),
                     };
-                    self.bytecodes.push(code);
+                    self.emit(code);
                     nmap += 1;
-                    self.sp = sp0;
+                    self.fs.sp = sp0;
                 }
                 TableEntry::Array(value) => {
-                    self.discharge(sp0, value);
                     narray += 1;
-                    tostore += 1;
-                    if tostore == 50 {
-                        self.bytecodes.push(Bytecode::SetList(table as u8, tostore, stored));
-                        stored += tostore;
-                    }
+                    pending = Some(value);
                 }
             }
 
-            match self.lexer.next() {
-                Token ::SemiColon | Token::Comma => (),
+            match self.lexer.next()? {
+                Token::SemiColon | Token::Comma => (),
                 Token::CurlyR => break,
-                t => panic!("unexpected token in table constructor: {:?}", t)
+                t => return Err(self.lexer.match_error(Token::CurlyR, Token::CurlyL, line, &t)),
             }
         }
 
-        if self.sp > table + 1 {
-            self.bytecodes.push(Bytecode::SetList(table as u8, (self.sp - (table + 1)) as u8, stored));
+        match pending {
+            Some(ExpDesc::Call(pc)) => {
+                self.set_returns(pc, MULTRET);
+                self.set_list(table, MULTRET as usize, stored);
+            }
+            Some(value) => {
+                self.array_item(table, value, &mut tostore, &mut stored)?;
+                if tostore > 0 {
+                    self.set_list(table, tostore, stored);
+                }
+            }
+            None if tostore > 0 => self.set_list(table, tostore, stored),
+            None => (),
         }
 
-        self.bytecodes[inew] = Bytecode::NewTable(table as u8, narray as u8, nmap as u8);
+        // sizes are only hints
+        let narray = u8::try_from(narray).unwrap_or(u8::MAX);
+        let nmap = u8::try_from(nmap).unwrap_or(u8::MAX);
+        self.fs.bytecodes[inew] = Bytecode::NewTable(table as u8, narray, nmap);
 
-        self.sp = table + 1;
-        ExpDesc::Local(table)
+        self.fs.sp = table + 1;
+        Ok(ExpDesc::Local(table))
+    }
+
+    fn map_field_entry(&mut self, key: Vec<u8>) -> Result<(SetCode, SetCode, usize), SyntaxError> {
+        Ok(match self.field_key(key)? {
+            ConstStack::Const(ikey) => (Bytecode::SetField, Bytecode::SetFieldConst, ikey),
+            ConstStack::Stack(ikey) => (Bytecode::SetTable, Bytecode::SetTableConst, ikey),
+        })
     }
 
-    fn read_name(&mut self) -> String {
-        if let Token::Ident(name) = self.lexer.next() {
-            name
+    // items are stored 50 at a time
+    fn array_item(&mut self, table: usize, value: ExpDesc, tostore: &mut usize, stored: &mut usize)
+        -> Result<(), SyntaxError> {
+        self.discharge_new(value)?;
+        *tostore += 1;
+        if *tostore == 50 {
+            self.set_list(table, *tostore, *stored);
+            *stored += *tostore;
+            *tostore = 0;
+            self.fs.sp = table + 1;
+        }
+        Ok(())
+    }
+
+    fn set_list(&mut self, table: usize, tostore: usize, stored: usize) {
+        if let Ok(stored) = u8::try_from(stored) {
+            self.emit(Bytecode::SetList(table as u8, tostore as u8, stored));
         } else {
-            panic!("expected name");
+            self.emit(Bytecode::SetListX(table as u8, tostore as u8));
+            self.emit(Bytecode::extra_arg(stored));
         }
     }
-}
\ No newline at end of file
+
+    fn read_name(&mut self) -> Result<String, SyntaxError> {
+        match self.lexer.next()? {
+            Token::Ident(name) => Ok(name),
+            t => Err(self.lexer.error_near("<name> expected", &t)),
+        }
+    }
+}
+
+// float keys with integral values are the same as integer keys, and
+// invalid keys like nil are left to fail at run time
+fn table_key(key: ExpDesc) -> ExpDesc {
+    match key {
+        ExpDesc::Float(f) => match arith::float_to_int(f) {
+            Some(i) => ExpDesc::Integer(i),
+            None => key,
+        },
+        _ => key,
+    }
+}
+
+fn arith_op(op: BinOp) -> Option<ArithOp> {
+    Some(match op {
+        BinOp::Add => ArithOp::Add,
+        BinOp::Sub => ArithOp::Sub,
+        BinOp::Mul => ArithOp::Mul,
+        BinOp::Div => ArithOp::Div,
+        BinOp::Idiv => ArithOp::IDiv,
+        BinOp::Mod => ArithOp::Mod,
+        BinOp::Pow => ArithOp::Pow,
+        BinOp::BitAnd => ArithOp::BAnd,
+        BinOp::BitOr => ArithOp::BOr,
+        BinOp::BitXor => ArithOp::BXor,
+        BinOp::ShiftL => ArithOp::Shl,
+        BinOp::ShiftR => ArithOp::Shr,
+        _ => return None,
+    })
+}
+
+fn fold_binop(op: ArithOp, left: &ExpDesc, right: &ExpDesc) -> Option<ExpDesc> {
+    let (a, b) = (left.numeral()?, right.numeral()?);
+
+    // division by zero is left to run time, as an error or an inf/NaN
+    if matches!(op, ArithOp::Div | ArithOp::IDiv | ArithOp::Mod) && b.to_float() == Some(0.0) {
+        return None;
+    }
+    folded(arith::arith(op, &a, &b)?)
+}
+
+fn fold_unop(op: UnOp, operand: &ExpDesc) -> Option<ExpDesc> {
+    match op {
+        UnOp::Not => operand.const_truth().map(|truth| ExpDesc::Boolean(!truth)),
+        UnOp::Neg => folded(arith::neg(&operand.numeral()?)?),
+        UnOp::BitNot => folded(arith::bnot(&operand.numeral()?)?),
+        UnOp::Len => None,
+    }
+}
+
+fn folded(v: Value) -> Option<ExpDesc> {
+    match v {
+        Value::Integer(i) => Some(ExpDesc::Integer(i)),
+        // constants of NaN and -0.0 are not reliably told apart from others
+        Value::Float(f) if f.is_nan() || f == 0.0 => None,
+        Value::Float(f) => Some(ExpDesc::Float(f)),
+        _ => None,
+    }
+}
@@ -0,0 +1,340 @@
+use crate::bytecode::{Bytecode, MULTRET};
+
+// Peephole optimization over the compiled bytecodes. Pairs of adjacent
+// instructions are rewritten when the temporary register passing a value
+// between them is dead afterwards, then removed instructions are dropped
+// and the jump offsets are fixed.
+
+/// Rewrite `bytecodes` in place, with their `lines` if any. Returns the
+/// new position of each old one, and of the end, for the other debug info.
+pub fn optimize(bytecodes: &mut Vec<Bytecode>, lines: &mut Vec<u32>) -> Vec<usize> {
+    let targets = jump_targets(bytecodes);
+    let live_out = liveness(bytecodes);
+
+    let mut keep = vec![true; bytecodes.len()];
+    let mut pc = 0;
+    while pc + 1 < bytecodes.len() {
+        // the second instruction must be reached from the first only
+        if targets[pc + 1] {
+            pc += 1;
+            continue;
+        }
+        let dead = |reg: u8| !live_out[pc + 1].contains(reg);
+
+        match rewrite(bytecodes[pc], bytecodes[pc + 1], dead) {
+            Some(Rewrite::Replace(first, second)) => {
+                bytecodes[pc] = first;
+                bytecodes[pc + 1] = second;
+            }
+            Some(Rewrite::Merge(code)) => {
+                // a jump to the first instruction lands on the merged one
+                bytecodes[pc + 1] = code;
+                keep[pc] = false;
+            }
+            Some(Rewrite::MergeFirst(code)) => {
+                bytecodes[pc] = code;
+                keep[pc + 1] = false;
+                if let Bytecode::Move(dst, src) = code && dst == src {
+                    keep[pc] = false;
+                }
+            }
+            None => {
+                pc += 1;
+                continue;
+            }
+        }
+        pc += 2;
+    }
+
+    compact(bytecodes, lines, &keep)
+}
+
+enum Rewrite {
+    Replace(Bytecode, Bytecode),
+    // into the position of the second instruction
+    Merge(Bytecode),
+    // into the position of the first instruction
+    MergeFirst(Bytecode),
+}
+
+fn rewrite(first: Bytecode, second: Bytecode, dead: impl Fn(u8) -> bool) -> Option<Rewrite> {
+    match (first, second) {
+        // a value computed into a temporary and moved away
+        (_, Bytecode::Move(dst, t)) if dead(t) && writes_only(&first) == Some(t) => {
+            Some(Rewrite::MergeFirst(set_dst(first, dst)))
+        }
+
+        // a constant stored through a temporary
+        (Bytecode::LoadConst(t, k), _) if dead(t) => {
+            let k = u8::try_from(k).ok()?;
+            let code = match second {
+                Bytecode::SetGlobal(name, v) if v == t => Bytecode::SetGlobalConst(name, k),
+                Bytecode::SetTable(table, key, v) if v == t && table != t && key != t =>
+                    Bytecode::SetTableConst(table, key, k),
+                Bytecode::SetField(table, key, v) if v == t && table != t =>
+                    Bytecode::SetFieldConst(table, key, k),
+                Bytecode::SetInt(table, i, v) if v == t && table != t =>
+                    Bytecode::SetIntConst(table, i, k),
+                _ => return None,
+            };
+            Some(Rewrite::Merge(code))
+        }
+
+        // a comparison result only tested by the jump
+        (Bytecode::Eq(t, a, b) | Bytecode::Ne(t, a, b) | Bytecode::Lt(t, a, b) | Bytecode::Le(t, a, b),
+            Bytecode::JumpFalse(r, offset) | Bytecode::JumpTrue(r, offset)) if r == t && dead(t) => {
+            // the jump is taken when the comparison result is `k`
+            let k = matches!(second, Bytecode::JumpTrue(..)) != matches!(first, Bytecode::Ne(..));
+            let test = match first {
+                Bytecode::Eq(..) | Bytecode::Ne(..) => Bytecode::TestEq(a, b, k),
+                Bytecode::Lt(..) => Bytecode::TestLt(a, b, k),
+                _ => Bytecode::TestLe(a, b, k),
+            };
+            Some(Rewrite::Replace(test, Bytecode::Jump(offset)))
+        }
+
+        // a small integer operand
+        (Bytecode::LoadInt(t, i), Bytecode::Add(dst, a, b)) if b == t && a != t && (dead(t) || dst == t) => {
+            Some(Rewrite::Merge(Bytecode::AddI(dst, a, i8::try_from(i).ok()?)))
+        }
+
+        _ => None,
+    }
+}
+
+// the register written by a simple instruction which can be retargeted
+fn writes_only(code: &Bytecode) -> Option<u8> {
+    match *code {
+        Bytecode::GetGlobal(dst, _) | Bytecode::LoadConst(dst, _) | Bytecode::LoadNil(dst, 1)
+            | Bytecode::LoadBool(dst, _) | Bytecode::LoadInt(dst, _) | Bytecode::Move(dst, _)
+            | Bytecode::GetTable(dst, _, _) | Bytecode::GetField(dst, _, _) | Bytecode::GetInt(dst, _, _)
+            | Bytecode::Add(dst, _, _) | Bytecode::Sub(dst, _, _) | Bytecode::Mul(dst, _, _)
+            | Bytecode::Div(dst, _, _) | Bytecode::IDiv(dst, _, _) | Bytecode::Mod(dst, _, _)
+            | Bytecode::Pow(dst, _, _) | Bytecode::BAnd(dst, _, _) | Bytecode::BOr(dst, _, _)
+            | Bytecode::BXor(dst, _, _) | Bytecode::Shl(dst, _, _) | Bytecode::Shr(dst, _, _)
+            | Bytecode::Concat(dst, _, _) | Bytecode::Eq(dst, _, _) | Bytecode::Ne(dst, _, _)
+            | Bytecode::Lt(dst, _, _) | Bytecode::Le(dst, _, _) | Bytecode::Unm(dst, _)
+            | Bytecode::Not(dst, _) | Bytecode::Len(dst, _) | Bytecode::BNot(dst, _)
+            | Bytecode::AddI(dst, _, _) | Bytecode::Closure(dst, _) => Some(dst),
+        _ => None,
+    }
+}
+
+fn set_dst(code: Bytecode, dst: u8) -> Bytecode {
+    match code {
+        Bytecode::GetGlobal(_, k) => Bytecode::GetGlobal(dst, k),
+        Bytecode::LoadConst(_, k) => Bytecode::LoadConst(dst, k),
+        Bytecode::LoadNil(_, n) => Bytecode::LoadNil(dst, n),
+        Bytecode::LoadBool(_, b) => Bytecode::LoadBool(dst, b),
+        Bytecode::LoadInt(_, i) => Bytecode::LoadInt(dst, i),
+        Bytecode::Move(_, src) => Bytecode::Move(dst, src),
+        Bytecode::GetTable(_, t, k) => Bytecode::GetTable(dst, t, k),
+        Bytecode::GetField(_, t, k) => Bytecode::GetField(dst, t, k),
+        Bytecode::GetInt(_, t, k) => Bytecode::GetInt(dst, t, k),
+        Bytecode::Add(_, a, b) => Bytecode::Add(dst, a, b),
+        Bytecode::Sub(_, a, b) => Bytecode::Sub(dst, a, b),
+        Bytecode::Mul(_, a, b) => Bytecode::Mul(dst, a, b),
+        Bytecode::Div(_, a, b) => Bytecode::Div(dst, a, b),
+        Bytecode::IDiv(_, a, b) => Bytecode::IDiv(dst, a, b),
+        Bytecode::Mod(_, a, b) => Bytecode::Mod(dst, a, b),
+        Bytecode::Pow(_, a, b) => Bytecode::Pow(dst, a, b),
+        Bytecode::BAnd(_, a, b) => Bytecode::BAnd(dst, a, b),
+        Bytecode::BOr(_, a, b) => Bytecode::BOr(dst, a, b),
+        Bytecode::BXor(_, a, b) => Bytecode::BXor(dst, a, b),
+        Bytecode::Shl(_, a, b) => Bytecode::Shl(dst, a, b),
+        Bytecode::Shr(_, a, b) => Bytecode::Shr(dst, a, b),
+        Bytecode::Concat(_, a, b) => Bytecode::Concat(dst, a, b),
+        Bytecode::Eq(_, a, b) => Bytecode::Eq(dst, a, b),
+        Bytecode::Ne(_, a, b) => Bytecode::Ne(dst, a, b),
+        Bytecode::Lt(_, a, b) => Bytecode::Lt(dst, a, b),
+        Bytecode::Le(_, a, b) => Bytecode::Le(dst, a, b),
+        Bytecode::Unm(_, src) => Bytecode::Unm(dst, src),
+        Bytecode::Not(_, src) => Bytecode::Not(dst, src),
+        Bytecode::Len(_, src) => Bytecode::Len(dst, src),
+        Bytecode::BNot(_, src) => Bytecode::BNot(dst, src),
+        Bytecode::AddI(_, src, i) => Bytecode::AddI(dst, src, i),
+        Bytecode::Closure(_, i) => Bytecode::Closure(dst, i),
+        code => unreachable!("set dst {:?}", code),
+    }
+}
+
+#[derive(Clone, Copy, Default, PartialEq)]
+struct RegSet([u64; 4]);
+
+impl RegSet {
+    fn contains(&self, reg: u8) -> bool {
+        self.0[reg as usize / 64] & (1 << (reg % 64)) != 0
+    }
+    fn insert(&mut self, reg: u8) {
+        self.0[reg as usize / 64] |= 1 << (reg % 64);
+    }
+    // `first` and `n` registers after it, or all after it for `MULTRET`
+    fn insert_range(&mut self, first: u8, n: u8) {
+        let last = if n == MULTRET { u8::MAX } else { first.saturating_add(n) };
+        for reg in first ..= last {
+            self.insert(reg);
+        }
+    }
+    fn union(&mut self, other: &RegSet) {
+        for (a, b) in self.0.iter_mut().zip(other.0) {
+            *a |= b;
+        }
+    }
+}
+
+// where the control goes after the instruction at `pc`
+fn successors(bytecodes: &[Bytecode], pc: usize) -> [Option<usize>; 2] {
+    let jump = |offset: i16| pc.checked_add_signed(offset as isize + 1);
+    match bytecodes[pc] {
+        Bytecode::Jump(offset) => [jump(offset), None],
+        Bytecode::JumpFalse(_, offset) | Bytecode::JumpTrue(_, offset) => [Some(pc + 1), jump(offset)],
+        Bytecode::TestEq(..) | Bytecode::TestLt(..) | Bytecode::TestLe(..) => [Some(pc + 1), Some(pc + 2)],
+        Bytecode::Return(..) => [None, None],
+        _ => [Some(pc + 1), None],
+    }
+}
+
+fn jump_targets(bytecodes: &[Bytecode]) -> Vec<bool> {
+    let mut targets = vec![false; bytecodes.len() + 1];
+    for pc in 0 .. bytecodes.len() {
+        let [next, other] = successors(bytecodes, pc);
+        if let Bytecode::Jump(_) = bytecodes[pc] {
+            targets[next.unwrap()] = true;
+        }
+        if let Some(target) = other {
+            targets[target] = true;
+        }
+    }
+    targets
+}
+
+// registers read and written by an instruction
+fn uses(code: &Bytecode) -> (RegSet, RegSet) {
+    let mut reads = RegSet::default();
+    let mut writes = RegSet::default();
+    match *code {
+        Bytecode::GetGlobal(dst, _) | Bytecode::GetGlobalX(dst) | Bytecode::LoadConst(dst, _)
+            | Bytecode::LoadConstX(dst) | Bytecode::LoadBool(dst, _) | Bytecode::LoadInt(dst, _)
+            | Bytecode::NewTable(dst, _, _) | Bytecode::Closure(dst, _) => writes.insert(dst),
+        Bytecode::LoadNil(dst, n) => {
+            for reg in dst .. dst + n {
+                writes.insert(reg);
+            }
+        }
+        Bytecode::SetGlobal(_, src) | Bytecode::SetGlobalX(src) | Bytecode::SetFieldConst(src, _, _)
+            | Bytecode::SetIntConst(src, _, _) | Bytecode::JumpFalse(src, _)
+            | Bytecode::JumpTrue(src, _) | Bytecode::Tbc(src) => reads.insert(src),
+        Bytecode::SetField(a, _, b) | Bytecode::SetInt(a, _, b) | Bytecode::SetTableConst(a, b, _)
+            | Bytecode::TestEq(a, b, _) | Bytecode::TestLt(a, b, _) | Bytecode::TestLe(a, b, _) => {
+            reads.insert(a);
+            reads.insert(b);
+        }
+        Bytecode::SetTable(t, k, v) => {
+            reads.insert(t);
+            reads.insert(k);
+            reads.insert(v);
+        }
+        Bytecode::Move(dst, src) | Bytecode::GetField(dst, src, _) | Bytecode::GetInt(dst, src, _)
+            | Bytecode::Unm(dst, src) | Bytecode::Not(dst, src) | Bytecode::Len(dst, src)
+            | Bytecode::BNot(dst, src) | Bytecode::AddI(dst, src, _) => {
+            reads.insert(src);
+            writes.insert(dst);
+        }
+        Bytecode::GetTable(dst, a, b) | Bytecode::Add(dst, a, b) | Bytecode::Sub(dst, a, b)
+            | Bytecode::Mul(dst, a, b) | Bytecode::Div(dst, a, b) | Bytecode::IDiv(dst, a, b)
+            | Bytecode::Mod(dst, a, b) | Bytecode::Pow(dst, a, b) | Bytecode::BAnd(dst, a, b)
+            | Bytecode::BOr(dst, a, b) | Bytecode::BXor(dst, a, b) | Bytecode::Shl(dst, a, b)
+            | Bytecode::Shr(dst, a, b) | Bytecode::Concat(dst, a, b) | Bytecode::Eq(dst, a, b)
+            | Bytecode::Ne(dst, a, b) | Bytecode::Lt(dst, a, b) | Bytecode::Le(dst, a, b) => {
+            reads.insert(a);
+            reads.insert(b);
+            writes.insert(dst);
+        }
+        // the function and its arguments, replaced by the results
+        Bytecode::Call(func, nargs, nresults) => {
+            reads.insert_range(func, nargs);
+            match nresults {
+                0 => (),
+                MULTRET => writes.insert_range(func, MULTRET),
+                n => writes.insert_range(func, n - 1),
+            }
+        }
+        Bytecode::SetList(table, n, _) | Bytecode::SetListX(table, n) => reads.insert_range(table, n),
+        // any variable from the first one may be to be closed
+        Bytecode::Close(first) => reads.insert_range(first, MULTRET),
+        Bytecode::Return(first, n) => match n {
+            0 => (),
+            MULTRET => reads.insert_range(first, MULTRET),
+            n => reads.insert_range(first, n - 1),
+        },
+        Bytecode::SetGlobalConst(..) | Bytecode::Jump(_) | Bytecode::ExtraArg(..) => (),
+    }
+    (reads, writes)
+}
+
+// registers live after each instruction, by backward data flow to a fixed point
+fn liveness(bytecodes: &[Bytecode]) -> Vec<RegSet> {
+    let uses: Vec<_> = bytecodes.iter().map(uses).collect();
+    let mut live_in = vec![RegSet::default(); bytecodes.len() + 1];
+    let mut live_out = vec![RegSet::default(); bytecodes.len()];
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for pc in (0 .. bytecodes.len()).rev() {
+            let mut out = RegSet::default();
+            for succ in successors(bytecodes, pc).into_iter().flatten() {
+                out.union(&live_in[succ]);
+            }
+
+            let (reads, writes) = &uses[pc];
+            let mut live = out;
+            for (l, w) in live.0.iter_mut().zip(writes.0) {
+                *l &= !w;
+            }
+            live.union(reads);
+
+            if live != live_in[pc] {
+                live_in[pc] = live;
+                changed = true;
+            }
+            live_out[pc] = out;
+        }
+    }
+    live_out
+}
+
+// drop the instructions not kept, and fix the jumps over them
+fn compact(bytecodes: &mut Vec<Bytecode>, lines: &mut Vec<u32>, keep: &[bool]) -> Vec<usize> {
+    // new position of each old one, a removed instruction maps to the next kept
+    let mut remap = Vec::with_capacity(bytecodes.len() + 1);
+    let mut n = 0;
+    for &k in keep {
+        remap.push(n);
+        n += k as usize;
+    }
+    remap.push(n);
+
+    let fix = |pc: usize, offset: i16| {
+        let target = remap[pc.wrapping_add_signed(offset as isize + 1)];
+        (target as isize - remap[pc] as isize - 1) as i16
+    };
+    let mut pc = 0;
+    bytecodes.retain_mut(|code| {
+        match code {
+            Bytecode::Jump(offset) | Bytecode::JumpFalse(_, offset) | Bytecode::JumpTrue(_, offset) =>
+                *offset = fix(pc, *offset),
+            _ => (),
+        }
+        pc += 1;
+        keep[pc - 1]
+    });
+    if !lines.is_empty() {
+        let mut pc = 0;
+        lines.retain(|_| {
+            pc += 1;
+            keep[pc - 1]
+        });
+    }
+    remap
+}
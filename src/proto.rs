@@ -0,0 +1,103 @@
+use std::rc::Rc;
+use crate::{bytecode::Bytecode, value::Value};
+
+/// A compiled function, runnable and shareable without the parser that
+/// produced it.
+#[derive(Debug, Default)]
+pub struct Proto {
+    pub constants: Vec<Value>,
+    pub bytecodes: Vec<Bytecode>,
+    pub protos: Vec<Rc<Proto>>,
+    pub max_stack_size: usize,
+    pub nparams: usize, // in the first registers
+
+    // where the function is defined, 0 for the main chunk
+    pub linedefined: u32,
+
+    // debug info, empty if stripped
+    pub source: Option<String>,
+    pub lineinfo: Vec<i8>,
+    pub abslineinfo: Vec<AbsLineInfo>,
+    pub locvars: Vec<LocVar>,
+}
+
+// Lines of instructions are stored as in Lua: `lineinfo` has the difference
+// from the line of the previous instruction, or `ABSLINEINFO` if it does
+// not fit, in which case the line is in `abslineinfo`. There is also an
+// absolute line at least every `MAX_IWTHABS` instructions, so finding a
+// line does not sum over the whole function.
+
+/// Marks an instruction whose line is in `abslineinfo`.
+pub const ABSLINEINFO: i8 = i8::MIN;
+
+/// Most instructions without an absolute line info between two with one.
+pub const MAX_IWTHABS: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AbsLineInfo {
+    pub pc: usize,
+    pub line: u32,
+}
+
+/// A local variable, active in the instructions `startpc .. endpc`.
+#[derive(Debug, Clone)]
+pub struct LocVar {
+    pub name: String,
+    pub startpc: usize,
+    pub endpc: usize,
+}
+
+impl Proto {
+    /// Set the line info from the line of each instruction.
+    pub fn set_lines(&mut self, lines: &[u32]) {
+        self.lineinfo.clear();
+        self.abslineinfo.clear();
+
+        let mut prev = 0;
+        let mut last_abs = 0; // instructions since the last absolute line
+        for (pc, &line) in lines.iter().enumerate() {
+            let delta = i8::try_from(line as i64 - prev as i64).ok().filter(|&d| d != ABSLINEINFO);
+            match delta {
+                Some(delta) if last_abs < MAX_IWTHABS => {
+                    self.lineinfo.push(delta);
+                    last_abs += 1;
+                }
+                _ => {
+                    self.lineinfo.push(ABSLINEINFO);
+                    self.abslineinfo.push(AbsLineInfo { pc, line });
+                    last_abs = 0;
+                }
+            }
+            prev = line;
+        }
+    }
+
+    /// Line of the instruction at `pc`, if known.
+    pub fn line(&self, pc: usize) -> Option<u32> {
+        if pc >= self.lineinfo.len() {
+            return None;
+        }
+        // start from the last absolute line before, if any
+        let (mut base, mut line) = match self.abslineinfo.partition_point(|abs| abs.pc <= pc) {
+            0 => (0, 0),
+            i => {
+                let abs = self.abslineinfo[i - 1];
+                (abs.pc + 1, abs.line)
+            }
+        };
+        while base <= pc {
+            line = line.wrapping_add_signed(self.lineinfo[base] as i32);
+            base += 1;
+        }
+        Some(line)
+    }
+
+    /// Name of the local variable in register `reg` at `pc`, if any.
+    pub fn local_name(&self, reg: usize, pc: usize) -> Option<&str> {
+        // active variables take the registers in their declaration order
+        self.locvars.iter()
+            .filter(|v| v.startpc <= pc && pc < v.endpc)
+            .nth(reg)
+            .map(|v| v.name.as_str())
+    }
+}
@@ -0,0 +1,260 @@
+use super::Value;
+use crate::lexer::{self, Token};
+
+// Lua arithmetic, shared by the VM and the constant folding of the parser.
+
+// 2^63 is exact as a float, i64::MAX is not
+const TWO_POW_63: f64 = 9223372036854775808.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add, Sub, Mul, Div, IDiv, Mod, Pow,
+    BAnd, BOr, BXor, Shl, Shr,
+}
+
+impl ArithOp {
+    pub fn is_bitwise(self) -> bool {
+        matches!(self, ArithOp::BAnd | ArithOp::BOr | ArithOp::BXor | ArithOp::Shl | ArithOp::Shr)
+    }
+}
+
+/// Returns `None` if an operand can not be converted to a number (or to an
+/// integer for bitwise operators), or for integer division by zero.
+pub fn arith(op: ArithOp, a: &Value, b: &Value) -> Option<Value> {
+    if op.is_bitwise() {
+        let (a, b) = (a.to_integer()?, b.to_integer()?);
+        let r = match op {
+            ArithOp::BAnd => a & b,
+            ArithOp::BOr => a | b,
+            ArithOp::BXor => a ^ b,
+            ArithOp::Shl => shift_left(a, b),
+            ArithOp::Shr => shift_left(a, b.wrapping_neg()),
+            _ => unreachable!(),
+        };
+        return Some(Value::Integer(r));
+    }
+
+    match (a.to_number()?, b.to_number()?) {
+        (Value::Integer(a), Value::Integer(b)) => int_arith(op, a, b),
+        (a, b) => Some(Value::Float(float_arith(op, a.to_float()?, b.to_float()?))),
+    }
+}
+
+fn int_arith(op: ArithOp, a: i64, b: i64) -> Option<Value> {
+    let r = match op {
+        ArithOp::Add => a.wrapping_add(b),
+        ArithOp::Sub => a.wrapping_sub(b),
+        ArithOp::Mul => a.wrapping_mul(b),
+        ArithOp::IDiv => {
+            if b == 0 {
+                return None;
+            }
+            let q = a.wrapping_div(b);
+            // round towards minus infinity
+            if a.wrapping_rem(b) != 0 && (a ^ b) < 0 { q - 1 } else { q }
+        }
+        ArithOp::Mod => {
+            if b == 0 {
+                return None;
+            }
+            let m = a.wrapping_rem(b);
+            if m != 0 && (m ^ b) < 0 { m + b } else { m }
+        }
+        ArithOp::Div | ArithOp::Pow => return Some(Value::Float(float_arith(op, a as f64, b as f64))),
+        _ => unreachable!(),
+    };
+    Some(Value::Integer(r))
+}
+
+fn float_arith(op: ArithOp, a: f64, b: f64) -> f64 {
+    match op {
+        ArithOp::Add => a + b,
+        ArithOp::Sub => a - b,
+        ArithOp::Mul => a * b,
+        ArithOp::Div => a / b,
+        ArithOp::Pow => a.powf(b),
+        ArithOp::IDiv => (a / b).floor(),
+        ArithOp::Mod => {
+            let m = a % b;
+            if (m > 0.0 && b < 0.0) || (m < 0.0 && b > 0.0) { m + b } else { m }
+        }
+        _ => unreachable!(),
+    }
+}
+
+// logical shift, negative counts shift right
+fn shift_left(a: i64, n: i64) -> i64 {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((a as u64) << n) as i64
+    } else {
+        ((a as u64) >> -n) as i64
+    }
+}
+
+pub fn neg(a: &Value) -> Option<Value> {
+    match a.to_number()? {
+        Value::Integer(i) => Some(Value::Integer(i.wrapping_neg())),
+        n => Some(Value::Float(-n.to_float()?)),
+    }
+}
+
+pub fn bnot(a: &Value) -> Option<Value> {
+    Some(Value::Integer(!a.to_integer()?))
+}
+
+/// The integer with the exact value of `f`, if any.
+pub fn float_to_int(f: f64) -> Option<i64> {
+    if f.fract() == 0.0 && (-TWO_POW_63..TWO_POW_63).contains(&f) {
+        Some(f as i64)
+    } else {
+        None
+    }
+}
+
+/// Numbers are equal by their mathematical values, others as raw values.
+pub fn equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Integer(i), Value::Float(f)) | (Value::Float(f), Value::Integer(i)) =>
+            float_to_int(*f) == Some(*i),
+        _ => a == b,
+    }
+}
+
+/// `None` if the values are not both numbers or both strings.
+pub fn less_than(a: &Value, b: &Value) -> Option<bool> {
+    Some(match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a < b,
+        (Value::Float(a), Value::Float(b)) => a < b,
+        (Value::Integer(i), Value::Float(f)) => !f.is_nan() && int_lt_float(*i, *f),
+        (Value::Float(f), Value::Integer(i)) => !f.is_nan() && !int_le_float(*i, *f),
+        _ if a.is_string() && b.is_string() => <&[u8]>::from(a) < <&[u8]>::from(b),
+        _ => return None,
+    })
+}
+
+pub fn less_equal(a: &Value, b: &Value) -> Option<bool> {
+    Some(match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) => a <= b,
+        (Value::Float(a), Value::Float(b)) => a <= b,
+        (Value::Integer(i), Value::Float(f)) => !f.is_nan() && int_le_float(*i, *f),
+        (Value::Float(f), Value::Integer(i)) => !f.is_nan() && !int_lt_float(*i, *f),
+        _ if a.is_string() && b.is_string() => <&[u8]>::from(a) <= <&[u8]>::from(b),
+        _ => return None,
+    })
+}
+
+// exact comparisons for any non-NaN float, without converting the integer
+fn int_lt_float(i: i64, f: f64) -> bool {
+    if f >= TWO_POW_63 {
+        true
+    } else if f > -TWO_POW_63 {
+        i < f.ceil() as i64
+    } else {
+        false
+    }
+}
+
+fn int_le_float(i: i64, f: f64) -> bool {
+    if f >= TWO_POW_63 {
+        true
+    } else if f >= -TWO_POW_63 {
+        i <= f.floor() as i64
+    } else {
+        false
+    }
+}
+
+/// `None` unless both values are strings or numbers.
+pub fn concat(a: &Value, b: &Value) -> Option<Value> {
+    let mut s = a.concat_bytes()?;
+    s.extend_from_slice(&b.concat_bytes()?);
+    Some(s.into())
+}
+
+impl Value {
+    /// Numbers, and strings converted to numbers as Lua does.
+    pub fn to_number(&self) -> Option<Value> {
+        match self {
+            Value::Integer(_) | Value::Float(_) => Some(self.clone()),
+            _ if self.is_string() => str_to_number(self.into()),
+            _ => None,
+        }
+    }
+
+    pub fn to_float(&self) -> Option<f64> {
+        match self.to_number()? {
+            Value::Integer(i) => Some(i as f64),
+            Value::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+
+    /// Floats and strings are converted only if they have exact integer values.
+    pub fn to_integer(&self) -> Option<i64> {
+        match self.to_number()? {
+            Value::Integer(i) => Some(i),
+            Value::Float(f) => float_to_int(f),
+            _ => None,
+        }
+    }
+
+    fn concat_bytes(&self) -> Option<Vec<u8>> {
+        match self {
+            Value::Integer(i) => Some(i.to_string().into_bytes()),
+            Value::Float(f) => Some(fmt_float(*f).into_bytes()),
+            _ if self.is_string() => Some(<&[u8]>::from(self).to_vec()),
+            _ => None,
+        }
+    }
+}
+
+fn str_to_number(s: &[u8]) -> Option<Value> {
+    let s = s.trim_ascii();
+    let (neg, digits) = match s.split_first()? {
+        (b'-', rest) => (true, rest),
+        (b'+', rest) => (false, rest),
+        _ => (false, s),
+    };
+    // the lexer also reads "inf" and "nan", which are not numerals
+    if !digits.first().is_some_and(|c| c.is_ascii_digit() || *c == b'.') {
+        return None;
+    }
+    match lexer::str_to_number(digits)? {
+        Token::Integer(i) => Some(Value::Integer(if neg { i.wrapping_neg() } else { i })),
+        Token::Float(f) => Some(Value::Float(if neg { -f } else { f })),
+        _ => None,
+    }
+}
+
+/// Format a float like Lua's "%.14g", keeping a ".0" on integral values.
+pub fn fmt_float(f: f64) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+
+    let sci = format!("{:.13e}", f);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let s = if !(-4..14).contains(&exp) {
+        let mantissa = trim_fraction(mantissa);
+        format!("{}e{}{:02}", mantissa, if exp < 0 { '-' } else { '+' }, exp.abs())
+    } else {
+        let fixed = format!("{:.*}", (13 - exp) as usize, f);
+        trim_fraction(&fixed).to_string()
+    };
+
+    if s.contains(['.', 'e']) { s } else { s + ".0" }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
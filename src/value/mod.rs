@@ -1,16 +1,30 @@
+pub mod arith;
 mod table;
 
 use std::{cell::RefCell, fmt, hash::{Hash, Hasher}, mem, rc::Rc};
-use crate::vm::ExeState;
+use crate::{proto::Proto, vm::{Coroutine, ExeState, LuaError}};
 pub use table::Table;
 
 const SHORT_STR_MAX: usize = 14;
 const MID_STR_MAX: usize = 48 - 1;
 
+/// A function implemented in Rust, see `vm` for its calling convention.
+pub type RustFunction = fn(&mut ExeState) -> Result<usize, LuaError>;
+
+/// A Rust function with values of its own, which it gets by
+/// `ExeState::upvalue()`.
+pub struct RustClosure {
+    pub f: RustFunction,
+    pub upvalues: Vec<Value>,
+}
+
 #[derive(Default, Clone)]
 pub enum Value {
-    Function(fn(&mut ExeState) -> i32),
+    RustFunction(RustFunction),
+    LuaFunction(Rc<Proto>),
+    RustClosure(Rc<RustClosure>),
     Table(Rc<RefCell<Table>>),
+    Thread(Rc<RefCell<Coroutine>>),
     ShortString(u8, [u8; SHORT_STR_MAX]),
     MidString(Rc<(u8, [u8; MID_STR_MAX])>),
     LongString(Rc<Vec<u8>>),
@@ -24,7 +38,10 @@ pub enum Value {
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Value::Function(_) => write!(f, "Function"),
+            Value::RustFunction(_) => write!(f, "Function"),
+            Value::LuaFunction(p) => write!(f, "LuaFunction({:p})", Rc::as_ptr(p)),
+            Value::RustClosure(c) => write!(f, "RustClosure({:p})", Rc::as_ptr(c)),
+            Value::Thread(co) => write!(f, "Thread({:p})", Rc::as_ptr(co)),
             Value::Table(t) => {
                 let t = t.borrow();
                 let mut map_content = String::new();
@@ -59,19 +76,38 @@ impl fmt::Debug for Value {
 
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_nested(f, &mut Vec::new())
+    }
+}
+
+impl Value {
+    // inside the tables of `path`, which are printed as `{...}` if met
+    // again in a cycle
+    fn fmt_nested(&self, f: &mut fmt::Formatter<'_>, path: &mut Vec<*const RefCell<Table>>) -> fmt::Result {
         match self {
-            Value::Function(_) => write!(f, "Function"),
+            Value::RustFunction(_) | Value::LuaFunction(_) | Value::RustClosure(_) => write!(f, "Function"),
+            Value::Thread(_) => write!(f, "Thread"),
             Value::Table(t) => {
+                if path.contains(&Rc::as_ptr(t)) {
+                    return write!(f, "{{...}}");
+                }
+                path.push(Rc::as_ptr(t));
                 let t = t.borrow();
-                let mut map_content = String::new();
-                let mut array_content = String::new();
+                write!(f, "{{")?;
                 for (i, v) in t.array.iter().enumerate() {
-                    array_content += format!("[{}] = {}, ", i + 1, v).as_str();
+                    write!(f, "[{}] = ", i + 1)?;
+                    v.fmt_nested(f, path)?;
+                    write!(f, ", ")?;
                 }
                 for (k, v) in t.map.iter() {
-                    map_content += format!("[{}] = {}, ", k, v).as_str();
+                    write!(f, "[")?;
+                    k.fmt_nested(f, path)?;
+                    write!(f, "] = ")?;
+                    v.fmt_nested(f, path)?;
+                    write!(f, ", ")?;
                 }
-                write!(f, "{{{}{}}}", array_content, map_content)
+                path.pop();
+                write!(f, "}}")
             },
             Value::LongString(s) => {
                 let s = String::from_utf8_lossy(s);
@@ -86,31 +122,51 @@ impl fmt::Display for Value {
                 write!(f, "\"{}\"", s)
             }
             Value::Integer(n) => write!(f, "{}", n),
-            Value::Float(n) => write!(f, "{}", n),
+            Value::Float(n) => write!(f, "{}", arith::fmt_float(*n)),
             Value::Boolean(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil")
         }
     }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::RustFunction(_) | Value::LuaFunction(_) | Value::RustClosure(_) => "function",
+            Value::Table(_) => "table",
+            Value::Thread(_) => "thread",
+            Value::ShortString(..) | Value::MidString(_) | Value::LongString(_) => "string",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::Boolean(_) => "boolean",
+            Value::Nil => "nil",
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        matches!(self, Value::ShortString(..) | Value::MidString(_) | Value::LongString(_))
+    }
+
+    pub fn is_function(&self) -> bool {
+        matches!(self, Value::RustFunction(_) | Value::LuaFunction(_) | Value::RustClosure(_))
+    }
+
+    /// Only `nil` and `false` are false in conditions.
+    pub fn is_falsy(&self) -> bool {
+        matches!(self, Value::Nil | Value::Boolean(false))
+    }
 }
 
 impl PartialEq for Value {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
-            (Value::Function(_), Value::Function(_)) => true,
+            (Value::RustFunction(f1), Value::RustFunction(f2)) => *f1 as usize == *f2 as usize,
+            (Value::LuaFunction(p1), Value::LuaFunction(p2)) => Rc::ptr_eq(p1, p2),
+            (Value::RustClosure(c1), Value::RustClosure(c2)) => Rc::ptr_eq(c1, c2),
             (Value::Table(t1), Value::Table(t2)) => Rc::ptr_eq(t1, t2),
+            (Value::Thread(co1), Value::Thread(co2)) => Rc::ptr_eq(co1, co2),
             (Value::LongString(s1), Value::LongString(s2)) => s1 == s2,
-            (Value::ShortString(len1, s1), Value::ShortString(len2, s2)) => {
-                if len1 != len2 { return false; }
-                let s1 = String::from_utf8_lossy(&s1[..*len1 as usize]);
-                let s2 = String::from_utf8_lossy(&s2[..*len2 as usize]);
-                s1 == s2
-            },
-            (Value::MidString(s1), Value::MidString(s2)) => {
-                if s1.0 != s2.0 { return false; }
-                let s1 = String::from_utf8_lossy(&s1.1[..s1.0 as usize]);
-                let s2 = String::from_utf8_lossy(&s2.1[..s2.0 as usize]);
-                s1 == s2
-            }
+            (Value::ShortString(len1, s1), Value::ShortString(len2, s2)) =>
+                s1[..*len1 as usize] == s2[..*len2 as usize],
+            (Value::MidString(s1), Value::MidString(s2)) =>
+                s1.1[..s1.0 as usize] == s2.1[..s2.0 as usize],
             (Value::Integer(n1), Value::Integer(n2)) => n1 == n2,
             (Value::Float(n1), Value::Float(n2)) => n1 == n2,
             (Value::Boolean(b1), Value::Boolean(b2)) => b1 == b2,
@@ -135,7 +191,10 @@ impl Hash for Value {
             Value::MidString(s) => s.1[..s.0 as usize].hash(state),
             Value::LongString(s) => s.hash(state),
             Value::Table(t) => Rc::as_ptr(t).hash(state),
-            Value::Function(f) => (*f as *const usize).hash(state),
+            Value::RustFunction(f) => (*f as *const usize).hash(state),
+            Value::LuaFunction(p) => Rc::as_ptr(p).hash(state),
+            Value::RustClosure(c) => Rc::as_ptr(c).hash(state),
+            Value::Thread(co) => Rc::as_ptr(co).hash(state),
         }
     }
 }
@@ -185,12 +244,12 @@ fn vec_to_short_mid_str(v: &[u8]) -> Option<Value> {
     let len = v.len();
     if len <= SHORT_STR_MAX {
         let mut buf = [0; SHORT_STR_MAX];
-        buf[..len].copy_from_slice(&v);
+        buf[..len].copy_from_slice(v);
         Some(Value::ShortString(len as u8, buf))
 
     } else if len <= MID_STR_MAX {
         let mut buf = [0; MID_STR_MAX];
-        buf[..len].copy_from_slice(&v);
+        buf[..len].copy_from_slice(v);
         Some(Value::MidString(Rc::new((len as u8, buf))))
 
     } else {
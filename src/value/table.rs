@@ -1,16 +1,113 @@
-use super::Value;
-use std::collections::HashMap;
+use super::{arith, Value};
+use std::{cell::RefCell, cmp::Ordering, collections::HashMap, rc::Rc};
 
 pub struct Table {
     pub array: Vec<Value>,
-    pub map: HashMap<Value, Value>
+    pub map: HashMap<Value, Value>,
+    pub metatable: Option<Rc<RefCell<Table>>>,
 }
 
 impl Table {
-    pub fn new(narray: usize, nmap: usize) -> Self {
+    // by `ExeState::new_table()`, for the collector
+    pub(crate) fn new(narray: usize, nmap: usize) -> Self {
         Table {
             array: Vec::with_capacity(narray),
-            map: HashMap::with_capacity(nmap)
+            map: HashMap::with_capacity(nmap),
+            metatable: None,
         }
     }
-}
\ No newline at end of file
+
+    /// `t[key]` without metamethods, nil if absent. Floats with integral
+    /// values are the same keys as the integers.
+    pub fn get(&self, key: &Value) -> &Value {
+        match key {
+            Value::Integer(i) => self.get_int(*i),
+            Value::Float(f) => match arith::float_to_int(*f) {
+                Some(i) => self.get_int(i),
+                None => self.map.get(key).unwrap_or(&Value::Nil),
+            },
+            _ => self.map.get(key).unwrap_or(&Value::Nil),
+        }
+    }
+
+    pub fn get_int(&self, i: i64) -> &Value {
+        let index = usize::try_from(i.wrapping_sub(1)).ok();
+        index.and_then(|index| self.array.get(index))
+            .unwrap_or_else(|| self.map.get(&Value::Integer(i)).unwrap_or(&Value::Nil))
+    }
+
+    /// `t[key] = value` without metamethods. Nil and NaN keys are errors.
+    pub fn set(&mut self, key: Value, value: Value) -> Result<(), String> {
+        match key {
+            Value::Integer(i) => self.set_int(i, value),
+            Value::Float(f) => match arith::float_to_int(f) {
+                Some(i) => self.set_int(i, value),
+                None if f.is_nan() => return Err("table index is NaN".to_string()),
+                None => {
+                    self.map.insert(key, value);
+                }
+            },
+            Value::Nil => return Err("table index is nil".to_string()),
+            _ => {
+                self.map.insert(key, value);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn set_int(&mut self, i: i64, value: Value) {
+        // this is not same with Lua's official implement
+        if i > 0 && (i < 4 || i < self.array.capacity() as i64 * 2) {
+            set_vec(&mut self.array, i as usize - 1, value);
+        } else {
+            self.map.insert(Value::Integer(i), value);
+        }
+    }
+
+    /// Store `values` in the array part, after the first `nelems` items,
+    /// as table constructors do.
+    pub fn set_list(&mut self, nelems: usize, values: &[Value]) {
+        // a constructor stores its items in order, but a crafted chunk may
+        // skip far past the array, which is not to grow for that
+        if nelems > self.array.len() {
+            for (i, v) in values.iter().enumerate() {
+                self.set_int((nelems + i + 1) as i64, v.clone());
+            }
+            return;
+        }
+        self.array.reserve(values.len());
+        for (i, v) in values.iter().enumerate() {
+            set_vec(&mut self.array, nelems + i, v.clone());
+        }
+    }
+
+    /// A border of the table, which is what Lua's `#` returns.
+    pub fn border(&self) -> i64 {
+        let mut n = self.array.len();
+        while n > 0 && self.array[n - 1] == Value::Nil {
+            n -= 1;
+        }
+        if n < self.array.len() {
+            return n as i64;
+        }
+
+        // the sequence may continue in the map part
+        let mut n = n as i64;
+        while self.map.get(&Value::Integer(n + 1)).is_some_and(|v| v != &Value::Nil) {
+            n += 1;
+        }
+        n
+    }
+}
+
+// store at `i`, growing the vector with nils as needed
+fn set_vec(vec: &mut Vec<Value>, i: usize, value: Value) {
+    match i.cmp(&vec.len()) {
+        Ordering::Less => vec[i] = value,
+        Ordering::Equal => vec.push(value),
+        Ordering::Greater => {
+            vec.resize(i, Value::Nil);
+            vec.push(value);
+        }
+    }
+}
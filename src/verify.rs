@@ -0,0 +1,117 @@
+use crate::{bytecode::{Bytecode, MULTRET}, proto::Proto};
+
+// Checks of loaded bytecode, which the VM runs without bounds checks of
+// its own beyond Rust's. The compiler never emits code failing them.
+
+/// Check that the operands of all instructions of `proto` are within its
+/// registers, constants and nested prototypes, that jumps land in its code
+/// and that the code can not run past its end. Nested prototypes are
+/// checked on their own.
+pub fn verify(proto: &Proto) -> Result<(), String> {
+    let nregs = proto.max_stack_size;
+    let nconsts = proto.constants.len();
+    let ncode = proto.bytecodes.len();
+
+    if !matches!(proto.bytecodes.last(), Some(Bytecode::Return(..) | Bytecode::Jump(_))) {
+        return Err("missing final return".to_string());
+    }
+
+    for (pc, code) in proto.bytecodes.iter().enumerate() {
+        let error = |what: &str| Err(format!("{} in instruction {} ({:?})", what, pc + 1, code));
+
+        let extra = match proto.bytecodes.get(pc + 1) {
+            Some(next @ Bytecode::ExtraArg(..)) => Some(next.extra_arg_value()),
+            _ => None,
+        };
+        let prev_needs_extra = pc > 0 && matches!(proto.bytecodes[pc - 1],
+            Bytecode::GetGlobalX(_) | Bytecode::SetGlobalX(_) | Bytecode::LoadConstX(_) | Bytecode::SetListX(..));
+
+        let (regs, consts, names) = operands(*code, extra);
+        if regs.iter().any(|&(first, n)| first + n > nregs) {
+            return error("register out of range");
+        }
+        if consts.iter().chain(&names).any(|&k| k >= nconsts) {
+            return error("constant out of range");
+        }
+        // global names are looked up as UTF-8 strings
+        if names.iter().any(|&k| !proto.constants[k].is_string()
+            || str::from_utf8((&proto.constants[k]).into()).is_err()) {
+            return error("invalid global name");
+        }
+
+        match *code {
+            Bytecode::GetGlobalX(_) | Bytecode::SetGlobalX(_) | Bytecode::LoadConstX(_)
+                | Bytecode::SetListX(..) if extra.is_none() => return error("missing ExtraArg"),
+            Bytecode::ExtraArg(..) if !prev_needs_extra => return error("unexpected ExtraArg"),
+            Bytecode::Jump(offset) | Bytecode::JumpFalse(_, offset) | Bytecode::JumpTrue(_, offset) =>
+                match pc.checked_add_signed(offset as isize + 1).filter(|&target| target < ncode) {
+                    None => return error("jump out of range"),
+                    // the operand of the instruction before
+                    Some(target) if matches!(proto.bytecodes[target], Bytecode::ExtraArg(..)) =>
+                        return error("jump into ExtraArg"),
+                    Some(_) => (),
+                },
+            Bytecode::Closure(_, i) if i as usize >= proto.protos.len() => return error("function out of range"),
+            // the registers above the results are gone until they are used,
+            // from below them
+            Bytecode::Call(func, _, MULTRET) if !match proto.bytecodes.get(pc + 1) {
+                Some(&(Bytecode::Call(a, MULTRET, _) | Bytecode::SetList(a, MULTRET, _)
+                    | Bytecode::SetListX(a, MULTRET))) => a < func,
+                Some(&Bytecode::Return(a, MULTRET)) => a <= func,
+                _ => false,
+            } => return error("open results not used"),
+            Bytecode::TestEq(..) | Bytecode::TestLt(..) | Bytecode::TestLe(..)
+                if !matches!(proto.bytecodes.get(pc + 1), Some(Bytecode::Jump(_))) =>
+                return error("test without jump"),
+            // skipping the jump
+            Bytecode::TestEq(..) | Bytecode::TestLt(..) | Bytecode::TestLe(..) if pc + 2 >= ncode =>
+                return error("test out of range"),
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+// (first, count) of register ranges, constant indexes, and constant
+// indexes of global names read by an instruction
+fn operands(code: Bytecode, extra: Option<usize>) -> (Vec<(usize, usize)>, Vec<usize>, Vec<usize>) {
+    let r = |reg: u8| (reg as usize, 1);
+    // the values up to the top are not registers
+    let n = |n: u8| if n == MULTRET { 0 } else { n as usize };
+    let k = |k: u8| k as usize;
+    let extra = extra.unwrap_or(0);
+    match code {
+        Bytecode::GetGlobal(a, name) | Bytecode::SetGlobal(name, a) => (vec![r(a)], vec![], vec![k(name)]),
+        Bytecode::SetGlobalConst(name, v) => (vec![], vec![k(v)], vec![k(name)]),
+        Bytecode::GetGlobalX(a) | Bytecode::SetGlobalX(a) => (vec![r(a)], vec![], vec![extra]),
+        Bytecode::LoadConst(a, c) => (vec![r(a)], vec![c as usize], vec![]),
+        Bytecode::LoadConstX(a) => (vec![r(a)], vec![extra], vec![]),
+        Bytecode::LoadNil(a, n) => (vec![(a as usize, n as usize)], vec![], vec![]),
+        Bytecode::LoadBool(a, _) | Bytecode::LoadInt(a, _) | Bytecode::NewTable(a, _, _)
+            | Bytecode::JumpFalse(a, _) | Bytecode::JumpTrue(a, _) | Bytecode::Closure(a, _)
+            | Bytecode::Tbc(a) | Bytecode::Close(a) =>
+            (vec![r(a)], vec![], vec![]),
+        // the function and its arguments, and the results
+        Bytecode::Call(a, nargs, nresults) =>
+            (vec![(a as usize, n(nargs) + 1), (a as usize, n(nresults))], vec![], vec![]),
+        Bytecode::Return(a, count) => (vec![(a as usize, n(count))], vec![], vec![]),
+        Bytecode::SetList(t, count, _) | Bytecode::SetListX(t, count) =>
+            (vec![(t as usize, n(count) + 1)], vec![], vec![]),
+        Bytecode::Move(a, b) | Bytecode::Unm(a, b) | Bytecode::Not(a, b) | Bytecode::Len(a, b)
+            | Bytecode::BNot(a, b) | Bytecode::SetInt(a, _, b) | Bytecode::GetInt(a, b, _)
+            | Bytecode::TestEq(a, b, _) | Bytecode::TestLt(a, b, _) | Bytecode::TestLe(a, b, _)
+            | Bytecode::AddI(a, b, _) => (vec![r(a), r(b)], vec![], vec![]),
+        Bytecode::SetField(t, c, v) | Bytecode::GetField(v, t, c) => (vec![r(t), r(v)], vec![k(c)], vec![]),
+        Bytecode::SetTableConst(t, key, c) => (vec![r(t), r(key)], vec![k(c)], vec![]),
+        Bytecode::SetFieldConst(t, c1, c2) => (vec![r(t)], vec![k(c1), k(c2)], vec![]),
+        Bytecode::SetIntConst(t, _, c) => (vec![r(t)], vec![k(c)], vec![]),
+        Bytecode::SetTable(a, b, c) | Bytecode::GetTable(a, b, c) | Bytecode::Add(a, b, c)
+            | Bytecode::Sub(a, b, c) | Bytecode::Mul(a, b, c) | Bytecode::Div(a, b, c)
+            | Bytecode::IDiv(a, b, c) | Bytecode::Mod(a, b, c) | Bytecode::Pow(a, b, c)
+            | Bytecode::BAnd(a, b, c) | Bytecode::BOr(a, b, c) | Bytecode::BXor(a, b, c)
+            | Bytecode::Shl(a, b, c) | Bytecode::Shr(a, b, c) | Bytecode::Concat(a, b, c)
+            | Bytecode::Eq(a, b, c) | Bytecode::Ne(a, b, c) | Bytecode::Lt(a, b, c)
+            | Bytecode::Le(a, b, c) => (vec![r(a), r(b), r(c)], vec![], vec![]),
+        Bytecode::Jump(_) | Bytecode::ExtraArg(..) => (vec![], vec![], vec![]),
+    }
+}
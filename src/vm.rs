@@ -1,38 +1,988 @@
-use std::{cell::RefCell, cmp::Ordering, collections::HashMap, io::Read, rc::Rc};
-use crate::{bytecode::Bytecode, parser::ParseProto, value::{Value, Table}};
+use std::{cell::RefCell, collections::HashMap, error::Error, fmt, mem, rc::Rc};
+use crate::{bytecode::{Bytecode, MULTRET}, gc::Heap, proto::Proto, value::{arith::{self, ArithOp}, RustClosure, RustFunction, Value, Table}};
 
-fn rs_print(state: &mut ExeState) -> i32 {
-    println!("{}", state.stack[state.func_index + 1]);
-    0
+// Rust functions get their arguments by `arg_count()` and `arg()`, push
+// their results by `push()` and return how many they pushed. In coroutines
+// they may return `yield_()` or `yield_k()` instead.
+
+fn rs_print(state: &mut ExeState) -> Result<usize, LuaError> {
+    let args: Vec<String> = (1 ..= state.arg_count()).map(|i| state.arg(i).to_string()).collect();
+    println!("{}", args.join("\t"));
+    Ok(0)
+}
+
+fn rs_dbg_print(state: &mut ExeState) -> Result<usize, LuaError> {
+    let args: Vec<String> = (1 ..= state.arg_count()).map(|i| format!("{:?}", state.arg(i))).collect();
+    println!("{}", args.join("\t"));
+    Ok(0)
+}
+
+fn lib_error(state: &mut ExeState) -> Result<usize, LuaError> {
+    let value = state.arg(1).clone();
+    let level = match state.arg(2) {
+        Value::Nil => 1,
+        v => v.to_integer().ok_or_else(||
+            LuaError::from(format!("bad argument #2 to 'error' (number expected, got {})", v.type_name())))?,
+    };
+    // only messages get a position
+    if level > 0 && value.is_string() {
+        let msg = String::from_utf8_lossy((&value).into()).into_owned();
+        return Err(LuaError::new(state.where_(level as usize) + &msg));
+    }
+    Err(LuaError::new(value))
+}
+
+fn lib_pcall(state: &mut ExeState) -> Result<usize, LuaError> {
+    let func = state.base;
+    if state.arg_count() == 0 {
+        return Err("bad argument #1 to 'pcall' (value expected)".to_string().into());
+    }
+    let status = match state.protected_call(func, None) {
+        Ok(n) => n,
+        Err(e) if e.yielding.is_some() => return Err(e),
+        Err(e) => {
+            state.stack.push(e.value);
+            1
+        }
+    };
+    Ok(status + 1)
+}
+
+// The rest of `pcall()` and `xpcall()` after a yield in their call, with
+// the status and the results or the error in place of the arguments.
+fn finish_pcall(state: &mut ExeState, _ctx: usize) -> Result<usize, LuaError> {
+    Ok(state.arg_count())
+}
+
+fn lib_xpcall(state: &mut ExeState) -> Result<usize, LuaError> {
+    let func = state.base;
+    if state.arg_count() < 2 {
+        return Err("bad argument #2 to 'xpcall' (value expected)".to_string().into());
+    }
+    // the handler makes the error value returned
+    let handler = state.stack.remove(func + 1);
+    let status = match state.protected_call(func, Some(handler)) {
+        Ok(n) => n,
+        Err(e) if e.yielding.is_some() => return Err(e),
+        Err(e) => {
+            state.stack.push(e.value);
+            1
+        }
+    };
+    Ok(status + 1)
+}
+
+fn lib_collectgarbage(state: &mut ExeState) -> Result<usize, LuaError> {
+    match state.arg(1) {
+        Value::Nil => (),
+        opt if opt == &Value::from("collect") => (),
+        opt if opt.is_string() => {
+            let opt = String::from_utf8_lossy(opt.into()).into_owned();
+            return Err(format!("bad argument #1 to 'collectgarbage' (invalid option '{}')", opt).into());
+        }
+        _ => return Err(arg_error(state, 1, "collectgarbage", "string")),
+    }
+    state.collect_garbage();
+    state.push(0);
+    Ok(1)
+}
+
+fn lib_setmetatable(state: &mut ExeState) -> Result<usize, LuaError> {
+    let Value::Table(t) = state.arg(1) else {
+        return Err(arg_error(state, 1, "setmetatable", "table"));
+    };
+    let mt = match state.arg(2) {
+        Value::Table(mt) if state.arg_count() >= 2 => Some(mt.clone()),
+        Value::Nil if state.arg_count() >= 2 => None,
+        _ => return Err(arg_error(state, 2, "setmetatable", "nil or table")),
+    };
+    if metamethod(state.arg(1), "__metatable") != Value::Nil {
+        return Err("cannot change a protected metatable".to_string().into());
+    }
+    t.borrow_mut().metatable = mt;
+    state.push(state.arg(1).clone());
+    Ok(1)
+}
+
+fn lib_getmetatable(state: &mut ExeState) -> Result<usize, LuaError> {
+    if state.arg_count() == 0 {
+        return Err("bad argument #1 to 'getmetatable' (value expected)".to_string().into());
+    }
+    let mt = match state.arg(1) {
+        Value::Table(t) => t.borrow().metatable.clone(),
+        _ => None,
+    };
+    // a `__metatable` field stands for the metatable
+    let value = match mt {
+        Some(mt) => match metamethod(state.arg(1), "__metatable") {
+            Value::Nil => Value::Table(mt),
+            protected => protected,
+        },
+        None => Value::Nil,
+    };
+    state.push(value);
+    Ok(1)
+}
+
+fn lib_rawget(state: &mut ExeState) -> Result<usize, LuaError> {
+    let Value::Table(t) = state.arg(1) else {
+        return Err(arg_error(state, 1, "rawget", "table"));
+    };
+    if state.arg_count() < 2 {
+        return Err("bad argument #2 to 'rawget' (value expected)".to_string().into());
+    }
+    let value = t.borrow().get(state.arg(2)).clone();
+    state.push(value);
+    Ok(1)
+}
+
+fn lib_rawset(state: &mut ExeState) -> Result<usize, LuaError> {
+    let Value::Table(t) = state.arg(1) else {
+        return Err(arg_error(state, 1, "rawset", "table"));
+    };
+    if state.arg_count() < 3 {
+        let i = state.arg_count() + 1;
+        return Err(format!("bad argument #{} to 'rawset' (value expected)", i).into());
+    }
+    t.borrow_mut().set(state.arg(2).clone(), state.arg(3).clone())?;
+    state.push(state.arg(1).clone());
+    Ok(1)
 }
 
-fn rs_dbg_print(state: &mut ExeState) -> i32 {
-    println!("{:?}", state.stack[state.func_index + 1]);
-    0
+fn lib_cocreate(state: &mut ExeState) -> Result<usize, LuaError> {
+    let co = new_coroutine(state, "create")?;
+    state.push(co);
+    Ok(1)
+}
+
+fn lib_coresume(state: &mut ExeState) -> Result<usize, LuaError> {
+    let co = coroutine_arg(state, "resume")?;
+    let first = state.base + 1;
+    match state.resume(&co, first) {
+        Ok(n) => {
+            state.stack.insert(first, Value::Boolean(true));
+            Ok(n + 1)
+        }
+        Err(e) => {
+            state.push(false);
+            state.push(e.value);
+            Ok(2)
+        }
+    }
+}
+
+fn lib_yield(state: &mut ExeState) -> Result<usize, LuaError> {
+    state.yield_(state.arg_count())
+}
+
+fn lib_cowrap(state: &mut ExeState) -> Result<usize, LuaError> {
+    let co = new_coroutine(state, "wrap")?;
+    let wrap = state.track(Value::RustClosure(Rc::new(RustClosure { f: wrap_resume, upvalues: vec![co] })));
+    state.push(wrap);
+    Ok(1)
+}
+
+// the function made by `coroutine.wrap()`, raising the errors
+fn wrap_resume(state: &mut ExeState) -> Result<usize, LuaError> {
+    let Some(Value::Thread(co)) = state.upvalue(1).cloned() else {
+        unreachable!();
+    };
+    let first = state.base;
+    let mut e = match state.resume(&co, first) {
+        Ok(n) => return Ok(n),
+        Err(e) => e,
+    };
+    // the variables to be closed of a failed body go with the error
+    if co.borrow().error.is_some() {
+        e = state.close_coroutine(&co).unwrap_err();
+    }
+    if e.value.is_string() {
+        let msg = String::from_utf8_lossy((&e.value).into()).into_owned();
+        e = LuaError::new(state.where_(1) + &msg);
+    }
+    Err(e)
+}
+
+fn lib_costatus(state: &mut ExeState) -> Result<usize, LuaError> {
+    let co = coroutine_arg(state, "status")?;
+    let status = match co.borrow().status {
+        Status::Suspended => "suspended",
+        Status::Running => "running",
+        Status::Normal => "normal",
+        Status::Dead => "dead",
+    };
+    state.push(status);
+    Ok(1)
+}
+
+fn lib_yieldable(state: &mut ExeState) -> Result<usize, LuaError> {
+    // this function itself is called from Lua code or not
+    let yieldable = state.check_yieldable().is_ok();
+    state.push(yieldable);
+    Ok(1)
+}
+
+fn lib_coclose(state: &mut ExeState) -> Result<usize, LuaError> {
+    let co = coroutine_arg(state, "close")?;
+    let status = co.borrow().status;
+    match status {
+        Status::Running => return Err("cannot close a running coroutine".to_string().into()),
+        Status::Normal => return Err("cannot close a normal coroutine".to_string().into()),
+        Status::Suspended | Status::Dead => (),
+    }
+    match state.close_coroutine(&co) {
+        Ok(()) => {
+            state.push(true);
+            Ok(1)
+        }
+        Err(e) => {
+            state.push(false);
+            state.push(e.value);
+            Ok(2)
+        }
+    }
+}
+
+// a coroutine of the function argument #1 of `fname`
+fn new_coroutine(state: &mut ExeState, fname: &str) -> Result<Value, LuaError> {
+    if !state.arg(1).is_function() {
+        return Err(arg_error(state, 1, fname, "function"));
+    }
+    Ok(state.new_coroutine(state.arg(1).clone()))
+}
+
+// the coroutine as argument #1 of `fname`
+fn coroutine_arg(state: &ExeState, fname: &str) -> Result<Rc<RefCell<Coroutine>>, LuaError> {
+    match state.arg(1) {
+        Value::Thread(co) => Ok(co.clone()),
+        _ => Err(arg_error(state, 1, fname, "coroutine")),
+    }
+}
+
+// argument `i` of `fname` not of the `expected` type
+fn arg_error(state: &ExeState, i: usize, fname: &str, expected: &str) -> LuaError {
+    let got = match i <= state.arg_count() {
+        true => state.arg(i).type_name(),
+        false => "no value",
+    };
+    format!("bad argument #{} to '{}' ({} expected, got {})", i, fname, expected, got).into()
+}
+
+/// An error raised while running Lua code.
+#[derive(Debug, Clone)]
+pub struct LuaError {
+    /// The error object. Runtime errors are strings starting with the
+    /// position, like `chunkname:line: attempt to index a nil value`.
+    pub value: Value,
+
+    // the message is about the Lua code running the failed instruction,
+    // whose position is still to be prefixed
+    locate: bool,
+
+    // not an error but a coroutine yielding this many values, on the top
+    // of the stack, back to `resume()`
+    yielding: Option<usize>,
+
+    // already given to the message handler, if any, where it was raised
+    handled: bool,
+}
+
+impl LuaError {
+    /// Raise `value` as it is, like Lua's `error(value, 0)`.
+    pub fn new(value: impl Into<Value>) -> Self {
+        LuaError { value: value.into(), locate: false, yielding: None, handled: false }
+    }
+
+    // the error leaves a Lua function running the instruction at `pc`
+    fn locate(mut self, proto: &Proto, pc: usize) -> Self {
+        if self.locate {
+            let msg = String::from_utf8_lossy((&self.value).into()).into_owned();
+            self.value = format!("{}: {}", position(proto, pc), msg).into();
+            self.locate = false;
+        }
+        self
+    }
+}
+
+/// A message about the running Lua code, to be prefixed with its position.
+/// Rust functions raise these about their callers.
+impl From<String> for LuaError {
+    fn from(msg: String) -> Self {
+        LuaError { value: msg.into(), locate: true, yielding: None, handled: false }
+    }
+}
+
+impl fmt::Display for LuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.value.is_string() {
+            write!(f, "{}", String::from_utf8_lossy((&self.value).into()))
+        } else {
+            write!(f, "(error object is a {} value)", self.value.type_name())
+        }
+    }
+}
+
+impl Error for LuaError {}
+
+// like Lua's LUAI_MAXSTACK, LUAI_MAXCCALLS and MAXTAGLOOP
+const MAX_STACK: usize = 1_000_000;
+const MAX_RUST_CALLS: usize = 200;
+const MAX_TAG_LOOP: usize = 2000; // of `__index`, `__newindex` and `__call` chains
+
+/// The rest of a Rust function after a yield, called with the context
+/// given to `ExeState::yield_k()`. It returns like the function.
+pub type Continuation = fn(&mut ExeState, usize) -> Result<usize, LuaError>;
+
+/// What a coroutine resumed by `ExeState::resume_coroutine()` gave back.
+#[derive(Debug)]
+pub enum Resumed {
+    Yield(Vec<Value>),
+    Return(Vec<Value>),
+}
+
+// A function being called. Lua functions calling each other are run by
+// the same `run()` loop, while a call from Rust starts a nested one.
+struct CallInfo {
+    func: usize, // on the stack, followed by its arguments and registers
+    proto: Option<Rc<Proto>>, // `None` for Rust functions
+    pc: usize, // of the call made by a Lua function, if any
+    nresults: Option<usize>, // wanted by the caller, `None` for all
+    cont: Option<(Continuation, usize)>, // of a yielded Rust function, and its context
+    protected: Option<Option<Value>>, // of a `pcall()` yielded in its call: the message handler there
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Suspended,
+    Running,
+    Normal, // resuming another coroutine
+    Dead,
+}
+
+/// A coroutine, with a stack and call frames of its own. They are swapped
+/// with those of the `ExeState` while it runs.
+pub struct Coroutine {
+    status: Status,
+    stack: Vec<Value>,
+    frames: Vec<CallInfo>,
+    tbc: Vec<usize>,
+    base: usize,
+    pc: usize,
+    started: bool,
+    error: Option<Value>, // it died of, to be passed to `__close` metamethods
+}
+
+impl Coroutine {
+    fn new(body: Value) -> Self {
+        Coroutine {
+            status: Status::Suspended,
+            stack: vec![body],
+            frames: Vec::new(),
+            tbc: Vec::new(),
+            base: 0,
+            pc: 0,
+            started: false,
+            error: None,
+        }
+    }
+
+    // the values it refers to, for the collector
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Value> {
+        let handlers = self.frames.iter().filter_map(|ci| ci.protected.as_ref()?.as_ref());
+        self.stack.iter().chain(&self.error).chain(handlers)
+    }
+
+    // unreachable, it is left dead and without values
+    pub(crate) fn clear(&mut self) {
+        self.status = Status::Dead;
+        self.stack.clear();
+        self.frames.clear();
+        self.tbc.clear();
+        self.error = None;
+    }
 }
 
 pub struct ExeState {
     globals: HashMap<String, Value>,
     stack: Vec<Value>,
-    func_index: usize
+    frames: Vec<CallInfo>,
+    tbc: Vec<usize>, // stack indexes of the variables to be closed
+    base: usize, // first register or argument of the running function
+    pc: usize, // of the running Lua function, saved in its frame on calls
+    rust_calls: usize, // nested calls from Rust functions
+    current: Option<Rc<RefCell<Coroutine>>>, // running, `None` for the main one
+    yield_level: Option<usize>, // of `rust_calls` where the current one may yield
+    handler: Option<Value>, // message handler of the running `xpcall()`
+    heap: Heap,
+}
+
+impl Default for ExeState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ExeState {
     pub fn new() -> Self {
         let mut globals = HashMap::new();
-        globals.insert("print".to_string(), Value::Function(rs_print));
-        globals.insert("dbg_print".to_string(), Value::Function(rs_dbg_print));
+        globals.insert("print".to_string(), Value::RustFunction(rs_print));
+        globals.insert("dbg_print".to_string(), Value::RustFunction(rs_dbg_print));
+        globals.insert("error".to_string(), Value::RustFunction(lib_error));
+        globals.insert("pcall".to_string(), Value::RustFunction(lib_pcall));
+        globals.insert("xpcall".to_string(), Value::RustFunction(lib_xpcall));
+        globals.insert("setmetatable".to_string(), Value::RustFunction(lib_setmetatable));
+        globals.insert("getmetatable".to_string(), Value::RustFunction(lib_getmetatable));
+        globals.insert("rawget".to_string(), Value::RustFunction(lib_rawget));
+        globals.insert("rawset".to_string(), Value::RustFunction(lib_rawset));
+        globals.insert("collectgarbage".to_string(), Value::RustFunction(lib_collectgarbage));
 
-        Self {
+        let mut state = Self {
             globals,
             stack: Vec::new(),
-            func_index: 0
+            frames: Vec::new(),
+            tbc: Vec::new(),
+            base: 0,
+            pc: 0,
+            rust_calls: 0,
+            current: None,
+            yield_level: None,
+            handler: None,
+            heap: Heap::new(),
+        };
+
+        let coroutine = state.new_table(0, 7);
+        if let Value::Table(t) = &coroutine {
+            for (name, f) in [("create", lib_cocreate as RustFunction), ("resume", lib_coresume),
+                ("yield", lib_yield), ("wrap", lib_cowrap), ("status", lib_costatus),
+                ("isyieldable", lib_yieldable), ("close", lib_coclose)] {
+                t.borrow_mut().map.insert(name.into(), Value::RustFunction(f));
+            }
+        }
+        state.globals.insert("coroutine".to_string(), coroutine);
+        state
+    }
+
+    /// Run a compiled chunk, and return the values it returns.
+    pub fn execute(&mut self, proto: &Rc<Proto>) -> Result<Vec<Value>, LuaError> {
+        let func = self.stack.len();
+        self.stack.push(Value::LuaFunction(proto.clone()));
+        let result = match self.call(func) {
+            Ok(_) => Ok(self.stack.drain(func ..).collect()),
+            Err(e) => Err(self.close_tbc(func, Some(e)).unwrap_err()),
+        };
+        self.stack.truncate(func);
+        result
+    }
+
+    /// The value of the global variable `name`, nil if not set.
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Set the global variable `name`, to register a Rust function for
+    /// example.
+    pub fn set_global(&mut self, name: &str, value: impl Into<Value>) {
+        self.globals.insert(name.to_string(), value.into());
+    }
+
+    /// A coroutine to run the function `f`, as `coroutine.create()` makes.
+    pub fn new_coroutine(&mut self, f: Value) -> Value {
+        self.track(Value::Thread(Rc::new(RefCell::new(Coroutine::new(f)))))
+    }
+
+    /// A new table with room for `narray` items and `nmap` other fields.
+    /// All tables are made here, to be freed by the collector once
+    /// unreachable, even in reference cycles.
+    pub fn new_table(&mut self, narray: usize, nmap: usize) -> Value {
+        self.track(Value::Table(Rc::new(RefCell::new(Table::new(narray, nmap)))))
+    }
+
+    /// Free the unreachable tables, coroutines and Rust closures, even in
+    /// reference cycles, and return how many. The roots are the stack, the
+    /// globals and the values held by Rust code. This is also done on its
+    /// own while they are made.
+    pub fn collect_garbage(&mut self) -> usize {
+        let current = self.current.clone().map(Value::Thread);
+        self.heap.collect(self.stack.iter().chain(self.globals.values()).chain(&current))
+    }
+
+    // a new table, coroutine or Rust closure, collected once unreachable
+    fn track(&mut self, value: Value) -> Value {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.track(&value);
+        value
+    }
+
+    /// Resume the coroutine `co` with `args`, as `coroutine.resume()` does,
+    /// and return the values it yields or returns. It is dead after an
+    /// error, which is returned.
+    pub fn resume_coroutine(&mut self, co: &Value, args: Vec<Value>) -> Result<Resumed, LuaError> {
+        let Value::Thread(co) = co else {
+            return Err(LuaError::new(format!("cannot resume a {} value", co.type_name())));
+        };
+        let first = self.stack.len();
+        self.stack.extend(args);
+        let result = self.resume(co, first);
+        let values = self.stack.drain(first ..).collect();
+        result?;
+        match co.borrow().status {
+            Status::Dead => Ok(Resumed::Return(values)),
+            _ => Ok(Resumed::Yield(values)),
+        }
+    }
+
+    /// The number of arguments of the running Rust function. Pushed results
+    /// count as well.
+    pub fn arg_count(&self) -> usize {
+        self.stack.len() - self.base
+    }
+
+    /// Argument `i` of the running Rust function, counting from 1. Missing
+    /// arguments are nil.
+    pub fn arg(&self, i: usize) -> &Value {
+        const NIL: &Value = &Value::Nil;
+        match i.checked_sub(1) {
+            Some(i) => self.stack.get(self.base + i).unwrap_or(NIL),
+            None => NIL,
+        }
+    }
+
+    /// Upvalue `i` of the running Rust closure, counting from 1. None if
+    /// there is no such upvalue, or the running function is no closure.
+    pub fn upvalue(&self, i: usize) -> Option<&Value> {
+        match self.stack.get(self.base.checked_sub(1)?)? {
+            Value::RustClosure(c) => c.upvalues.get(i.checked_sub(1)?),
+            _ => None,
+        }
+    }
+
+    /// Push a result of the running Rust function, which returns the number
+    /// of results it pushed. They are adjusted to the number the caller
+    /// wants.
+    pub fn push(&mut self, value: impl Into<Value>) {
+        self.stack.push(value.into());
+    }
+
+    /// Yield the top `n` values of the running Rust function out of the
+    /// running coroutine, like `lua_yield()`, by returning this. The values
+    /// the coroutine is resumed with are the results of the function.
+    pub fn yield_(&mut self, n: usize) -> Result<usize, LuaError> {
+        self.check_yieldable()?;
+        Err(LuaError { yielding: Some(n), ..LuaError::new(Value::Nil) })
+    }
+
+    /// Yield like `yield_()`, but go on by calling `k` with `ctx` when the
+    /// coroutine is resumed, like `lua_yieldk()`. The values it is resumed
+    /// with are then in place of the `n` values, above the others of the
+    /// function, and the results are those of `k`.
+    pub fn yield_k(&mut self, n: usize, ctx: usize, k: Continuation) -> Result<usize, LuaError> {
+        self.check_yieldable()?;
+        self.frames.last_mut().unwrap().cont = Some((k, ctx));
+        Err(LuaError { yielding: Some(n), ..LuaError::new(Value::Nil) })
+    }
+
+    // Call the function at `func` with the values above it up to the top
+    // of the stack as arguments. Its results replace them, and the number
+    // of them is returned.
+    fn call(&mut self, func: usize) -> Result<usize, LuaError> {
+        if self.rust_calls >= MAX_RUST_CALLS {
+            return Err(LuaError::new("stack overflow"));
+        }
+        self.rust_calls += 1;
+        // a Lua function calling a metamethod goes on at its instruction
+        let (base, pc) = (self.base, self.pc);
+        if let Some(ci) = self.frames.last_mut() && ci.proto.is_some() {
+            ci.pc = pc;
+        }
+        let result = match self.callable(func) {
+            Ok(Value::RustFunction(f)) => self.call_rust(func, f),
+            Ok(Value::RustClosure(c)) => self.call_rust(func, c.f),
+            Ok(Value::LuaFunction(proto)) => {
+                let entry = self.frames.len();
+                self.push_lua_frame(func, proto, None)
+                    .map_err(LuaError::from)
+                    .and_then(|_| self.run(entry))
+                    .map(|_| self.stack.len() - func)
+            }
+            Ok(_) => unreachable!(),
+            Err(e) => Err(e.into()),
+        };
+        (self.base, self.pc) = (base, pc);
+        self.rust_calls -= 1;
+
+        // the position is added by the `run()` of a Lua caller, and there
+        // is none for Rust code
+        if self.frames.last().is_some_and(|ci| ci.proto.is_some()) {
+            return result;
+        }
+        result.map_err(|e| LuaError { locate: false, ..e })
+    }
+
+    fn call_rust(&mut self, func: usize, f: RustFunction) -> Result<usize, LuaError> {
+        self.frames.push(CallInfo { func, proto: None, pc: 0, nresults: None, cont: None, protected: None });
+        let base = self.base;
+        self.base = func + 1;
+        let result = f(self);
+        self.base = base;
+        // a yield keeps the frame, to be finished on resume
+        if matches!(result, Err(LuaError { yielding: Some(_), .. })) {
+            return result;
+        }
+        self.frames.pop();
+        Ok(self.rust_results(func, result?))
+    }
+
+    // Leave the `n` results of the Rust function at `func` in its place,
+    // and return how many. They are at the top, but not below the
+    // arguments.
+    fn rust_results(&mut self, func: usize, n: usize) -> usize {
+        let n = n.min(self.stack.len() - (func + 1));
+        self.stack.drain(func .. self.stack.len() - n);
+        n
+    }
+
+    // The function to call at `func`. Other values are called by their
+    // `__call` metamethods, which get them as first arguments.
+    fn callable(&mut self, func: usize) -> Result<Value, String> {
+        for _ in 0 .. MAX_TAG_LOOP {
+            let v = &self.stack[func];
+            if v.is_function() {
+                return Ok(v.clone());
+            }
+            match metamethod(v, "__call") {
+                Value::Nil => return Err(format!("attempt to call a {} value", v.type_name())),
+                handler => self.stack.insert(func, handler),
+            }
+        }
+        Err("'__call' chain too long; possible loop".to_string())
+    }
+
+    // enter the Lua function at `func`, whose arguments are up to the top
+    fn push_lua_frame(&mut self, func: usize, proto: Rc<Proto>, nresults: Option<usize>) -> Result<(), String> {
+        let top = func + 1 + proto.max_stack_size;
+        if top > MAX_STACK {
+            return Err("stack overflow".to_string());
+        }
+        // the missing arguments are nil, and the extra ones are dropped
+        self.stack.resize(top, Value::Nil);
+        self.frames.push(CallInfo { func, proto: Some(proto), pc: 0, nresults, cont: None, protected: None });
+        self.base = func + 1;
+        self.pc = 0;
+        Ok(())
+    }
+
+    // Call like `call()`, but on errors the stack is cut back at `func`
+    // and the error returned to be handled. On success `true` is inserted
+    // before the results. The call may yield if the Rust function at the
+    // top may, which is then finished by `finish_pcall()` on resume.
+    fn protected_call(&mut self, func: usize, handler: Option<Value>) -> Result<usize, LuaError> {
+        let handler = mem::replace(&mut self.handler, handler);
+        let yield_level = self.yield_level;
+        if yield_level == Some(self.rust_calls) {
+            self.yield_level = Some(self.rust_calls + 1);
+        }
+        let ipcall = self.frames.len() - 1;
+        let result = match self.call(func) {
+            Ok(n) => {
+                self.stack.insert(func, Value::Boolean(true));
+                Ok(n)
+            }
+            Err(e) if e.yielding.is_some() => {
+                let ci = &mut self.frames[ipcall];
+                ci.cont = Some((finish_pcall, 0));
+                ci.protected = Some(self.handler.clone());
+                Err(e)
+            }
+            Err(e) => {
+                // errors of Rust functions are raised here, and the variables
+                // to be closed go with the error
+                let e = self.handle(e);
+                let e = self.close_tbc(func, Some(e)).unwrap_err();
+                self.stack.truncate(func);
+                self.stack.push(Value::Boolean(false));
+                Err(e)
+            }
+        };
+        self.yield_level = yield_level;
+        self.handler = handler;
+        result
+    }
+
+    // Give the error to the message handler of `xpcall()`, if any, where
+    // it is raised, and return the error with the value it makes.
+    fn handle(&mut self, mut e: LuaError) -> LuaError {
+        if mem::replace(&mut e.handled, true) {
+            return e;
+        }
+        // errors of the handler itself are not handled
+        let Some(handler) = self.handler.take() else {
+            return e;
+        };
+        let hfunc = self.stack.len();
+        self.stack.push(handler.clone());
+        self.stack.push(e.value);
+        e.value = match self.call(hfunc) {
+            Ok(0) => Value::Nil,
+            Ok(_) => self.stack[hfunc].clone(),
+            Err(_) => Value::from("error in error handling"),
+        };
+        self.stack.truncate(hfunc);
+        self.handler = Some(handler);
+        e
+    }
+
+    // Call the `__close` metamethods of the variables to be closed from the
+    // stack index `level` up, the last one first. The error being raised,
+    // if any, is passed to them, and replaced by theirs.
+    fn close_tbc(&mut self, level: usize, mut error: Option<LuaError>) -> Result<(), LuaError> {
+        while let Some(&i) = self.tbc.last() && i >= level {
+            self.tbc.pop();
+            let v = self.stack[i].clone();
+            let e = error.as_ref().map_or(Value::Nil, |e| e.value.clone());
+            if let Err(e) = self.call_meta(metamethod(&v, "__close"), &[v, e]) {
+                error = Some(e);
+            }
+        }
+        error.map_or(Ok(()), Err)
+    }
+
+    // Run `co` with the values from `first` up to the top, as arguments of
+    // its body or results of its yield, until it returns, yields or fails.
+    // The values are replaced by those it returns or yields.
+    fn resume(&mut self, co: &Rc<RefCell<Coroutine>>, first: usize) -> Result<usize, LuaError> {
+        match co.borrow().status {
+            Status::Suspended => (),
+            Status::Dead => return Err(LuaError::new("cannot resume dead coroutine")),
+            _ => return Err(LuaError::new("cannot resume non-suspended coroutine")),
+        }
+        if self.rust_calls >= MAX_RUST_CALLS {
+            return Err(LuaError::new("C stack overflow"));
+        }
+        let args: Vec<_> = self.stack.drain(first ..).collect();
+
+        let resumer = self.current.replace(co.clone());
+        if let Some(resumer) = &resumer {
+            resumer.borrow_mut().status = Status::Normal;
+        }
+        co.borrow_mut().status = Status::Running;
+        self.switch(co);
+        // the body is called from here, and may yield unless it calls Rust
+        // functions calling back, other than `pcall()`
+        let yield_level = self.yield_level.replace(self.rust_calls + 1);
+        // the errors in the coroutine are its own
+        let handler = self.handler.take();
+        let result = self.resume_inside(args);
+        self.handler = handler;
+        self.yield_level = yield_level;
+        self.switch(co);
+        if let Some(resumer) = &resumer {
+            resumer.borrow_mut().status = Status::Running;
+        }
+        self.current = resumer;
+
+        let mut co = co.borrow_mut();
+        let n = match result {
+            Ok(()) => {
+                co.status = Status::Dead;
+                co.stack.len()
+            }
+            Err(LuaError { yielding: Some(n), .. }) => {
+                co.status = Status::Suspended;
+                n
+            }
+            Err(e) => {
+                co.status = Status::Dead;
+                co.error = Some(e.value.clone());
+                return Err(e);
+            }
+        };
+        let values = co.stack.len() - n;
+        self.stack.extend(co.stack.drain(values ..));
+        Ok(n)
+    }
+
+    // The part of `resume()` in the coroutine. The values it returns are
+    // left as its whole stack.
+    fn resume_inside(&mut self, args: Vec<Value>) -> Result<(), LuaError> {
+        let n = args.len();
+        self.stack.extend(args);
+        if !mem::replace(&mut self.current.as_ref().unwrap().borrow_mut().started, true) {
+            return self.call(0).map(|_| ());
         }
+
+        self.rust_calls += 1;
+        let result = self.finish_yield(n);
+        self.rust_calls -= 1;
+        result
     }
 
-    pub fn execute<R: Read>(&mut self, proto: &ParseProto<R>) {
-        for bytecode in &proto.bytecodes {
-            match *bytecode {
+    // Go on after the yield of the Rust function at the top, with the `n`
+    // values at the top as its results or for its continuation. The calls
+    // of the `pcall()`s below it are finished as they return or fail.
+    fn finish_yield(&mut self, n: usize) -> Result<(), LuaError> {
+        self.handler = self.pcall_handler();
+        let mut result = self.finish_rust(n);
+        loop {
+            match result {
+                Ok(()) if self.frames.is_empty() => return Ok(()),
+                // a `pcall()` whose call returned
+                Ok(()) => {
+                    let ci = self.frames.last_mut().unwrap();
+                    ci.protected = None;
+                    let func = ci.func + 1;
+                    self.handler = self.pcall_handler();
+                    self.stack.insert(func, Value::Boolean(true));
+                    result = self.finish_rust(0);
+                }
+                Err(e) if e.yielding.is_some() => return Err(e),
+                Err(e) => {
+                    let Some(i) = self.frames.iter().rposition(|ci| ci.protected.is_some()) else {
+                        return Err(e);
+                    };
+                    // like `protected_call()`, with the frames above it gone
+                    let e = self.handle(e);
+                    self.frames.truncate(i + 1);
+                    self.frames[i].protected = None;
+                    self.handler = self.pcall_handler();
+                    let func = self.frames[i].func + 1;
+                    let e = self.close_tbc(func, Some(e)).unwrap_err();
+                    self.stack.truncate(func);
+                    self.stack.push(Value::Boolean(false));
+                    self.stack.push(e.value);
+                    result = self.finish_rust(0);
+                }
+            }
+        }
+    }
+
+    // the message handler in the call of the innermost yielded `pcall()`
+    fn pcall_handler(&self) -> Option<Value> {
+        self.frames.iter().rev().find_map(|ci| ci.protected.clone()).flatten()
+    }
+
+    // Finish the Rust function at the top, with the `n` values at the top
+    // as its results or for its continuation, and run its Lua caller, if
+    // any, until it returns.
+    fn finish_rust(&mut self, n: usize) -> Result<(), LuaError> {
+        let ci = self.frames.last_mut().unwrap();
+        let func = ci.func;
+        let n = match ci.cont.take() {
+            Some((k, ctx)) => {
+                self.base = func + 1;
+                // yielding again keeps the frame
+                match k(self, ctx) {
+                    Err(e) if e.yielding.is_some() => return Err(e),
+                    Err(e) => {
+                        self.frames.pop();
+                        // at the call of the Lua caller, if any
+                        return Err(match self.frames.last().and_then(|ci| ci.proto.as_ref().map(|p| (p, ci.pc))) {
+                            Some((proto, pc)) => e.locate(proto, pc),
+                            None => LuaError { locate: false, ..e },
+                        });
+                    }
+                    Ok(n) => n,
+                }
+            }
+            None => n,
+        };
+        let n = self.rust_results(func, n);
+        self.frames.pop();
+        // the body itself, or a `pcall()` calling it, returns them now
+        let Some(ci) = self.frames.last().filter(|ci| ci.proto.is_some()) else {
+            return Ok(());
+        };
+
+        // back to the Lua function after its call
+        let proto = ci.proto.clone().unwrap();
+        let Bytecode::Call(_, _, nresults) = proto.bytecodes[ci.pc] else {
+            unreachable!("yield out of a call");
+        };
+        self.base = ci.func + 1;
+        self.pc = ci.pc + 1;
+        if nresults != MULTRET {
+            self.adjust_results(func, n, nresults as usize);
+            self.stack.resize(self.base + proto.max_stack_size, Value::Nil);
+        }
+        // up to the `pcall()` below, if any
+        let entry = self.frames.iter().rposition(|ci| ci.proto.is_none()).map_or(0, |i| i + 1);
+        self.run(entry)
+    }
+
+    // Run the variables to be closed of `co`, suspended or dead, which is
+    // then dead.
+    fn close_coroutine(&mut self, co: &Rc<RefCell<Coroutine>>) -> Result<(), LuaError> {
+        let error = {
+            let mut co = co.borrow_mut();
+            co.status = Status::Dead;
+            co.error.take().map(LuaError::new)
+        };
+        self.switch(co);
+        // no yields from the metamethods
+        let yield_level = self.yield_level.replace(self.rust_calls);
+        let result = self.close_tbc(0, error);
+        self.yield_level = yield_level;
+        self.stack.clear();
+        self.frames.clear();
+        self.switch(co);
+        result
+    }
+
+    // exchange the stack and frames with those of `co`
+    fn switch(&mut self, co: &RefCell<Coroutine>) {
+        let co = &mut *co.borrow_mut();
+        mem::swap(&mut self.stack, &mut co.stack);
+        mem::swap(&mut self.frames, &mut co.frames);
+        mem::swap(&mut self.tbc, &mut co.tbc);
+        mem::swap(&mut self.base, &mut co.base);
+        mem::swap(&mut self.pc, &mut co.pc);
+    }
+
+    fn check_yieldable(&self) -> Result<(), String> {
+        match self.yield_level {
+            None => Err("attempt to yield from outside a coroutine".to_string()),
+            Some(level) if level != self.rust_calls => Err("attempt to yield across a C-call boundary".to_string()),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // The position of the function `level` calls up from the running one,
+    // as a prefix of messages, like `luaL_where()`. Empty for Rust functions.
+    fn where_(&self, level: usize) -> String {
+        let Some(ci) = self.frames.len().checked_sub(level + 1).map(|i| &self.frames[i]) else {
+            return String::new();
+        };
+        match &ci.proto {
+            Some(proto) => format!("{}: ", position(proto, ci.pc)),
+            None => String::new(),
+        }
+    }
+
+    // Run the Lua function of the frame at `entry`, the top one, until it
+    // returns. On errors the frames from `entry` on are dropped, but kept on
+    // yields to be resumed.
+    fn run(&mut self, entry: usize) -> Result<(), LuaError> {
+        self.dispatch(entry).map_err(|e| {
+            if e.yielding.is_some() {
+                return e;
+            }
+            // the failed instruction is in the function at the top
+            let proto = self.frames.last().unwrap().proto.as_ref().unwrap();
+            let e = e.locate(proto, self.pc);
+            let e = self.handle(e);
+            self.frames.truncate(entry);
+            e
+        })
+    }
+
+    // Lua functions calling Lua functions enter their frames here, and
+    // leave them on return. On errors, `self.pc` is left at the failed
+    // instruction of the function at the top.
+    fn dispatch(&mut self, entry: usize) -> Result<(), LuaError> {
+        let mut proto = self.frames.last().unwrap().proto.clone().unwrap();
+        loop {
+            match proto.bytecodes[self.pc] {
                 Bytecode::GetGlobal(stack_dst, const_idx) => {
                     let key: &str = (&proto.constants[const_idx as usize]).into();
                     let global_value = self.globals.get(key).unwrap_or(&Value::default()).clone();
@@ -40,7 +990,7 @@ impl ExeState {
                 }
                 Bytecode::SetGlobal(ident_idx, src) => {
                     let key = &proto.constants[ident_idx as usize];
-                    let value = self.stack[src as usize].clone();
+                    let value = self.stack[self.base + src as usize].clone();
                     self.globals.insert(key.into(), value);
                 }
                 Bytecode::SetGlobalConst(ident_idx, src) => {
@@ -48,10 +998,27 @@ impl ExeState {
                     let value = proto.constants[src as usize].clone();
                     self.globals.insert(key.into(), value);
                 }
+                Bytecode::GetGlobalX(stack_dst) => {
+                    self.pc += 1;
+                    let key: &str = (&proto.constants[proto.bytecodes[self.pc].extra_arg_value()]).into();
+                    let global_value = self.globals.get(key).unwrap_or(&Value::default()).clone();
+                    self.set_stack(stack_dst, global_value);
+                }
+                Bytecode::SetGlobalX(src) => {
+                    self.pc += 1;
+                    let key = &proto.constants[proto.bytecodes[self.pc].extra_arg_value()];
+                    let value = self.stack[self.base + src as usize].clone();
+                    self.globals.insert(key.into(), value);
+                }
                 Bytecode::LoadConst(stack_dst, const_idx) => {
                     let const_value = proto.constants[const_idx as usize].clone();
                     self.set_stack(stack_dst, const_value);
                 }
+                Bytecode::LoadConstX(stack_dst) => {
+                    self.pc += 1;
+                    let const_value = proto.constants[proto.bytecodes[self.pc].extra_arg_value()].clone();
+                    self.set_stack(stack_dst, const_value);
+                }
                 Bytecode::LoadNil(dst, n) => {
                     self.fill_stack(dst as usize, n as usize);
                 }
@@ -62,171 +1029,531 @@ impl ExeState {
                     self.set_stack(dst, Value::Integer(i as i64));
                 }
                 Bytecode::Move(dst, src) => {
-                    let v = self.stack[src as usize].clone();
+                    let v = self.stack[self.base + src as usize].clone();
                     self.set_stack(dst, v);
                 }
-                Bytecode::Call(func, _) => {
-                    self.func_index = func as usize;
-                    let func = &self.stack[self.func_index];
-                    match func {
-                        Value::Function(f) => {
-                            f(self);
-                        },
-                        unknown => panic!("Expected function: {:?}", unknown)
+                Bytecode::Call(func, nargs, nresults) => {
+                    // the arguments are the top of the stack during the call,
+                    // the registers above them are free
+                    let func = self.base + func as usize;
+                    if nargs != MULTRET {
+                        self.stack.truncate(func + 1 + nargs as usize);
+                    }
+                    let nresults = (nresults != MULTRET).then_some(nresults as usize);
+                    self.frames.last_mut().unwrap().pc = self.pc;
+                    match self.callable(func)? {
+                        Value::LuaFunction(p) => {
+                            self.push_lua_frame(func, p.clone(), nresults)?;
+                            proto = p;
+                            continue;
+                        }
+                        Value::RustFunction(f) => self.call_rust_from_lua(func, f, nresults, &proto)?,
+                        Value::RustClosure(c) => self.call_rust_from_lua(func, c.f, nresults, &proto)?,
+                        _ => unreachable!(),
                     }
                 }
+                Bytecode::Return(first, n) => {
+                    if self.tbc.last().is_some_and(|&i| i >= self.base) {
+                        self.close_tbc(self.base, None)?;
+                    }
+                    let ci = self.frames.pop().unwrap();
+                    let first = self.base + first as usize;
+                    let n = if n == MULTRET { self.stack.len() - first } else { n as usize };
+                    for i in 0 .. n {
+                        self.stack[ci.func + i] = mem::take(&mut self.stack[first + i]);
+                    }
+                    self.adjust_results(ci.func, n, ci.nresults.unwrap_or(n));
+                    if self.frames.len() == entry {
+                        return Ok(());
+                    }
+
+                    // back to the calling Lua function, after its call
+                    let caller = self.frames.last().unwrap();
+                    proto = caller.proto.clone().unwrap();
+                    self.pc = caller.pc;
+                    self.base = caller.func + 1;
+                    if ci.nresults.is_some() {
+                        self.stack.resize(self.base + proto.max_stack_size, Value::Nil);
+                    }
+                }
+                Bytecode::Closure(dst, i) => {
+                    let f = Value::LuaFunction(proto.protos[i as usize].clone());
+                    self.set_stack(dst, f);
+                }
+                Bytecode::Tbc(r) => {
+                    // nil and false are not closed
+                    let v = &self.stack[self.base + r as usize];
+                    if !v.is_falsy() {
+                        if metamethod(v, "__close") == Value::Nil {
+                            let name = proto.local_name(r as usize, self.pc).unwrap_or("?");
+                            return Err(format!("variable '{}' got a non-closable value", name).into());
+                        }
+                        self.tbc.push(self.base + r as usize);
+                    }
+                }
+                Bytecode::Close(r) => self.close_tbc(self.base + r as usize, None)?,
                 Bytecode::NewTable(dst, narray, nmap) => {
-                    let table = Table::new(narray as usize, nmap as usize);
-                    self.set_stack(dst, Value::Table(Rc::new(RefCell::new(table))));
+                    let table = self.new_table(narray as usize, nmap as usize);
+                    self.set_stack(dst, table);
                 }
                 Bytecode::SetInt(t, i, v) => {
-                    let value = self.stack[v as usize].clone();
-                    self.set_table_int(t, i as i64, value);
+                    let value = self.stack[self.base + v as usize].clone();
+                    self.set_table_int(t, i as i64, value)?;
                 }
                 Bytecode::GetInt(dst, t, k) => {
-                    let value = self.get_table_int(t, k as i64);
+                    let value = self.get_table_int(t, k as i64)?;
                     self.set_stack(dst, value);
                 }
                 Bytecode::SetIntConst(t, i, v) => {
                     let value = proto.constants[v as usize].clone();
-                    self.set_table_int(t, i as i64, value);
+                    self.set_table_int(t, i as i64, value)?;
                 }
                 Bytecode::SetField(t, k, v) => {
                     let key = proto.constants[k as usize].clone();
-                    let value = self.stack[v as usize].clone();
-                    self.set_table(t, key, value);
+                    let value = self.stack[self.base + v as usize].clone();
+                    self.set_table(t, key, value)?;
                 }
                 Bytecode::SetFieldConst(t, k, v) => {
                     let key = proto.constants[k as usize].clone();
                     let value = proto.constants[v as usize].clone();
-                    self.set_table(t, key, value);
+                    self.set_table(t, key, value)?;
                 }
                 Bytecode::SetTable(t, k, v) => {
-                    let key = self.stack[k as usize].clone();
-                    let value = self.stack[v as usize].clone();
-                    self.set_table(t, key, value);
+                    let key = self.stack[self.base + k as usize].clone();
+                    let value = self.stack[self.base + v as usize].clone();
+                    self.set_table(t, key, value)?;
                 }
                 Bytecode::SetTableConst(t, k, v) => {
-                    let key = self.stack[k as usize].clone();
+                    let key = self.stack[self.base + k as usize].clone();
                     let value: Value = proto.constants[v as usize].clone();
-                    self.set_table(t, key, value);
+                    self.set_table(t, key, value)?;
                 }
                 Bytecode::SetList(table, tostore, nelems) => {
-                    let ivalue = table as usize + 1;
-                    if let Value::Table(table) = self.stack[table as usize].clone() {
-                        let array = &mut table.borrow_mut().array;
-
-                        let cur_size = array.len();
-                        let new_size = cur_size + tostore as usize;
-                        array.reserve(new_size);
-
-                        let values = self.stack.drain(ivalue .. ivalue + tostore as usize);
-                        assert_eq!(values.len(), tostore as usize);
-                        for (i, v) in values.enumerate() {
-                            set_vec(array, nelems as usize + i, v);
-                        }
-                    } else {
-                        panic!("not table");
-                    }
+                    self.set_list(table, tostore, nelems as usize)?;
+                    self.stack.resize(self.base + proto.max_stack_size, Value::Nil);
+                }
+                Bytecode::SetListX(table, tostore) => {
+                    self.pc += 1;
+                    self.set_list(table, tostore, proto.bytecodes[self.pc].extra_arg_value())?;
+                    self.stack.resize(self.base + proto.max_stack_size, Value::Nil);
                 }
                 Bytecode::GetField(dst, t, k) => {
                     let key = &proto.constants[k as usize];
-                    let value = self.get_table(t, key);
+                    let value = self.get_table(t, key)?;
                     self.set_stack(dst, value);
                 }
                 Bytecode::GetTable(dst, t, k) => {
-                    let key = &self.stack[k as usize];
-                    let value = self.get_table(t, key);
+                    let key = self.stack[self.base + k as usize].clone();
+                    let value = self.get_table(t, &key)?;
+                    self.set_stack(dst, value);
+                }
+                Bytecode::Add(dst, a, b) => self.arith(ArithOp::Add, dst, a, b)?,
+                Bytecode::Sub(dst, a, b) => self.arith(ArithOp::Sub, dst, a, b)?,
+                Bytecode::Mul(dst, a, b) => self.arith(ArithOp::Mul, dst, a, b)?,
+                Bytecode::Div(dst, a, b) => self.arith(ArithOp::Div, dst, a, b)?,
+                Bytecode::IDiv(dst, a, b) => self.arith(ArithOp::IDiv, dst, a, b)?,
+                Bytecode::Mod(dst, a, b) => self.arith(ArithOp::Mod, dst, a, b)?,
+                Bytecode::Pow(dst, a, b) => self.arith(ArithOp::Pow, dst, a, b)?,
+                Bytecode::BAnd(dst, a, b) => self.arith(ArithOp::BAnd, dst, a, b)?,
+                Bytecode::BOr(dst, a, b) => self.arith(ArithOp::BOr, dst, a, b)?,
+                Bytecode::BXor(dst, a, b) => self.arith(ArithOp::BXor, dst, a, b)?,
+                Bytecode::Shl(dst, a, b) => self.arith(ArithOp::Shl, dst, a, b)?,
+                Bytecode::Shr(dst, a, b) => self.arith(ArithOp::Shr, dst, a, b)?,
+                Bytecode::Concat(dst, a, b) => {
+                    let (a, b) = (&self.stack[self.base + a as usize], &self.stack[self.base + b as usize]);
+                    let value = match arith::concat(a, b) {
+                        Some(value) => value,
+                        None => {
+                            let (a, b) = (a.clone(), b.clone());
+                            self.bin_meta(&a, &b, "__concat")?.ok_or_else(|| {
+                                let bad = if a.is_string() || a.to_number().is_some() { b } else { a };
+                                format!("attempt to concatenate a {} value", bad.type_name())
+                            })?
+                        }
+                    };
+                    self.set_stack(dst, value);
+                }
+                Bytecode::Eq(dst, a, b) => {
+                    let value = self.equal(a, b)?;
+                    self.set_stack(dst, Value::Boolean(value));
+                }
+                Bytecode::Ne(dst, a, b) => {
+                    let value = !self.equal(a, b)?;
+                    self.set_stack(dst, Value::Boolean(value));
+                }
+                Bytecode::Lt(dst, a, b) => {
+                    let value = self.compare(arith::less_than, "__lt", a, b)?;
+                    self.set_stack(dst, Value::Boolean(value));
+                }
+                Bytecode::Le(dst, a, b) => {
+                    let value = self.compare(arith::less_equal, "__le", a, b)?;
+                    self.set_stack(dst, Value::Boolean(value));
+                }
+                Bytecode::Unm(dst, src) => {
+                    let v = &self.stack[self.base + src as usize];
+                    let value = match arith::neg(v) {
+                        Some(value) => value,
+                        None => {
+                            let v = v.clone();
+                            self.bin_meta(&v, &v, "__unm")?.ok_or_else(||
+                                format!("attempt to perform arithmetic on a {} value", v.type_name()))?
+                        }
+                    };
                     self.set_stack(dst, value);
                 }
+                Bytecode::BNot(dst, src) => {
+                    let v = &self.stack[self.base + src as usize];
+                    let value = match arith::bnot(v) {
+                        Some(value) => value,
+                        None => {
+                            let v = v.clone();
+                            self.bin_meta(&v, &v, "__bnot")?.ok_or_else(|| bitwise_error(&v, &v))?
+                        }
+                    };
+                    self.set_stack(dst, value);
+                }
+                Bytecode::Not(dst, src) => {
+                    let value = self.stack[self.base + src as usize].is_falsy();
+                    self.set_stack(dst, Value::Boolean(value));
+                }
+                Bytecode::Len(dst, src) => {
+                    let v = &self.stack[self.base + src as usize];
+                    let value = match v {
+                        v if v.is_string() => Value::Integer(<&[u8]>::from(v).len() as i64),
+                        // tables without `__len` have their border
+                        v => match (metamethod(v, "__len"), v) {
+                            (Value::Nil, Value::Table(t)) => Value::Integer(t.borrow().border()),
+                            (Value::Nil, v) => return Err(format!("attempt to get length of a {} value", v.type_name()).into()),
+                            (handler, v) => {
+                                let v = v.clone();
+                                self.call_meta(handler, &[v.clone(), v])?
+                            }
+                        },
+                    };
+                    self.set_stack(dst, value);
+                }
+                Bytecode::Jump(offset) => {
+                    self.pc = self.pc.wrapping_add_signed(offset as isize);
+                }
+                Bytecode::JumpFalse(r, offset) => {
+                    if self.stack[self.base + r as usize].is_falsy() {
+                        self.pc = self.pc.wrapping_add_signed(offset as isize);
+                    }
+                }
+                Bytecode::JumpTrue(r, offset) => {
+                    if !self.stack[self.base + r as usize].is_falsy() {
+                        self.pc = self.pc.wrapping_add_signed(offset as isize);
+                    }
+                }
+                Bytecode::TestEq(a, b, k) => {
+                    if self.equal(a, b)? != k {
+                        self.pc += 1;
+                    }
+                }
+                Bytecode::TestLt(a, b, k) => {
+                    if self.compare(arith::less_than, "__lt", a, b)? != k {
+                        self.pc += 1;
+                    }
+                }
+                Bytecode::TestLe(a, b, k) => {
+                    if self.compare(arith::less_equal, "__le", a, b)? != k {
+                        self.pc += 1;
+                    }
+                }
+                Bytecode::AddI(dst, src, i) => {
+                    let a = &self.stack[self.base + src as usize];
+                    let b = Value::Integer(i as i64);
+                    let value = match arith::arith(ArithOp::Add, a, &b) {
+                        Some(value) => value,
+                        None => self.arith_meta(ArithOp::Add, a.clone(), b)?,
+                    };
+                    self.set_stack(dst, value);
+                }
+                Bytecode::ExtraArg(..) => unreachable!("ExtraArg"),
             }
+            self.pc += 1;
         }
     }
 
-    fn set_stack(&mut self, dst: u8, value: Value) {
-        let dst = dst as usize;
-        match dst.cmp(&self.stack.len()) {
-            std::cmp::Ordering::Less => self.stack[dst] = value,
-            std::cmp::Ordering::Equal => self.stack.push(value),
-            std::cmp::Ordering::Greater => panic!("Invalid stack index: {}", dst)
+    fn call_rust_from_lua(&mut self, func: usize, f: RustFunction, nresults: Option<usize>, proto: &Proto)
+        -> Result<(), LuaError> {
+        let n = self.call_rust(func, f)?;
+        // all the results are left as the top, for the next instruction
+        if let Some(nresults) = nresults {
+            self.adjust_results(func, n, nresults);
+            self.stack.resize(self.base + proto.max_stack_size, Value::Nil);
         }
+        Ok(())
     }
 
-    fn fill_stack(&mut self, begin: usize, num: usize) {
-        let end = begin + num;
-        let len = self.stack.len();
-        if begin < len {
-            self.stack[begin .. len].fill(Value::Nil);
+    // leave `nresults` of the `n` results at `func` as the top of the stack
+    fn adjust_results(&mut self, func: usize, n: usize, nresults: usize) {
+        self.stack.truncate(func + n);
+        self.stack.resize(func + nresults, Value::Nil);
+    }
+
+    fn arith(&mut self, op: ArithOp, dst: u8, a: u8, b: u8) -> Result<(), LuaError> {
+        let (a, b) = (&self.stack[self.base + a as usize], &self.stack[self.base + b as usize]);
+        let value = match arith::arith(op, a, b) {
+            Some(value) => value,
+            None => self.arith_meta(op, a.clone(), b.clone())?,
+        };
+        self.set_stack(dst, value);
+        Ok(())
+    }
+
+    // operands which are not numbers, by their metamethods
+    fn arith_meta(&mut self, op: ArithOp, a: Value, b: Value) -> Result<Value, LuaError> {
+        Ok(self.bin_meta(&a, &b, arith_event(op))?.ok_or_else(|| arith_error(op, &a, &b))?)
+    }
+
+    // Numbers and strings are compared by `f`, other values by the
+    // metamethod `event`.
+    fn compare(&mut self, f: fn(&Value, &Value) -> Option<bool>, event: &str, a: u8, b: u8)
+        -> Result<bool, LuaError> {
+        let (a, b) = (&self.stack[self.base + a as usize], &self.stack[self.base + b as usize]);
+        if let Some(result) = f(a, b) {
+            return Ok(result);
+        }
+        let (a, b) = (a.clone(), b.clone());
+        let result = self.bin_meta(&a, &b, event)?.ok_or_else(|| {
+            let (ta, tb) = (a.type_name(), b.type_name());
+            if ta == tb {
+                format!("attempt to compare two {} values", ta)
+            } else {
+                format!("attempt to compare {} with {}", ta, tb)
+            }
+        })?;
+        Ok(!result.is_falsy())
+    }
+
+    // `__eq` is only tried for two different tables
+    fn equal(&mut self, a: u8, b: u8) -> Result<bool, LuaError> {
+        let (a, b) = (&self.stack[self.base + a as usize], &self.stack[self.base + b as usize]);
+        if arith::equal(a, b) {
+            return Ok(true);
         }
-        if end > len {
-            self.stack.resize(end, Value::Nil);
+        if !matches!((a, b), (Value::Table(_), Value::Table(_))) {
+            return Ok(false);
         }
+        let (a, b) = (a.clone(), b.clone());
+        Ok(self.bin_meta(&a, &b, "__eq")?.is_some_and(|v| !v.is_falsy()))
+    }
+
+    fn set_stack(&mut self, dst: u8, value: Value) {
+        self.stack[self.base + dst as usize] = value;
+    }
+
+    // the values after the table in the registers, or up to the top
+    fn set_list(&mut self, table: u8, tostore: u8, nelems: usize) -> Result<(), String> {
+        let ivalue = self.base + table as usize + 1;
+        let tostore = if tostore == MULTRET { self.stack.len() - ivalue } else { tostore as usize };
+        let values = &self.stack[ivalue .. ivalue + tostore];
+        self.table(table)?.borrow_mut().set_list(nelems, values);
+        Ok(())
+    }
+
+    fn fill_stack(&mut self, begin: usize, num: usize) {
+        let begin = self.base + begin;
+        self.stack[begin .. begin + num].fill(Value::Nil);
     }
 
-    fn set_table(&mut self, t: u8, key: Value, value: Value) {
-        match &key {
-            Value::Integer(i) => self.set_table_int(t, *i, value), // TODO Float
-            _ => self.do_set_table(t, key, value),
+    // the table in register `t`
+    fn table(&self, t: u8) -> Result<&Rc<RefCell<Table>>, String> {
+        match &self.stack[self.base + t as usize] {
+            Value::Table(table) => Ok(table),
+            v => Err(format!("attempt to index a {} value", v.type_name())),
         }
     }
 
-    fn set_table_int(&mut self, t: u8, i: i64, value: Value) {
-        if let Value::Table(table) = &self.stack[t as usize] {
+    // The fast paths of the table accesses below are for keys present in
+    // the table and for tables without metatables. The rest goes by
+    // `index()` and `new_index()`.
+
+    fn set_table(&mut self, t: u8, key: Value, value: Value) -> Result<(), LuaError> {
+        if let Value::Table(table) = &self.stack[self.base + t as usize] {
             let mut table = table.borrow_mut();
-            // this is not same with Lua's official implement
-            if i > 0 && (i < 4 || i < table.array.capacity() as i64 * 2) {
-                set_vec(&mut table.array, i as usize - 1, value);
-            } else {
-                table.map.insert(Value::Integer(i), value);
+            if table.metatable.is_none() || table.get(&key) != &Value::Nil {
+                return Ok(table.set(key, value)?);
             }
-        } else {
-            panic!("invalid table");
         }
+        let t = self.stack[self.base + t as usize].clone();
+        self.new_index(t, key, value)
     }
 
-    fn do_set_table(&mut self, t: u8, key: Value, value: Value) {
-        if let Value::Table(table) = &self.stack[t as usize] {
-            table.borrow_mut().map.insert(key, value);
-        } else {
-            panic!("invalid table");
+    fn set_table_int(&mut self, t: u8, i: i64, value: Value) -> Result<(), LuaError> {
+        if let Value::Table(table) = &self.stack[self.base + t as usize] {
+            let mut table = table.borrow_mut();
+            if table.metatable.is_none() || table.get_int(i) != &Value::Nil {
+                table.set_int(i, value);
+                return Ok(());
+            }
         }
+        let t = self.stack[self.base + t as usize].clone();
+        self.new_index(t, Value::Integer(i), value)
     }
 
-    fn get_table(&self, t: u8, key: &Value) -> Value {
-        match key {
-            Value::Integer(i) => self.get_table_int(t, *i), // TODO Float
-            _ => self.do_get_table(t, key),
+    fn get_table(&mut self, t: u8, key: &Value) -> Result<Value, LuaError> {
+        if let Value::Table(table) = &self.stack[self.base + t as usize] {
+            let table = table.borrow();
+            let value = table.get(key);
+            if table.metatable.is_none() || value != &Value::Nil {
+                return Ok(value.clone());
+            }
         }
+        let t = self.stack[self.base + t as usize].clone();
+        self.index(t, key.clone())
     }
 
-    fn get_table_int(&self, t: u8, i: i64) -> Value {
-        if let Value::Table(table) = &self.stack[t as usize] {
+    fn get_table_int(&mut self, t: u8, i: i64) -> Result<Value, LuaError> {
+        if let Value::Table(table) = &self.stack[self.base + t as usize] {
             let table = table.borrow();
-            table.array.get(i as usize - 1)
-                .unwrap_or_else(|| table.map.get(&Value::Integer(i))
-                    .unwrap_or(&Value::Nil)).clone()
-        } else {
-            panic!("set invalid table");
+            let value = table.get_int(i);
+            if table.metatable.is_none() || value != &Value::Nil {
+                return Ok(value.clone());
+            }
         }
+        let t = self.stack[self.base + t as usize].clone();
+        self.index(t, Value::Integer(i))
     }
 
-    fn do_get_table(&self, t: u8, key: &Value) -> Value {
-        if let Value::Table(table) = &self.stack[t as usize] {
-            let table = table.borrow();
-            table.map.get(key).unwrap_or(&Value::Nil).clone()
-        } else {
-            panic!("set invalid table");
+    // `t[key]` with `__index`, which is followed through tables until a
+    // function is called or a value found
+    fn index(&mut self, mut t: Value, key: Value) -> Result<Value, LuaError> {
+        for _ in 0 .. MAX_TAG_LOOP {
+            let handler = match &t {
+                Value::Table(table) => {
+                    let value = table.borrow().get(&key).clone();
+                    let handler = metamethod(&t, "__index");
+                    if value != Value::Nil || handler == Value::Nil {
+                        return Ok(value);
+                    }
+                    handler
+                }
+                v => match metamethod(v, "__index") {
+                    Value::Nil => return Err(format!("attempt to index a {} value", v.type_name()).into()),
+                    handler => handler,
+                },
+            };
+            if handler.is_function() {
+                return self.call_meta(handler, &[t, key]);
+            }
+            t = handler;
         }
+        Err("'__index' chain too long; possible loop".to_string().into())
     }
-}
 
-fn set_vec(vec: &mut Vec<Value>, i: usize, value: Value) {
-    match i.cmp(&vec.len()) {
-        Ordering::Less => vec[i] = value,
-        Ordering::Equal => vec.push(value),
-        Ordering::Greater => {
-            vec.resize(i, Value::Nil);
-            vec.push(value);
+    // `t[key] = value` with `__newindex`, which only applies to keys absent
+    // from tables
+    fn new_index(&mut self, mut t: Value, key: Value, value: Value) -> Result<(), LuaError> {
+        for _ in 0 .. MAX_TAG_LOOP {
+            let handler = match &t {
+                Value::Table(table) => {
+                    let handler = match table.borrow().get(&key) {
+                        Value::Nil => metamethod(&t, "__newindex"),
+                        _ => Value::Nil,
+                    };
+                    if handler == Value::Nil {
+                        return Ok(table.borrow_mut().set(key, value)?);
+                    }
+                    handler
+                }
+                v => match metamethod(v, "__newindex") {
+                    Value::Nil => return Err(format!("attempt to index a {} value", v.type_name()).into()),
+                    handler => handler,
+                },
+            };
+            if handler.is_function() {
+                self.call_meta(handler, &[t, key, value])?;
+                return Ok(());
+            }
+            t = handler;
         }
+        Err("'__newindex' chain too long; possible loop".to_string().into())
     }
-}
\ No newline at end of file
+
+    // Call the metamethod `event` of the first operand having one, with
+    // both operands, like `luaT_trybinTM()`. `None` if neither has one.
+    fn bin_meta(&mut self, a: &Value, b: &Value, event: &str) -> Result<Option<Value>, LuaError> {
+        let handler = match metamethod(a, event) {
+            Value::Nil => metamethod(b, event),
+            handler => handler,
+        };
+        if handler == Value::Nil {
+            return Ok(None);
+        }
+        self.call_meta(handler, &[a.clone(), b.clone()]).map(Some)
+    }
+
+    // call a metamethod with `args` above the top of the stack, and return
+    // its first result
+    fn call_meta(&mut self, handler: Value, args: &[Value]) -> Result<Value, LuaError> {
+        let func = self.stack.len();
+        self.stack.push(handler);
+        self.stack.extend_from_slice(args);
+        let result = self.call(func).map(|n| match n {
+            0 => Value::Nil,
+            _ => mem::take(&mut self.stack[func]),
+        });
+        self.stack.truncate(func);
+        result
+    }
+}
+
+// the field `event` of the metatable of `v`, nil if none
+fn metamethod(v: &Value, event: &str) -> Value {
+    let Value::Table(t) = v else {
+        return Value::Nil;
+    };
+    match &t.borrow().metatable {
+        Some(mt) => mt.borrow().get(&Value::from(event)).clone(),
+        None => Value::Nil,
+    }
+}
+
+// where the instruction at `pc` comes from, as `chunkname:line`, with
+// "?" and -1 as Lua does for stripped code
+fn position(proto: &Proto, pc: usize) -> String {
+    let source = proto.source.as_deref().unwrap_or("?");
+    match proto.line(pc) {
+        Some(line) => format!("{}:{}", source, line),
+        None => format!("{}:-1", source),
+    }
+}
+
+fn arith_event(op: ArithOp) -> &'static str {
+    match op {
+        ArithOp::Add => "__add",
+        ArithOp::Sub => "__sub",
+        ArithOp::Mul => "__mul",
+        ArithOp::Div => "__div",
+        ArithOp::IDiv => "__idiv",
+        ArithOp::Mod => "__mod",
+        ArithOp::Pow => "__pow",
+        ArithOp::BAnd => "__band",
+        ArithOp::BOr => "__bor",
+        ArithOp::BXor => "__bxor",
+        ArithOp::Shl => "__shl",
+        ArithOp::Shr => "__shr",
+    }
+}
+
+fn arith_error(op: ArithOp, a: &Value, b: &Value) -> String {
+    if op.is_bitwise() {
+        return bitwise_error(a, b);
+    }
+    match (a.to_number(), b.to_number()) {
+        (None, _) => format!("attempt to perform arithmetic on a {} value", a.type_name()),
+        (_, None) => format!("attempt to perform arithmetic on a {} value", b.type_name()),
+        _ if op == ArithOp::IDiv => "attempt to perform 'n//0'".to_string(),
+        _ => "attempt to perform 'n%0'".to_string(),
+    }
+}
+
+fn bitwise_error(a: &Value, b: &Value) -> String {
+    match (a.to_number(), b.to_number()) {
+        (None, _) => format!("attempt to perform bitwise operation on a {} value", a.type_name()),
+        (_, None) => format!("attempt to perform bitwise operation on a {} value", b.type_name()),
+        _ => "number has no integer representation".to_string(),
+    }
+}
@@ -0,0 +1,63 @@
+use rlua::{ast::{self, BinOp, Block, Exp, ExpKind, StatKind, Visitor}, lexer::Lexer};
+
+fn parse(src: &str) -> Result<Block, String> {
+    ast::parse_ast(Lexer::new(src.as_bytes(), "test")).map_err(|e| e.to_string())
+}
+
+#[test]
+fn statements_and_spans() {
+    let src = "local x <const> = 1 + 2 * 3\nwhile x do x = f(x) end";
+    let block = parse(src).unwrap();
+    assert_eq!(block.stats.len(), 2);
+
+    let StatKind::Local(names, exps) = &block.stats[0].kind else {
+        panic!("not a local statement: {:?}", block.stats[0]);
+    };
+    assert_eq!(names[0].name.name, "x");
+    assert_eq!(names[0].attrib.as_ref().unwrap().name, "const");
+    // priorities
+    let ExpKind::BinOp(BinOp::Add, _, right) = &exps[0].kind else {
+        panic!("not an addition: {:?}", exps[0]);
+    };
+    assert!(matches!(right.kind, ExpKind::BinOp(BinOp::Mul, ..)));
+    assert_eq!(&src[exps[0].span.start .. exps[0].span.end], "1 + 2 * 3");
+
+    let stat = &block.stats[1];
+    assert!(matches!(stat.kind, StatKind::While(..)));
+    assert_eq!(stat.span.line, 2);
+    assert_eq!(&src[stat.span.start .. stat.span.end], "while x do x = f(x) end");
+}
+
+#[test]
+fn syntax_beyond_the_compiler() {
+    let block = parse("for i = 1, 10 do goto next ::next:: end\nrepeat local t = {...} until #t > 0").unwrap();
+    assert!(matches!(block.stats[0].kind, StatKind::NumericFor(..)));
+    assert!(matches!(block.stats[1].kind, StatKind::Repeat(..)));
+}
+
+#[test]
+fn syntax_errors() {
+    assert_eq!(parse("x = = 1").unwrap_err(), "test:1: unexpected symbol near '='");
+    assert_eq!(parse("if x then\nend end").unwrap_err(), "test:2: '<eof>' expected near 'end'");
+    let nested = |n| format!("x = {}1{}", "f{(".repeat(n), ")}".repeat(n));
+    assert!(parse(&nested(30)).is_ok());
+    assert_eq!(parse(&nested(300)).unwrap_err(), "test:1: too many C levels (limit is 64)");
+}
+
+#[test]
+fn visitor_finds_calls() {
+    struct Calls(Vec<String>);
+    impl Visitor for Calls {
+        fn visit_exp(&mut self, exp: &Exp) {
+            if let ExpKind::Call(f, _) = &exp.kind && let ExpKind::Name(name) = &f.kind {
+                self.0.push(name.clone());
+            }
+            ast::walk_exp(self, exp);
+        }
+    }
+
+    let block = parse("local function g() return h(i(1)) end\nprint(g())").unwrap();
+    let mut calls = Calls(Vec::new());
+    calls.visit_block(&block);
+    assert_eq!(calls.0, ["h", "i", "print", "g"]);
+}
@@ -0,0 +1,103 @@
+mod common;
+
+use std::rc::Rc;
+use common::{compile, error, syntax_error, values};
+use rlua::{value::{RustClosure, Value}, vm::{ExeState, LuaError}};
+
+#[test]
+fn parentheses_cut_results_to_one() {
+    let src = "function f() return 1, 2, 3 end
+        local t, u = {(f())}, {f()}
+        local x, y = (f())
+        return #t, #u, x, y, (f())";
+    assert_eq!(values(src), [Value::Integer(1), Value::Integer(3), Value::Integer(1), Value::Nil, Value::Integer(1)]);
+}
+
+#[test]
+fn parentheses_make_values_not_variables() {
+    assert_eq!(syntax_error("local a = 1\n(a) = 2"), "test:2: syntax error near '='");
+    assert_eq!(syntax_error("(print(1))"), "test:1: syntax error near <eof>");
+}
+
+#[test]
+fn recursion() {
+    let src = "function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end
+        function depth(n) if n == 0 then return 0 end return 1 + depth(n - 1) end
+        return fib(20), depth(10000)";
+    assert_eq!(values(src), [Value::Integer(6765), Value::Integer(10000)]);
+    assert_eq!(error("function f() f() end\nf()"), "test:1: stack overflow");
+
+    // a local function is in scope in its body, not taken for a global
+    assert_eq!(syntax_error("local function f(n) if n > 0 then return f(n - 1) end end return f(3)"),
+        "test:1: local 'f' of an enclosing function is not accessible (no upvalues)");
+}
+
+#[test]
+fn arguments_and_fields() {
+    let src = "local function f(a, b, c) return c, b, a end
+        t = {a = {}}
+        function t.a.b(x) return x * 2 end
+        function t:m(x) return self == t, x end
+        local x, y, z = f(1)
+        return x, y, z, t.a.b(4), t:m(5)";
+    assert_eq!(values(src), [Value::Nil, Value::Nil, Value::Integer(1), Value::Integer(8),
+        Value::Boolean(true), Value::Integer(5)]);
+    assert_eq!(values("function f() end\nfunction g() return end\nlocal a, b = g(), 1\nreturn a, b, f()"),
+        [Value::Nil, Value::Integer(1)]);
+}
+
+fn count(state: &mut ExeState) -> Result<usize, LuaError> {
+    let n = state.arg_count();
+    state.push(n as i64);
+    Ok(1)
+}
+
+fn three(state: &mut ExeState) -> Result<usize, LuaError> {
+    for i in 1 ..= 3 {
+        state.push(i);
+    }
+    Ok(3)
+}
+
+fn second(state: &mut ExeState) -> Result<usize, LuaError> {
+    let arg = state.arg(2).clone();
+    state.push(arg);
+    Ok(1)
+}
+
+fn run_with_functions(src: &str) -> Vec<Value> {
+    let mut state = ExeState::new();
+    state.set_global("count", Value::RustFunction(count));
+    state.set_global("three", Value::RustFunction(three));
+    state.set_global("second", Value::RustFunction(second));
+    state.execute(&compile(src).unwrap()).unwrap()
+}
+
+#[test]
+fn results_of_rust_functions() {
+    assert_eq!(run_with_functions("return count(three()), count(three(), 0), count(0, three()), count()"),
+        [Value::Integer(3), Value::Integer(2), Value::Integer(4), Value::Integer(0)]);
+    assert_eq!(run_with_functions("local t = {three(), three()}\nlocal a, b, c, d = three()\nreturn #t, c, d, three()"),
+        [Value::Integer(4), Value::Integer(3), Value::Nil, Value::Integer(1), Value::Integer(2), Value::Integer(3)]);
+    assert_eq!(run_with_functions("return second(1), second(1, 'x', 3)"), [Value::Nil, Value::from("x")]);
+}
+
+// its upvalues, and whether it has a second one
+fn upvalues(state: &mut ExeState) -> Result<usize, LuaError> {
+    let first = state.upvalue(1).cloned().unwrap_or(Value::from("none"));
+    let second = state.upvalue(2).is_some();
+    state.push(first);
+    state.push(second);
+    Ok(2)
+}
+
+#[test]
+fn upvalues_of_rust_closures() {
+    let mut state = ExeState::new();
+    let closure = RustClosure { f: upvalues, upvalues: vec![Value::Integer(5)] };
+    state.set_global("closure", Value::RustClosure(Rc::new(closure)));
+    state.set_global("plain", Value::RustFunction(upvalues));
+    let results = state.execute(&compile("local a, b = closure()
+return a, b, plain()").unwrap()).unwrap();
+    assert_eq!(results, [Value::Integer(5), Value::Boolean(false), Value::from("none"), Value::Boolean(false)]);
+}
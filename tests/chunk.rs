@@ -0,0 +1,186 @@
+mod common;
+
+use std::rc::Rc;
+use rlua::{bytecode::{Bytecode, MAX_EXTRA_ARG}, dump, proto::Proto, value::Value, vm::ExeState};
+
+// undump a chunk of the main function with `code`, for its error
+fn load(code: Vec<Bytecode>, constants: Vec<Value>) -> Result<(), String> {
+    let proto = Proto { bytecodes: code, constants, max_stack_size: 4, ..Default::default() };
+    let mut chunk = Vec::new();
+    dump::dump(&proto, &mut chunk, true).unwrap();
+    dump::undump(chunk.as_slice(), "crafted").map(|_| ()).map_err(|e| e.to_string())
+}
+
+#[test]
+fn jump_into_extra_arg() {
+    let code = vec![
+        Bytecode::Jump(1),
+        Bytecode::LoadConstX(0),
+        Bytecode::extra_arg(0),
+        Bytecode::Return(0, 0),
+    ];
+    assert_eq!(load(code, vec![Value::Integer(1)]).unwrap_err(),
+        "crafted: bad binary format (jump into ExtraArg in instruction 1 (Jump(1)))");
+}
+
+#[test]
+fn global_name_not_utf8() {
+    let code = vec![Bytecode::GetGlobal(0, 0), Bytecode::Return(0, 0)];
+    let err = load(code, vec![Value::from(&b"\xff\xfe"[..])]).unwrap_err();
+    assert!(err.contains("invalid global name"), "{}", err);
+
+    let code = vec![Bytecode::SetGlobal(0, 0), Bytecode::Return(0, 0)];
+    assert!(load(code, vec![Value::Integer(1)]).unwrap_err().contains("invalid global name"));
+}
+
+#[test]
+fn test_skipping_past_the_end() {
+    let code = vec![Bytecode::Return(0, 0), Bytecode::TestEq(0, 1, true), Bytecode::Jump(-3)];
+    assert!(load(code, vec![]).unwrap_err().contains("test out of range"));
+}
+
+#[test]
+fn set_list_far_past_the_array() {
+    // the values are stored by key, not by growing the array up to them
+    let code = vec![
+        Bytecode::NewTable(0, 0, 0),
+        Bytecode::LoadInt(1, 7),
+        Bytecode::SetListX(0, 1),
+        Bytecode::extra_arg(MAX_EXTRA_ARG),
+        Bytecode::LoadConst(2, 0),
+        Bytecode::GetTable(1, 0, 2),
+        Bytecode::Len(2, 0),
+        Bytecode::Return(0, 3),
+    ];
+    let proto = Proto { bytecodes: code, constants: vec![Value::Integer(MAX_EXTRA_ARG as i64 + 1)],
+        max_stack_size: 4, ..Default::default() };
+    let mut chunk = Vec::new();
+    dump::dump(&proto, &mut chunk, true).unwrap();
+    let loaded = dump::undump(chunk.as_slice(), "crafted").unwrap();
+    let results = ExeState::new().execute(&loaded).unwrap();
+    let Value::Table(t) = &results[0] else { panic!("not a table: {:?}", results[0]) };
+    assert!(t.borrow().array.is_empty());
+    assert_eq!(results[1 ..], [Value::Integer(7), Value::Integer(0)]);
+}
+
+#[test]
+fn valid_chunk_is_loaded() {
+    let code = vec![Bytecode::LoadConstX(0), Bytecode::extra_arg(0), Bytecode::Return(0, 1)];
+    assert_eq!(load(code, vec![Value::Integer(1)]), Ok(()));
+}
+
+#[test]
+fn corrupted_chunks_do_not_panic() {
+    let proto = common::compile("local t = {1, 2, x = 'y'}\nif t.x == 'y' then g = #t end\nprint(g)").unwrap();
+    let mut chunk = Vec::new();
+    dump::dump(&proto, &mut chunk, false).unwrap();
+    for i in 0 .. chunk.len() {
+        for byte in [0, 1, 0x7f, 0x80, 0xff] {
+            let mut bad = chunk.clone();
+            bad[i] = byte;
+            // those loaded run without panicking, unless they may loop
+            if let Ok(proto) = dump::undump(bad.as_slice(), "corrupted")
+                && !proto.bytecodes.iter().any(|code| matches!(code, Bytecode::Jump(offset)
+                    | Bytecode::JumpFalse(_, offset) | Bytecode::JumpTrue(_, offset) if *offset < 0)) {
+                let _ = ExeState::new().execute(&proto);
+            }
+        }
+        let _ = dump::undump(&chunk[.. i], "truncated");
+    }
+}
+
+#[test]
+fn open_results_used_from_above_the_call() {
+    // the arguments of the next call would start above the results
+    let code = vec![
+        Bytecode::GetGlobal(0, 0),
+        Bytecode::Call(0, 0, u8::MAX),
+        Bytecode::Call(2, u8::MAX, 1),
+        Bytecode::Return(0, 0),
+    ];
+    let err = load(code, vec![Value::from("print")]).unwrap_err();
+    assert!(err.contains("open results not used"), "{}", err);
+
+    let code = vec![
+        Bytecode::GetGlobal(0, 0),
+        Bytecode::Call(0, 0, u8::MAX),
+        Bytecode::Return(1, u8::MAX),
+    ];
+    assert!(load(code, vec![Value::from("print")]).unwrap_err().contains("open results not used"));
+
+    let code = vec![
+        Bytecode::GetGlobal(1, 0),
+        Bytecode::Call(1, 0, u8::MAX),
+        Bytecode::Return(1, u8::MAX),
+    ];
+    assert_eq!(load(code, vec![Value::from("print")]), Ok(()));
+}
+
+fn round_trip(proto: &Proto, strip: bool) -> Rc<Proto> {
+    let mut chunk = Vec::new();
+    dump::dump(proto, &mut chunk, strip).unwrap();
+    dump::undump(chunk.as_slice(), "loaded").unwrap()
+}
+
+#[test]
+fn dumped_chunk_runs_the_same() {
+    let src = "local function f(a) return a .. 'x', 2.5, 10, true end\nreturn f('y')";
+    let proto = common::compile(src).unwrap();
+    for strip in [false, true] {
+        let loaded = round_trip(&proto, strip);
+        assert_eq!(ExeState::new().execute(&loaded).unwrap(),
+            [Value::from("yx"), Value::Float(2.5), Value::Integer(10), Value::Boolean(true)]);
+    }
+}
+
+#[test]
+fn dumped_debug_info() {
+    let proto = common::compile("local x = 1\n\nlocal t = nil\nreturn t.x").unwrap();
+    let loaded = round_trip(&proto, false);
+    assert_eq!(loaded.source.as_deref(), Some("test"));
+    assert_eq!(loaded.locvars.len(), 2);
+    assert_eq!(ExeState::new().execute(&loaded).unwrap_err().to_string(), "test:4: attempt to index a nil value");
+
+    let loaded = round_trip(&proto, true);
+    assert_eq!(loaded.source, None);
+    assert!(loaded.locvars.is_empty());
+    assert_eq!(ExeState::new().execute(&loaded).unwrap_err().to_string(), "?:-1: attempt to index a nil value");
+}
+
+#[test]
+fn bad_headers() {
+    let undump = |chunk: &[u8]| dump::undump(chunk, "bad").map(|_| ()).unwrap_err().to_string();
+    let mut chunk = Vec::new();
+    dump::dump(&common::compile("x = 1").unwrap(), &mut chunk, false).unwrap();
+
+    assert_eq!(undump(b"x = 1"), "bad: bad binary format (not a binary chunk)");
+    let mut bad = chunk.clone();
+    bad[dump::SIGNATURE.len()] += 1;
+    assert_eq!(undump(&bad), "bad: bad binary format (version mismatch)");
+    let mut bad = chunk.clone();
+    bad[dump::SIGNATURE.len() + 3] = b'\n';
+    assert_eq!(undump(&bad), "bad: bad binary format (corrupted chunk)");
+    assert_eq!(undump(&chunk[.. chunk.len() - 1]), "bad: bad binary format (truncated chunk)");
+}
+
+#[test]
+fn operands_out_of_range() {
+    // registers up to max_stack_size, which is 4
+    let err = load(vec![Bytecode::LoadInt(4, 1), Bytecode::Return(0, 0)], vec![]).unwrap_err();
+    assert_eq!(err, "crafted: bad binary format (register out of range in instruction 1 (LoadInt(4, 1)))");
+    let err = load(vec![Bytecode::LoadConst(0, 1), Bytecode::Return(0, 0)], vec![Value::Integer(1)]).unwrap_err();
+    assert!(err.contains("constant out of range"), "{}", err);
+    let err = load(vec![Bytecode::Closure(0, 0), Bytecode::Return(0, 0)], vec![]).unwrap_err();
+    assert!(err.contains("function out of range"), "{}", err);
+}
+
+#[test]
+fn malformed_code() {
+    assert!(load(vec![Bytecode::LoadInt(0, 1)], vec![]).unwrap_err().contains("missing final return"));
+    assert!(load(vec![Bytecode::LoadConstX(0), Bytecode::Return(0, 0)], vec![Value::Integer(1)])
+        .unwrap_err().contains("missing ExtraArg"));
+    assert!(load(vec![Bytecode::extra_arg(0), Bytecode::Return(0, 0)], vec![]).unwrap_err().contains("unexpected ExtraArg"));
+    assert!(load(vec![Bytecode::Jump(5), Bytecode::Return(0, 0)], vec![]).unwrap_err().contains("jump out of range"));
+    let code = vec![Bytecode::TestEq(0, 1, true), Bytecode::Return(0, 0), Bytecode::Return(0, 0)];
+    assert!(load(code, vec![]).unwrap_err().contains("test without jump"));
+}
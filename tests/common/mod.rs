@@ -0,0 +1,28 @@
+#![allow(dead_code)]
+
+use std::rc::Rc;
+use rlua::{lexer::SyntaxError, parser::{CompileOptions, ParseProto}, proto::Proto, value::Value, vm::ExeState};
+
+pub fn compile(src: &str) -> Result<Rc<Proto>, SyntaxError> {
+    let options = CompileOptions { chunk_name: "test".to_string(), ..Default::default() };
+    ParseProto::load(src.as_bytes(), options)
+}
+
+// the message of a syntax error
+pub fn syntax_error(src: &str) -> String {
+    compile(src).unwrap_err().to_string()
+}
+
+// the values returned by the chunk, or the message of its error
+pub fn run(src: &str) -> Result<Vec<Value>, String> {
+    let proto = compile(src).map_err(|e| e.to_string())?;
+    ExeState::new().execute(&proto).map_err(|e| e.to_string())
+}
+
+pub fn values(src: &str) -> Vec<Value> {
+    run(src).unwrap()
+}
+
+pub fn error(src: &str) -> String {
+    run(src).unwrap_err()
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{compile, values};
+use rlua::{bytecode::Bytecode, parser::{CompileOptions, ParseProto}, peephole, proto::Proto, value::Value, vm::ExeState};
+
+#[test]
+fn max_stack_size() {
+    assert_eq!(compile("local a, b = 1, 2\nlocal c = a").unwrap().max_stack_size, 3);
+    // temporaries are freed at the end of statements
+    assert_eq!(compile("x = {1, 2, {3, 4}}\ny = 1\nlocal z = 2").unwrap().max_stack_size, 6);
+    assert_eq!(compile("print(1, 2, 3)").unwrap().max_stack_size, 4);
+
+    let proto = compile("function f(a, b, c) local d = a + b end\nf(1, 2, 3, 4, 5)").unwrap();
+    assert_eq!(proto.max_stack_size, 6);
+    assert_eq!(proto.protos[0].max_stack_size, 4);
+}
+
+#[test]
+fn registers_written_out_of_order() {
+    assert_eq!(values("local a, b, c\nc = 3\na = 1\nreturn a, b, c"),
+        [Value::Integer(1), Value::Nil, Value::Integer(3)]);
+    assert_eq!(values("local t = {x = {1, 2}, 3}\nreturn t[1], t.x[2]"), [Value::Integer(3), Value::Integer(2)]);
+    assert_eq!(values("function f(a, b) local c = b return c, a end\nreturn f(1, 2, 3)"),
+        [Value::Integer(2), Value::Integer(1)]);
+}
+
+fn has(proto: &Proto, f: impl Fn(&Bytecode) -> bool) -> bool {
+    proto.bytecodes.iter().any(f)
+}
+
+#[test]
+fn peephole_rewrites() {
+    let proto = compile("x = 1.5\nlocal a = 1\nlocal b = a + 1\nif a < b then y = b end").unwrap();
+    assert!(has(&proto, |code| matches!(code, Bytecode::SetGlobalConst(..))));
+    assert!(has(&proto, |code| matches!(code, Bytecode::AddI(1, 0, 1))));
+    assert!(has(&proto, |code| matches!(code, Bytecode::TestLt(0, 1, false))));
+    assert!(!has(&proto, |code| matches!(code, Bytecode::LoadConst(..) | Bytecode::Lt(..) | Bytecode::JumpFalse(..))));
+}
+
+#[test]
+fn peephole_fixes_jumps_and_lines() {
+    let mut bytecodes = vec![Bytecode::Jump(2), Bytecode::LoadConst(1, 0), Bytecode::SetGlobal(0, 1), Bytecode::LoadNil(0, 1)];
+    let mut lines = vec![1, 2, 2, 3];
+    let moved = peephole::optimize(&mut bytecodes, &mut lines);
+    assert!(matches!(bytecodes[..], [Bytecode::Jump(1), Bytecode::SetGlobalConst(0, 0), Bytecode::LoadNil(0, 1)]));
+    assert_eq!(lines, [1, 2, 3]);
+    assert_eq!(moved[3 ..], [2, 3]);
+}
+
+#[test]
+fn peephole_keeps_the_results() {
+    let src = "local a, b = 1, 2.5\nx = 1.5\nif a < b then y = a + 1 elseif a == 1 then y = 0 end\nlocal c = a <= b\nreturn x, y, c, a + 100";
+    let run = |opt_level| {
+        let options = CompileOptions { opt_level, ..Default::default() };
+        ExeState::new().execute(&ParseProto::load(src.as_bytes(), options).unwrap()).unwrap()
+    };
+    assert_eq!(run(0), [Value::Float(1.5), Value::Integer(2), Value::Boolean(true), Value::Integer(101)]);
+    assert_eq!(run(0), run(1));
+}
+
+#[test]
+fn constants_are_deduplicated() {
+    let long = "y".repeat(60);
+    let src = format!("a = 'x'\nb = 'x'\na = 2.5\nb = 2.5\na = 2.0\nb = 2\na = '{long}'\nb = '{long}'");
+    let proto = compile(&src).unwrap();
+    // integers and floats of the same value are different constants
+    assert_eq!(proto.constants, [Value::from("a"), Value::from("x"), Value::from("b"), Value::Float(2.5),
+        Value::Float(2.0), Value::Integer(2), Value::from(long.as_str())]);
+}
+
+#[test]
+fn constants_beyond_the_operand_range_are_shared() {
+    // "x" stays one constant, used again after the keys fill 256 others
+    let sets: String = (0 .. 300).map(|i| format!("t.k{i} = 'x'\n")).collect();
+    let src = format!("t = {{1}}\n{sets}return t.k0, t.k299");
+    let proto = compile(&src).unwrap();
+    assert_eq!(proto.constants.iter().filter(|&k| k == &Value::from("x")).count(), 1);
+    assert_eq!(values(&src), [Value::from("x"), Value::from("x")]);
+}
+
+#[test]
+fn proto_runs_without_the_source() {
+    let src = String::from("n = (n or 0) + 1\nreturn n");
+    let proto = compile(&src).unwrap();
+    drop(src);
+
+    let mut state = ExeState::new();
+    assert_eq!(state.execute(&proto).unwrap(), [Value::Integer(1)]);
+    assert_eq!(state.execute(&proto).unwrap(), [Value::Integer(2)]);
+    assert_eq!(ExeState::new().execute(&proto).unwrap(), [Value::Integer(1)]);
+}
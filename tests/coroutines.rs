@@ -0,0 +1,224 @@
+mod common;
+
+use common::{compile, error, values};
+use rlua::{value::Value, vm::{ExeState, LuaError, Resumed}};
+
+fn s(s: &str) -> Value {
+    Value::from(s)
+}
+
+#[test]
+fn resume_and_yield() {
+    let src = "local co = coroutine.create(function(a, b)
+            local c = coroutine.yield(a + b)
+            local d, e = coroutine.yield(c * 2)
+            return d + e
+        end)
+        local r1, r2, r3 = {coroutine.resume(co, 1, 2)}, {coroutine.resume(co, 10)}, {coroutine.resume(co, 3, 4)}
+        local r4 = {coroutine.resume(co)}
+        return r1[2], r2[2], r3[1], r3[2], r4[1], r4[2], coroutine.status(co)";
+    assert_eq!(values(src), [Value::Integer(3), Value::Integer(20), Value::Boolean(true), Value::Integer(7),
+        Value::Boolean(false), s("cannot resume dead coroutine"), s("dead")]);
+}
+
+#[test]
+fn errors_in_coroutines() {
+    let src = "local co = coroutine.create(function() error('oops') end)
+        local ok, e = coroutine.resume(co)
+        return ok, e, coroutine.status(co)";
+    assert_eq!(values(src), [Value::Boolean(false), s("test:1: oops"), s("dead")]);
+    // like Lua, `wrap` adds the position of its caller to string errors
+    assert_eq!(error("local f = coroutine.wrap(function() error('w') end)\nf()"), "test:2: test:1: w");
+    assert_eq!(error("return coroutine.yield(1)"), "test:1: attempt to yield from outside a coroutine");
+    assert_eq!(error("coroutine.resume(1)"), "test:1: bad argument #1 to 'resume' (coroutine expected, got number)");
+}
+
+#[test]
+fn wrap_status_and_isyieldable() {
+    let src = "local f = coroutine.wrap(function(x) coroutine.yield(x) return x + 1 end)
+        return f(1), f(), pcall(f)";
+    assert_eq!(values(src), [Value::Integer(1), Value::Integer(2), Value::Boolean(false), s("cannot resume dead coroutine")]);
+
+    let src = "co = coroutine.create(function() return coroutine.status(co), coroutine.isyieldable() end)
+        local status = coroutine.status(co)
+        local _, inside, yieldable = coroutine.resume(co)
+        return status, inside, yieldable, coroutine.isyieldable()";
+    assert_eq!(values(src), [s("suspended"), s("running"), Value::Boolean(true), Value::Boolean(false)]);
+}
+
+#[test]
+fn close_suspended_coroutine() {
+    let src = "log = {}
+        local co = coroutine.create(function()
+            local x <close> = setmetatable({}, {__close = function(o, e) log[1] = 'closed' end})
+            coroutine.yield()
+        end)
+        coroutine.resume(co)
+        return coroutine.close(co), log[1], coroutine.status(co)";
+    assert_eq!(values(src), [Value::Boolean(true), s("closed"), s("dead")]);
+}
+
+#[test]
+fn close_variables() {
+    let src = "log = {}
+        function closer(name) return setmetatable({name = name}, {__close = function(o) log[#log + 1] = o.name end}) end
+        function f()
+            local a <close> = closer('a')
+            local b <close> = closer('b')
+            local c <close> = nil
+            return 'r'
+        end
+        return f(), log[1], log[2]";
+    assert_eq!(values(src), [s("r"), s("b"), s("a")]);
+    assert_eq!(error("local x <close> = 1"), "test:1: variable 'x' got a non-closable value");
+
+    // a return which may not be taken does not close them
+    let src = "log = {}
+        function closer(name) return setmetatable({name = name}, {__close = function(o) log[#log + 1] = o.name end}) end
+        function f(c)
+            if true then
+                local a <close> = closer('a')
+                if c then return end
+            end
+            local b = 'b'
+            return b, log[1]
+        end
+        return f(false)";
+    assert_eq!(values(src), [s("b"), s("a")]);
+}
+
+#[test]
+fn close_variables_on_errors() {
+    let src = "log = {}
+        function f()
+            local a <close> = setmetatable({}, {__close = function(o, e) log[1] = e end})
+            error('E', 0)
+        end
+        local ok, e = pcall(f)
+        return ok, e, log[1]";
+    assert_eq!(values(src), [Value::Boolean(false), s("E"), s("E")]);
+
+    let src = "function f()
+            local a <close> = setmetatable({}, {__close = function() error('in close', 0) end})
+            return 1
+        end
+        return pcall(f)";
+    assert_eq!(values(src), [Value::Boolean(false), s("in close")]);
+}
+
+#[test]
+fn yields_across_pcall() {
+    let src = "local co = coroutine.create(function(a)
+            local ok, b = pcall(coroutine.yield, a)
+            local ok2, e = pcall(function(v) coroutine.yield(v) error('E', 0) end, b)
+            return ok, b, ok2, e
+        end)
+        local _, x = coroutine.resume(co, 1)
+        local _, y = coroutine.resume(co, 2)
+        local r = {coroutine.resume(co)}
+        return x, y, r[2], r[3], r[4], r[5]";
+    assert_eq!(values(src), [Value::Integer(1), Value::Integer(2), Value::Boolean(true), Value::Integer(2),
+        Value::Boolean(false), s("E")]);
+
+    // the handler of `xpcall()` and the variables to be closed are kept
+    let src = "log = {}
+        local co = coroutine.wrap(function()
+            return xpcall(function()
+                local a <close> = setmetatable({}, {__close = function(o, e) log[1] = e end})
+                coroutine.yield(coroutine.isyieldable())
+                error('E', 0)
+            end, function(e) return 'handled ' .. e end)
+        end)
+        local y = co()
+        local ok, e = co()
+        return y, ok, e, log[1]";
+    assert_eq!(values(src), [Value::Boolean(true), Value::Boolean(false), s("handled E"), s("handled E")]);
+
+    // nested calls of `pcall()`
+    let src = "local co = coroutine.wrap(function() return pcall(pcall, coroutine.yield, 1) end)
+        local y = co()
+        return y, co('x')";
+    assert_eq!(values(src), [Value::Integer(1), Value::Boolean(true), Value::Boolean(true), s("x")]);
+
+    // metamethods are still called from Rust
+    let src = "t = setmetatable({}, {__index = function() coroutine.yield() end})
+        local co = coroutine.create(function() return pcall(function() return t.x end) end)
+        local _, ok, e = coroutine.resume(co)
+        return ok, e";
+    assert_eq!(values(src), [Value::Boolean(false), s("test:1: attempt to yield across a C-call boundary")]);
+}
+
+// yields its arguments, and returns the values it is resumed with and its
+// context
+fn wait(state: &mut ExeState) -> Result<usize, LuaError> {
+    let n = state.arg_count();
+    state.yield_k(n, 10, after_wait)
+}
+
+fn after_wait(state: &mut ExeState, ctx: usize) -> Result<usize, LuaError> {
+    if state.arg(1) == &Value::from("fail") {
+        return Err("failed".to_string().into());
+    }
+    state.push(ctx as i64);
+    Ok(state.arg_count())
+}
+
+// yields nothing, and returns the values it is resumed with
+fn pause(state: &mut ExeState) -> Result<usize, LuaError> {
+    state.yield_(0)
+}
+
+fn host(src: &str) -> (ExeState, Value) {
+    let mut state = ExeState::new();
+    state.set_global("wait", Value::RustFunction(wait));
+    state.set_global("pause", Value::RustFunction(pause));
+    state.execute(&compile(src).unwrap()).unwrap();
+    let co = state.get_global("co");
+    (state, co)
+}
+
+fn resume(state: &mut ExeState, co: &Value, args: Vec<Value>) -> (bool, Vec<Value>) {
+    match state.resume_coroutine(co, args).unwrap() {
+        Resumed::Yield(values) => (true, values),
+        Resumed::Return(values) => (false, values),
+    }
+}
+
+#[test]
+fn rust_functions_yield_with_continuations() {
+    let (mut state, co) = host("co = coroutine.create(function(x)
+            local a, b, c = wait(x, 'sent')
+            local d = pause()
+            return a, b, c, d
+        end)");
+    assert_eq!(resume(&mut state, &co, vec![Value::Integer(1)]), (true, vec![Value::Integer(1), s("sent")]));
+    assert_eq!(resume(&mut state, &co, vec![s("reply")]), (true, vec![]));
+    assert_eq!(resume(&mut state, &co, vec![s("d"), s("extra")]),
+        (false, vec![s("reply"), Value::Integer(10), Value::Nil, s("d")]));
+    assert!(state.resume_coroutine(&co, vec![]).is_err());
+}
+
+#[test]
+fn continuation_errors() {
+    let (mut state, co) = host("co = coroutine.create(function()\n  wait()\nend)");
+    resume(&mut state, &co, vec![]);
+    let e = state.resume_coroutine(&co, vec![s("fail")]).unwrap_err();
+    assert_eq!(e.to_string(), "test:2: failed");
+
+    // caught by a `pcall()` calling the function
+    let (mut state, co) = host("co = coroutine.create(function() return 'back', pcall(wait) end)");
+    resume(&mut state, &co, vec![]);
+    assert_eq!(resume(&mut state, &co, vec![s("fail")]), (false, vec![s("back"), Value::Boolean(false), s("failed")]));
+
+    let (mut state, _) = host("co = nil");
+    let e = state.execute(&compile("wait()").unwrap()).unwrap_err();
+    assert_eq!(e.to_string(), "test:1: attempt to yield from outside a coroutine");
+}
+
+#[test]
+fn wrapped_rust_yields() {
+    let (mut state, _) = host("co = nil");
+    let src = "local f = coroutine.wrap(function(x) local r = wait(x) return r .. '!' end)
+        return f('out'), f('in')";
+    assert_eq!(state.execute(&compile(src).unwrap()).unwrap(), [s("out"), s("in!")]);
+}
@@ -0,0 +1,53 @@
+mod common;
+
+use common::compile;
+use rlua::disasm;
+
+fn listing(src: &str) -> String {
+    let mut out = Vec::new();
+    disasm::list(&compile(src).unwrap(), &mut out).unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn nested_functions() {
+    let src = "local function add(a, b)\n  return a + b\nend\nprint(add(1, 2.5), \"s\")\n";
+    assert_eq!(listing(src), "\
+main <test> (9 instructions)
+0 params, 5 slots, 1 local, 3 constants, 1 function
+\t1\t[3]\tClosure       R0 0
+\t2\t[4]\tGetGlobal     R1 \"print\"
+\t3\t[4]\tMove          R2 add
+\t4\t[4]\tLoadInt       R3 1
+\t5\t[4]\tLoadConst     R4 2.5
+\t6\t[4]\tCall          R2 2 1
+\t7\t[4]\tLoadConst     R3 \"s\"
+\t8\t[4]\tCall          R1 2 0
+\t9\t[5]\tReturn        add 0
+constants (3):
+\t0\tstring\t\"print\"
+\t1\tnumber\t2.5
+\t2\tstring\t\"s\"
+locals (1):
+\t0\tadd\t2\t10
+
+function <test:1> (3 instructions)
+2 params, 3 slots, 2 locals, 0 constants, 0 functions
+\t1\t[2]\tAdd           R2 a b
+\t2\t[2]\tReturn        R2 1
+\t3\t[3]\tReturn        a 0
+constants (0):
+locals (2):
+\t0\ta\t1\t4
+\t1\tb\t1\t4
+");
+}
+
+#[test]
+fn jumps_and_locals_out_of_scope() {
+    let listing = listing("local x = 1\nif x then\n  local y = x\nend\nx = 2");
+    assert!(listing.contains("\t2\t[2]\tJumpFalse     x to 4\n"), "{listing}");
+    // `y` is active from after its initialization to the end of the block
+    assert!(listing.contains("\t3\t[3]\tMove          R1 x\n"), "{listing}");
+    assert!(listing.contains("\t1\ty\t4\t4\n"), "{listing}");
+}
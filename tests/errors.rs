@@ -0,0 +1,106 @@
+mod common;
+
+use common::{compile, error, values};
+use rlua::{value::Value, vm::ExeState};
+
+fn s(s: &str) -> Value {
+    Value::from(s)
+}
+
+#[test]
+fn xpcall_handler_runs_before_close() {
+    let src = "log = {}
+        function closer() return setmetatable({}, {__close = function() log[#log + 1] = 'close' end}) end
+        function body() local x <close> = closer() error('boom') end
+        function handler(e) log[#log + 1] = 'handler' return 'handled: ' .. e end
+        local ok, e = xpcall(body, handler)
+        return ok, e, log[1], log[2]";
+    assert_eq!(values(src), [Value::Boolean(false), s("handled: test:3: boom"), s("handler"), s("close")]);
+}
+
+#[test]
+fn xpcall_handler_of_errors_in_nested_calls() {
+    let src = "function inner() local t = nil return t.x end
+        function outer() inner() end
+        function handler(e) count = (count or 0) + 1 return e end
+        local ok, e = xpcall(outer, handler)
+        -- errors caught by an inner pcall are not handled
+        local ok2 = xpcall(function() pcall(error, 'x') end, handler)
+        return ok, e, count, ok2";
+    assert_eq!(values(src), [Value::Boolean(false), s("test:1: attempt to index a nil value"),
+        Value::Integer(1), Value::Boolean(true)]);
+}
+
+#[test]
+fn xpcall_handler_of_rust_errors_and_failing_handler() {
+    let src = "local ok, e = xpcall(error, function(e) return 'got ' .. e end, 'x')
+        local ok2, e2 = xpcall(error, function(e) error('again') end, 'y')
+        return e, e2";
+    assert_eq!(values(src), [s("got x"), s("error in error handling")]);
+    assert_eq!(error("xpcall(error)"), "test:1: bad argument #2 to 'xpcall' (value expected)");
+}
+
+#[test]
+fn lines_of_runtime_errors() {
+    assert_eq!(error("function f()\n  local t\n  return t.x\nend\n\nf()"), "test:3: attempt to index a nil value");
+    // lines too far apart for the relative line info
+    let gap = "\n".repeat(1000);
+    assert_eq!(error(&format!("local t = nil{gap}return t.x")), "test:1001: attempt to index a nil value");
+    // many instructions between absolute line info entries
+    let sets: String = (0 .. 300).map(|i| format!("x{i} = {i}\n")).collect();
+    assert_eq!(error(&format!("{sets}local t = nil return t.x")), "test:301: attempt to index a nil value");
+}
+
+#[test]
+fn runtime_error_messages() {
+    assert_eq!(error("x()"), "test:1: attempt to call a nil value");
+    assert_eq!(error("local t = {}\nt.f.g = 1"), "test:2: attempt to index a nil value");
+    assert_eq!(error("local s = 'a' .. {}"), "test:1: attempt to concatenate a table value");
+    assert_eq!(error("return #5"), "test:1: attempt to get length of a number value");
+    assert_eq!(error("return -{}"), "test:1: attempt to perform arithmetic on a table value");
+    assert_eq!(error("return {} < {}"), "test:1: attempt to compare two table values");
+    assert_eq!(error("return 2^63 | 0"), "test:1: number has no integer representation");
+}
+
+#[test]
+fn state_is_usable_after_an_error() {
+    let mut state = ExeState::new();
+    let err = state.execute(&compile("x = 1\nlocal t = nil\nt.y = 2\nx = 3").unwrap()).unwrap_err();
+    assert_eq!(err.value, Value::from("test:3: attempt to index a nil value"));
+    assert_eq!(state.execute(&compile("return x").unwrap()).unwrap(), [Value::Integer(1)]);
+
+    let err = state.execute(&compile("error({code = 42})").unwrap()).unwrap_err();
+    let Value::Table(t) = &err.value else {
+        panic!("not a table: {:?}", err.value);
+    };
+    assert_eq!(t.borrow().map.get(&Value::from("code")), Some(&Value::Integer(42)));
+    assert_eq!(err.to_string(), "(error object is a table value)");
+}
+
+#[test]
+fn pcall_results() {
+    assert_eq!(values("return pcall(function(a, b) return a + b, 'x' end, 1, 2)"),
+        [Value::Boolean(true), Value::Integer(3), s("x")]);
+    assert_eq!(values("local t = {}\nlocal ok, e = pcall(error, t)\nreturn ok, e == t"),
+        [Value::Boolean(false), Value::Boolean(true)]);
+    assert_eq!(values("return pcall(error)"), [Value::Boolean(false), Value::Nil]);
+    assert_eq!(values("return pcall(pcall, error, 'x')"), [Value::Boolean(true), Value::Boolean(false), s("x")]);
+    assert_eq!(values("return pcall(1)"), [Value::Boolean(false), s("attempt to call a number value")]);
+    assert_eq!(error("return pcall()"), "test:1: bad argument #1 to 'pcall' (value expected)");
+}
+
+#[test]
+fn error_levels() {
+    let src = "function f(level) error('deep', level) end
+        function g(level)
+            f(level)
+        end
+        local _, e1 = pcall(g, 1)
+        local _, e2 = pcall(g, 2)
+        local _, e0 = pcall(g, 0)
+        return e1, e2, e0";
+    assert_eq!(values(src), [s("test:1: deep"), s("test:3: deep"), s("deep")]);
+    // the caller of `error` is `pcall`, a Rust function without a line
+    assert_eq!(values("return pcall(error, 'msg')"), [Value::Boolean(false), s("msg")]);
+    assert_eq!(error("error()"), "(error object is a nil value)");
+}
@@ -0,0 +1,81 @@
+mod common;
+
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use rlua::{value::{Table, Value}, vm::ExeState};
+use common::compile;
+
+fn execute(state: &mut ExeState, src: &str) {
+    state.execute(&compile(src).unwrap()).unwrap();
+}
+
+fn weak(value: Value) -> Weak<RefCell<Table>> {
+    match value {
+        Value::Table(t) => Rc::downgrade(&t),
+        v => panic!("not a table: {v:?}"),
+    }
+}
+
+#[test]
+fn unreachable_cycle_is_freed() {
+    let mut state = ExeState::new();
+    execute(&mut state, "t = {}\nt.t = t\nu = {t}\nt.u = u\n");
+    let t = weak(state.get_global("t"));
+    execute(&mut state, "t = nil\nu = nil\n");
+    assert!(t.upgrade().is_some());
+    assert!(state.collect_garbage() >= 2);
+    assert!(t.upgrade().is_none());
+}
+
+#[test]
+fn cycles_are_freed_without_asking() {
+    let mut state = ExeState::new();
+    execute(&mut state, "t = {}\nt.t = t\n");
+    let t = weak(state.get_global("t"));
+    execute(&mut state, "t = nil\n");
+    let cycle = compile("local c = {}\nc.c = c\n").unwrap();
+    for _ in 0 .. 3000 {
+        state.execute(&cycle).unwrap();
+    }
+    assert!(t.upgrade().is_none());
+}
+
+#[test]
+fn reachable_cycle_is_kept() {
+    let mut state = ExeState::new();
+    execute(&mut state, "t = {}\nt.t = t\nt.x = 1\nlocal c = {}\nc.c = c\n");
+    let t = weak(state.get_global("t"));
+    state.collect_garbage();
+    let t = t.upgrade().unwrap();
+    assert_eq!(t.borrow().map.len(), 2);
+}
+
+#[test]
+fn library_tables_are_collected() {
+    let mut state = ExeState::new();
+    execute(&mut state, "coroutine.lib = coroutine\n");
+    let coroutine = weak(state.get_global("coroutine"));
+    execute(&mut state, "coroutine = nil\n");
+    state.collect_garbage();
+    assert!(coroutine.upgrade().is_none());
+}
+
+#[test]
+fn cyclic_table_is_printed() {
+    let mut state = ExeState::new();
+    execute(&mut state, "t = {1}\nt.t = t\n");
+    let t = state.get_global("t");
+    assert_eq!(t.to_string(), "{[1] = 1, [\"t\"] = {...}, }");
+    assert!(format!("{t:?}").contains("{...}"));
+}
+
+#[test]
+fn suspended_coroutine_in_a_cycle_is_freed() {
+    let mut state = ExeState::new();
+    execute(&mut state, "t = {}
+        t.co = coroutine.create(function(t) coroutine.yield() end)
+        coroutine.resume(t.co, t)");
+    let t = weak(state.get_global("t"));
+    execute(&mut state, "t = nil\nreturn collectgarbage()");
+    assert!(t.upgrade().is_none());
+}
@@ -0,0 +1,31 @@
+use rlua::lexer::{Lexer, Token};
+
+fn tokens(src: &str) -> Result<Vec<Token>, String> {
+    let mut lexer = Lexer::new(src.as_bytes(), "test");
+    let mut tokens = Vec::new();
+    loop {
+        match lexer.next().map_err(|e| e.to_string())? {
+            Token::Eos => break Ok(tokens),
+            t => tokens.push(t),
+        }
+    }
+}
+
+#[test]
+fn hexadecimal_numbers() {
+    assert_eq!(tokens("0xff 0x1.8p3 0x.8 0xAp-1 0x10P+2").unwrap(), [Token::Integer(255), Token::Float(12.0),
+        Token::Float(0.5), Token::Float(5.0), Token::Float(64.0)]);
+    // integers wrap around, unlike decimal ones
+    assert_eq!(tokens("0xffffffffffffffff").unwrap(), [Token::Integer(-1)]);
+    assert_eq!(tokens("0x1p").unwrap_err(), "test:1: malformed number near '0x1p'");
+    assert_eq!(tokens("0x.p1").unwrap_err(), "test:1: malformed number near '0x.p1'");
+}
+
+#[test]
+fn escapes() {
+    assert_eq!(tokens("'\\u{48}\\u{20AC}'").unwrap(), [Token::String("H\u{20AC}".into())]);
+    assert_eq!(tokens("'a\\z  \n  b'").unwrap(), [Token::String(b"ab".to_vec())]);
+    assert_eq!(tokens("'\\u{110000}'").unwrap_err(), "test:1: UTF-8 value too large near ''\\u{110000'");
+    assert_eq!(tokens("'\\u48'").unwrap_err(), "test:1: missing '{' in \\u{xxxx} near ''\\u4'");
+    assert_eq!(tokens("'\\u{48'").unwrap_err(), "test:1: missing '}' in \\u{xxxx} near ''\\u{48''");
+}
@@ -0,0 +1,64 @@
+mod common;
+
+use common::{compile, syntax_error, values};
+use rlua::{bytecode::{Bytecode, MAX_EXTRA_ARG}, value::Value};
+
+#[test]
+fn more_than_256_constants() {
+    let strings: Vec<String> = (0 .. 300).map(|i| format!("'s{i}'")).collect();
+    let src = format!("local t = {{{}}}\nx = 'last'\nreturn t[1], t[256], t[257], t[300], x",
+        strings.join(", "));
+    let proto = compile(&src).unwrap();
+    assert!(proto.constants.len() > 300);
+    assert_eq!(proto.constants[299], Value::from("s299"));
+    assert!(proto.bytecodes.iter().any(|code| matches!(code, Bytecode::LoadConst(_, 256 ..))));
+    assert_eq!(values(&src), [Value::from("s0"), Value::from("s255"), Value::from("s256"),
+        Value::from("s299"), Value::from("last")]);
+}
+
+#[test]
+fn more_than_256_globals() {
+    let sets: String = (0 .. 300).map(|i| format!("g{i} = {i}\n")).collect();
+    let src = format!("{sets}return g0, g255, g256, g299");
+    let proto = compile(&src).unwrap();
+    assert!(proto.bytecodes.iter().any(|code| matches!(code, Bytecode::SetGlobalX(..))));
+    assert!(proto.bytecodes.iter().any(|code| matches!(code, Bytecode::GetGlobalX(..))));
+    assert_eq!(values(&src), [Value::Integer(0), Value::Integer(255), Value::Integer(256), Value::Integer(299)]);
+}
+
+#[test]
+fn too_many_local_variables() {
+    let names: Vec<String> = (0 .. 201).map(|i| format!("l{i}")).collect();
+    let src = format!("local {}", names.join(", "));
+    assert_eq!(syntax_error(&src), "test:1: too many local variables (limit is 200) in main function");
+    let src = format!("local {}", names[.. 200].join(", "));
+    assert!(compile(&src).is_ok());
+}
+
+#[test]
+fn expression_too_complex() {
+    let args = vec!["1"; 300].join(", ");
+    assert_eq!(syntax_error(&format!("print({args})")), "test:1: function or expression too complex");
+}
+
+#[test]
+fn too_deeply_nested() {
+    // within the limit, the stack of a test thread is enough
+    let parens = |n| format!("local x = {}1{}", "(".repeat(n), ")".repeat(n));
+    assert!(compile(&parens(60)).is_ok());
+    assert_eq!(syntax_error(&parens(300)), "test:1: too many C levels (limit is 64) in main function");
+    let tables = |n| format!("local x = {}1{}", "{a = ".repeat(n), "}".repeat(n));
+    assert!(compile(&tables(60)).is_ok());
+    assert_eq!(syntax_error(&tables(300)), "test:1: too many C levels (limit is 64) in main function");
+    let ifs = |n| format!("{}{}", "if x then ".repeat(n), "end ".repeat(n));
+    assert!(compile(&ifs(60)).is_ok());
+    assert_eq!(syntax_error(&ifs(300)), "test:1: too many C levels (limit is 64) in main function");
+}
+
+#[test]
+fn extra_arguments() {
+    for n in [0, 0xff, 0x100, 0xffff, 0x10000, MAX_EXTRA_ARG] {
+        assert_eq!(Bytecode::extra_arg(n).extra_arg_value(), n);
+    }
+    assert_eq!(std::mem::size_of::<Bytecode>(), 4);
+}
@@ -0,0 +1,93 @@
+mod common;
+
+use common::{error, values};
+use rlua::value::Value;
+
+fn s(s: &str) -> Value {
+    Value::from(s)
+}
+
+#[test]
+fn index() {
+    let src = "local base = {x = 1}
+        local t = setmetatable({}, {__index = base})
+        local u = setmetatable({}, {__index = t})
+        local f = setmetatable({}, {__index = function(t, k) return k .. '!' end})
+        return t.x, t.y, u.x, f.a, f[1], getmetatable(t).__index == base";
+    assert_eq!(values(src), [Value::Integer(1), Value::Nil, Value::Integer(1), s("a!"), s("1!"), Value::Boolean(true)]);
+}
+
+#[test]
+fn newindex() {
+    let src = "store = {}
+        local t = setmetatable({y = 0}, {__newindex = store})
+        t.x = 1 t.y = 2
+        local u = setmetatable({}, {__newindex = function(t, k, v) rawset(t, k, v * 10) end})
+        u.z = 4
+        return t.x, store.x, t.y, store.y, u.z";
+    assert_eq!(values(src), [Value::Nil, Value::Integer(1), Value::Integer(2), Value::Nil, Value::Integer(40)]);
+}
+
+#[test]
+fn protected_metatables() {
+    let src = "local t = setmetatable({}, {__metatable = 'locked'})\nreturn getmetatable(t)";
+    assert_eq!(values(src), [s("locked")]);
+    assert_eq!(error("local t = setmetatable({}, {__metatable = 'locked'})\nsetmetatable(t, {})"),
+        "test:2: cannot change a protected metatable");
+    assert_eq!(values("local t = setmetatable({}, {})\nsetmetatable(t, nil)\nreturn getmetatable(t)"), [Value::Nil]);
+}
+
+#[test]
+fn metatable_errors() {
+    assert_eq!(error("return setmetatable(1, {})"), "test:1: bad argument #1 to 'setmetatable' (table expected, got number)");
+    assert_eq!(error("local t = {}\nsetmetatable(t, {__index = t})\nreturn t.x"), "test:3: '__index' chain too long; possible loop");
+    assert_eq!(error("local t = setmetatable({}, {__index = 5})\nreturn t.x"), "test:2: attempt to index a number value");
+}
+
+const OPERATORS: &str = "mt = {
+        __add = function(a, b) return 'add' end, __sub = function(a, b) return 'sub' end,
+        __idiv = function() return 'idiv' end, __band = function() return 'band' end,
+        __unm = function(a) return 'unm' end, __bnot = function() return 'bnot' end,
+        __concat = function(a, b) return 'cat' end, __len = function(a) return 42 end,
+        __eq = function(a, b) return true end, __lt = function(a, b) return true end,
+        __le = function(a, b) return false end, __call = function(self, x, y) return y, x end,
+    }
+    function new() return setmetatable({}, mt) end
+    ";
+
+#[test]
+fn operator_metamethods() {
+    let src = format!("{OPERATORS}local a, b = new(), new()
+        return a + 1, 1 - a, a // 2, a & 1, -a, ~a, a .. 'x', 'x' .. a, #a");
+    assert_eq!(values(&src), [s("add"), s("sub"), s("idiv"), s("band"), s("unm"), s("bnot"), s("cat"), s("cat"),
+        Value::Integer(42)]);
+}
+
+#[test]
+fn comparison_metamethods() {
+    let src = format!("{OPERATORS}local a, b = new(), new()
+        return a == b, a ~= b, a == {{}}, a < b, a <= b, a > b, 1 < a");
+    assert_eq!(values(&src), [Value::Boolean(true), Value::Boolean(false), Value::Boolean(true), Value::Boolean(true),
+        Value::Boolean(false), Value::Boolean(true), Value::Boolean(true)]);
+    // results are converted to booleans
+    let src = "local mt = {__eq = function() return 1 end, __lt = function() return nil end}
+        local a, b = setmetatable({}, mt), setmetatable({}, mt)
+        return a == b, a < b";
+    assert_eq!(values(src), [Value::Boolean(true), Value::Boolean(false)]);
+}
+
+#[test]
+fn call_metamethod() {
+    assert_eq!(values(&format!("{OPERATORS}return new()(1, 2)")), [Value::Integer(2), Value::Integer(1)]);
+    assert_eq!(error("local t = setmetatable({}, {__call = 5})\nreturn t()"), "test:2: attempt to call a number value");
+}
+
+#[test]
+fn metamethods_see_the_operands() {
+    let src = "local a = setmetatable({}, {__add = function(x, y) return x.v + y end, __index = {v = 10}})
+        local b = setmetatable({v = 1}, {__add = function(x, y) return x + y.v end})
+        return a + 5, 2 + b";
+    assert_eq!(values(src), [Value::Integer(15), Value::Integer(3)]);
+    assert_eq!(error("local a = setmetatable({}, {__index = function(t, k) error('no ' .. k) end})\nreturn a.foo"),
+        "test:1: no foo");
+}
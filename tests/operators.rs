@@ -0,0 +1,54 @@
+mod common;
+
+use common::{compile, error, values};
+use rlua::{bytecode::Bytecode, value::Value};
+
+#[test]
+fn equality_of_functions() {
+    assert_eq!(values("return print == pcall, print == print, print ~= error"),
+        [Value::Boolean(false), Value::Boolean(true), Value::Boolean(true)]);
+}
+
+#[test]
+fn equality_of_strings_by_bytes() {
+    assert_eq!(values(r#"return "\xff" == "\xfe", "\xff" == "\xff""#),
+        [Value::Boolean(false), Value::Boolean(true)]);
+    let mid = "x".repeat(20);
+    assert_eq!(values(&format!(r#"return "{mid}\xff" == "{mid}\xfe", "{mid}" == "{mid}""#)),
+        [Value::Boolean(false), Value::Boolean(true)]);
+}
+
+#[test]
+fn arithmetic() {
+    assert_eq!(values("local two, seven = 2, 7\nreturn two^10, seven // two, 7.0 // two, seven % -3, -seven % 3, seven / 0"),
+        [Value::Float(1024.0), Value::Integer(3), Value::Float(3.0), Value::Integer(-2), Value::Integer(2), Value::Float(f64::INFINITY)]);
+    assert_eq!(values("local x = 3\nreturn x | 5, 1 << 63, 'a' .. x .. 2.0, '10' + x"),
+        [Value::Integer(7), Value::Integer(i64::MIN), Value::from("a32.0"), Value::Integer(13)]);
+}
+
+#[test]
+fn arithmetic_errors() {
+    assert_eq!(error("local x = 1\nreturn x // 0"), "test:2: attempt to perform 'n//0'");
+    assert_eq!(error("local x = 1\nreturn x % 0"), "test:2: attempt to perform 'n%0'");
+    assert_eq!(error("return 1.5 | 1"), "test:1: number has no integer representation");
+    assert_eq!(error("return {} + 1"), "test:1: attempt to perform arithmetic on a table value");
+    assert_eq!(error("return 1 < 'x'"), "test:1: attempt to compare number with string");
+}
+
+#[test]
+fn constant_folding() {
+    let proto = compile("local a = 2^10 + 7 // 2 - -1\nreturn a").unwrap();
+    assert!(matches!(proto.bytecodes[0], Bytecode::LoadConst(_, 0)));
+    assert_eq!(proto.constants, [Value::Float(1028.0)]);
+    // errors are left to run time
+    assert_eq!(error("return 1 // 0"), "test:1: attempt to perform 'n//0'");
+    assert_eq!(values("return 1 / 0, -(0.0)"), [Value::Float(f64::INFINITY), Value::Float(-0.0)]);
+}
+
+#[test]
+fn dead_branches() {
+    let proto = compile("if false then y = 1 end\nif 1 then z = 2 else z = 3 end").unwrap();
+    assert_eq!(proto.bytecodes.len(), 2);
+    assert!(matches!(proto.bytecodes[0], Bytecode::SetGlobalConst(..)));
+    assert_eq!(values("if nil then x = 1 elseif 'x' then x = 2 else x = 3 end return x"), [Value::Integer(2)]);
+}
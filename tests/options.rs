@@ -0,0 +1,39 @@
+use std::rc::Rc;
+use rlua::{bytecode::Bytecode, parser::{CompileOptions, ParseProto}, proto::Proto, vm::ExeState};
+
+const SRC: &str = "local x = 1\nif x < 2 then y = x .. 1 end\nlocal t = nil\nreturn t.x";
+
+fn load(options: CompileOptions) -> Rc<Proto> {
+    ParseProto::load(SRC.as_bytes(), CompileOptions { chunk_name: "test".into(), ..options }).unwrap()
+}
+
+#[test]
+fn listing_goes_to_the_sink() {
+    let mut out = Vec::new();
+    load(CompileOptions { listing: Some(&mut out), ..Default::default() });
+    let listing = String::from_utf8(out).unwrap();
+    assert!(listing.starts_with("main <test> (11 instructions)\n"));
+    assert!(listing.contains("\t9\t[4]\tGetField      R2 t \"x\"\n"));
+}
+
+#[test]
+fn strip_leaves_out_debug_info() {
+    let proto = load(CompileOptions::default());
+    assert_eq!(proto.source.as_deref(), Some("test"));
+    assert_eq!(proto.line(8), Some(4));
+    assert_eq!(proto.locvars.len(), 2);
+    assert_eq!(ExeState::new().execute(&proto).unwrap_err().to_string(), "test:4: attempt to index a nil value");
+
+    let proto = load(CompileOptions { strip: true, ..Default::default() });
+    assert_eq!(proto.source, None);
+    assert_eq!(proto.line(8), None);
+    assert!(proto.locvars.is_empty());
+    assert_eq!(ExeState::new().execute(&proto).unwrap_err().to_string(), "?:-1: attempt to index a nil value");
+}
+
+#[test]
+fn opt_level_0_skips_the_peephole_pass() {
+    let fused = |proto: &Proto| proto.bytecodes.iter().any(|code| matches!(code, Bytecode::TestLt(..)));
+    assert!(!fused(&load(CompileOptions { opt_level: 0, ..Default::default() })));
+    assert!(fused(&load(CompileOptions::default())));
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use common::syntax_error;
+use std::rc::Rc;
+use rlua::{parser::{CompileOptions, ParseProto}, proto::Proto, value::Value, vm::ExeState};
+
+#[test]
+fn unsupported_syntax_is_an_error() {
+    assert_eq!(syntax_error("local a = ..."), "test:1: varargs are not supported near '...'");
+    assert_eq!(syntax_error("print(1,\n...)"), "test:2: varargs are not supported near '...'");
+}
+
+#[test]
+fn error_messages() {
+    assert_eq!(syntax_error("x = = 1"), "test:1: unexpected symbol near '='");
+    assert_eq!(syntax_error("local = 1"), "test:1: <name> expected near '='");
+    assert_eq!(syntax_error("f(1, 2"), "test:1: ')' expected near <eof>");
+    assert_eq!(syntax_error("x = 1 y"), "test:1: syntax error near <eof>");
+    assert_eq!(syntax_error("return 1 2"), "test:1: '<eof>' expected near '2'");
+    assert_eq!(syntax_error("local x <foo> = 1"), "test:1: unknown attribute 'foo'");
+    assert_eq!(syntax_error("local x <const> = 1; x = 2"), "test:1: attempt to assign to const variable 'x'");
+    assert_eq!(syntax_error("x = (1\n\n"), "test:3: ')' expected (to close '(' at line 1) near <eof>");
+    assert_eq!(syntax_error("if x\nthen\ny = 1\nelse"), "test:4: 'end' expected (to close 'if' at line 1) near <eof>");
+}
+
+#[test]
+fn lexical_error_messages() {
+    assert_eq!(syntax_error("x = 'abc"), "test:1: unfinished string near <eof>");
+    assert_eq!(syntax_error("x = 'abc\n'"), "test:1: unfinished string near ''abc'");
+    assert_eq!(syntax_error("x = 3x"), "test:1: malformed number near '3x'");
+    assert_eq!(syntax_error("x = '\\q'"), "test:1: invalid escape sequence near ''\\q'");
+    assert_eq!(syntax_error("x = '\\300'"), "test:1: decimal escape too large near ''\\300'");
+    assert_eq!(syntax_error("x = '\\xg'"), "test:1: hexadecimal digit expected near ''\\xg'");
+    assert_eq!(syntax_error("\n\n@"), "test:3: unexpected symbol near '@'");
+}
+
+fn recover(src: &str) -> (Rc<Proto>, Vec<String>) {
+    let options = CompileOptions { chunk_name: "test".to_string(), ..Default::default() };
+    let (proto, errors) = ParseProto::load_recovering(src.as_bytes(), options);
+    (proto, errors.iter().map(|e| e.to_string()).collect())
+}
+
+#[test]
+fn recovery_of_two_errors() {
+    let (_, errors) = recover("local x = = 1\nlocal y = * 2\nz = 3");
+    assert_eq!(errors, ["test:1: unexpected symbol near '='", "test:2: unexpected symbol near '*'"]);
+}
+
+#[test]
+fn recovery_at_a_stray_end() {
+    let (_, errors) = recover("x = 1 end\ny = )");
+    assert_eq!(errors, ["test:1: '<eof>' expected near 'end'", "test:2: unexpected symbol near ')'"]);
+}
+
+#[test]
+fn recovered_chunk_keeps_the_valid_statements() {
+    // resynchronized at the semicolon
+    let (proto, errors) = recover("x = 1\ny = = 2;\nz = 3\nreturn x, y, z");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(ExeState::new().execute(&proto).unwrap(), [Value::Integer(1), Value::Nil, Value::Integer(3)]);
+}
+
+#[test]
+fn recovery_keeps_the_end_of_nested_blocks() {
+    let (_, errors) = recover("if x then\n  local y = = 1\nend\nlocal y = 2\nif y then\n  y = )\nend\nlocal z = * 3\n");
+    assert_eq!(errors, [
+        "test:2: unexpected symbol near '='",
+        "test:6: unexpected symbol near ')'",
+        "test:8: unexpected symbol near '*'",
+    ]);
+}
+
+#[test]
+fn recovery_keeps_else_and_elseif() {
+    let (_, errors) = recover("if x then\n  y = )\nelseif y then\n  y = *\nelse\n  y = ]\nend\nz = =\n");
+    assert_eq!(errors, [
+        "test:2: unexpected symbol near ')'",
+        "test:4: unexpected symbol near '*'",
+        "test:6: unexpected symbol near ']'",
+        "test:8: unexpected symbol near '='",
+    ]);
+}
@@ -0,0 +1,29 @@
+mod common;
+
+use common::{error, values};
+use rlua::value::Value;
+
+#[test]
+fn integral_float_keys_are_integers() {
+    let src = "local t = {[1.0] = 'a', [2] = 'b', [1.5] = 'c', [-1] = 'd'}
+        return t[1], t[2.0], t[1.5], t[-1.0], #t";
+    assert_eq!(values(src), [Value::from("a"), Value::from("b"), Value::from("c"), Value::from("d"), Value::Integer(2)]);
+
+    let src = "local t, k = {}, 1
+        t[3.0] = 1 t[2^53] = 2 t[k + 0.0] = 3
+        return t[3], t[9007199254740992], t[1], t[nil]";
+    assert_eq!(values(src), [Value::Integer(1), Value::Integer(2), Value::Integer(3), Value::Nil]);
+}
+
+#[test]
+fn positional_items_override_keys() {
+    assert_eq!(values("local t = {10, 20, [3] = 30, 40} return t[3], #t"), [Value::Integer(40), Value::Integer(3)]);
+}
+
+#[test]
+fn nil_and_nan_keys_are_errors() {
+    assert_eq!(error("local t = {}\nt[nil] = 1"), "test:2: table index is nil");
+    assert_eq!(error("local t = {}\nt[0/0] = 1"), "test:2: table index is NaN");
+    assert_eq!(error("local t = {[nil] = 1}"), "test:1: table index is nil");
+    assert_eq!(error("local t = {[0/0] = 1}"), "test:1: table index is NaN");
+}